@@ -0,0 +1,57 @@
+use apache_avro::types::Value;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use foamcore::decoder::{AvroDecoder, Decoder};
+use foamcore::encoder::{AvroEncoder, Encoder};
+use foamcore::schema::{Decoded, NDArray, AvroType, record_schema};
+
+/// A record with a few scalars.
+fn small_record() -> (serde_json::Value, Decoded) {
+    let schema = record_schema("bench", "motor", vec![
+        ("pulse_id", serde_json::json!("long")),
+        ("position", serde_json::json!("double")),
+        ("moving", serde_json::json!("boolean")),
+        ("label", serde_json::json!(["null", "string"])),
+    ]);
+    let datum = Decoded::from([
+        ("pulse_id".to_string(), Value::Long(123456)),
+        ("position".to_string(), Value::Double(1.5)),
+        ("moving".to_string(), Value::Boolean(false)),
+        ("label".to_string(), Value::Union(1, Box::new(Value::String("x".to_string())))),
+    ]);
+    (schema, datum)
+}
+
+/// A record with a 1024 x 1024 float32 image (4 MB).
+fn ndarray_record() -> (serde_json::Value, Decoded) {
+    let schema = record_schema("bench", "frame", vec![
+        ("pulse_id", serde_json::json!("long")),
+        ("image", NDArray::avro_type()),
+    ]);
+    let datum = Decoded::from([
+        ("pulse_id".to_string(), Value::Long(1)),
+        ("image".to_string(), Value::Record(vec![
+            ("shape".to_string(), Value::Array(vec![Value::Int(1024), Value::Int(1024)])),
+            ("dtype".to_string(), Value::String("<f4".to_string())),
+            ("data".to_string(), Value::Bytes(vec![1; 4 << 20])),
+        ])),
+    ]);
+    (schema, datum)
+}
+
+fn bench_codec(c: &mut Criterion) {
+    for (name, (schema, datum)) in [("small_record", small_record()), ("ndarray_4MB", ndarray_record())] {
+        let encoder = AvroEncoder::new(&schema);
+        let decoder = AvroDecoder::new(&schema);
+        let bytes = encoder.pack(&datum).unwrap();
+
+        let mut group = c.benchmark_group(name);
+        group.throughput(Throughput::Bytes(bytes.len() as u64));
+        group.bench_function("pack", |b| b.iter(|| encoder.pack(black_box(&datum)).unwrap()));
+        group.bench_function("unpack", |b| b.iter(|| decoder.unpack(black_box(&bytes)).unwrap()));
+        group.finish();
+    }
+}
+
+criterion_group!(benches, bench_codec);
+criterion_main!(benches);
@@ -0,0 +1,84 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use apache_avro::types::Value;
+use criterion::{black_box, criterion_group, criterion_main, Criterion, Throughput};
+
+use foamcore::decoder::{AvroDecoder, Decoder};
+use foamcore::encoder::{AvroEncoder, Encoder};
+use foamcore::schema::{Decoded, NDArray, AvroType, record_schema};
+
+/// Global allocator which counts the allocations and the allocated bytes.
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Return the number of allocations and the allocated bytes of a call.
+fn count_allocations<F: FnOnce()>(f: F) -> (usize, usize) {
+    let (n0, b0) = (ALLOCATIONS.load(Ordering::Relaxed), ALLOCATED.load(Ordering::Relaxed));
+    f();
+    (ALLOCATIONS.load(Ordering::Relaxed) - n0, ALLOCATED.load(Ordering::Relaxed) - b0)
+}
+
+/// Encode a frame with a 1024 x 1024 float32 image (4 MB).
+fn frame() -> (serde_json::Value, Vec<u8>) {
+    let schema = record_schema("bench", "frame", vec![
+        ("pulse_id", serde_json::json!("long")),
+        ("image", NDArray::avro_type()),
+    ]);
+    let datum = Decoded::from([
+        ("pulse_id".to_string(), Value::Long(1)),
+        ("image".to_string(), Value::Record(vec![
+            ("shape".to_string(), Value::Array(vec![Value::Int(1024), Value::Int(1024)])),
+            ("dtype".to_string(), Value::String("<f4".to_string())),
+            ("data".to_string(), Value::Bytes(vec![1; 4 << 20])),
+        ])),
+    ]);
+    let bytes = AvroEncoder::new(&schema).pack(&datum).unwrap();
+    (schema, bytes)
+}
+
+fn bench_decode(c: &mut Criterion) {
+    let (schema, bytes) = frame();
+    let decoder = AvroDecoder::new(&schema);
+
+    let (n, size) = count_allocations(|| { decoder.unpack(&bytes).unwrap(); });
+    println!("unpack: {} allocations, {} bytes per frame", n, size);
+    // the first call checks the writer schema
+    decoder.unpack_borrowed(&bytes).unwrap();
+    let (n, size) = count_allocations(|| { decoder.unpack_borrowed(&bytes).unwrap(); });
+    println!("unpack_borrowed: {} allocations, {} bytes per frame", n, size);
+
+    let mut group = c.benchmark_group("decode_4MB_frame");
+    group.throughput(Throughput::Bytes(bytes.len() as u64));
+    group.bench_function("unpack", |b| b.iter(|| decoder.unpack(black_box(&bytes)).unwrap()));
+    group.bench_function("unpack_borrowed", |b| {
+        b.iter(|| decoder.unpack_borrowed(black_box(&bytes)).unwrap().len())
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_decode);
+criterion_main!(benches);
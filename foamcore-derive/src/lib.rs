@@ -0,0 +1,86 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::ext::IdentExt;
+use syn::{parse_macro_input, Data, DeriveInput, Fields, LitStr};
+
+/// Derive `foamcore::schema::FoamSchema` for a struct with named fields.
+///
+/// The record namespace is mandatory and the record name defaults to the
+/// struct name:
+///
+/// ```ignore
+/// #[derive(FoamSchema)]
+/// #[foam(namespace = "datahouse", name = "raw")]
+/// struct Raw {
+///     name: String,
+///     age: i32,
+///     stress: NDArray,
+/// }
+/// ```
+#[proc_macro_derive(FoamSchema, attributes(foam))]
+pub fn derive_foam_schema(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<proc_macro2::TokenStream> {
+    let ident = &input.ident;
+
+    let mut namespace: Option<LitStr> = None;
+    let mut name: Option<LitStr> = None;
+    for attr in &input.attrs {
+        if !attr.path().is_ident("foam") {
+            continue;
+        }
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("namespace") {
+                namespace = Some(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("name") {
+                name = Some(meta.value()?.parse()?);
+                Ok(())
+            } else {
+                Err(meta.error("Unsupported foam attribute"))
+            }
+        })?;
+    }
+    let namespace = namespace.ok_or_else(|| syn::Error::new_spanned(
+        ident, "FoamSchema requires #[foam(namespace = \"...\")]"))?;
+    let name = name.unwrap_or_else(|| LitStr::new(&ident.unraw().to_string(), ident.span()));
+
+    let fields = match &input.data {
+        Data::Struct(s) => match &s.fields {
+            Fields::Named(f) => &f.named,
+            _ => return Err(syn::Error::new_spanned(
+                ident, "FoamSchema can only be derived for structs with named fields")),
+        },
+        _ => return Err(syn::Error::new_spanned(
+            ident, "FoamSchema can only be derived for structs")),
+    };
+    let field_names = fields.iter().map(|f| f.ident.as_ref().unwrap().unraw().to_string());
+    let field_types = fields.iter().map(|f| &f.ty);
+
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::foamcore::schema::AvroType for #ident #ty_generics #where_clause {
+            fn avro_type() -> ::foamcore::serde_json::Value {
+                ::foamcore::schema::record_schema(#namespace, #name, vec![
+                    #( (#field_names, <#field_types as ::foamcore::schema::AvroType>::avro_type()) ),*
+                ])
+            }
+        }
+
+        impl #impl_generics ::foamcore::schema::FoamSchema for #ident #ty_generics #where_clause {}
+    })
+}
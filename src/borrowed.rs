@@ -0,0 +1,410 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::collections::HashMap;
+
+use apache_avro::{Decimal, Duration, Schema};
+use apache_avro::schema::DecimalSchema;
+use apache_avro::types::Value;
+
+use crate::json::Names;
+use crate::schema::Decoded;
+use crate::error::{FcError, FcResult};
+
+/// Avro value which borrows bytes and strings from the encoded buffer.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValueRef<'a> {
+    Null,
+    Boolean(bool),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    Bytes(&'a [u8]),
+    String(&'a str),
+    Fixed(&'a [u8]),
+    Enum(u32, &'a str),
+    Union(u32, Box<ValueRef<'a>>),
+    Array(Vec<ValueRef<'a>>),
+    Map(Vec<(&'a str, ValueRef<'a>)>),
+    Record(Vec<(&'a str, ValueRef<'a>)>),
+    Date(i32),
+    TimeMillis(i32),
+    TimeMicros(i64),
+    TimestampMillis(i64),
+    TimestampMicros(i64),
+    /// Big-endian two's complement bytes
+    Decimal(&'a [u8]),
+    Uuid(&'a str),
+    /// Months, days and milliseconds as little-endian u32
+    Duration(&'a [u8; 12]),
+}
+
+/// Decoded record which borrows from the encoded buffer.
+pub type DecodedRef<'a> = HashMap<&'a str, ValueRef<'a>>;
+
+impl<'a> ValueRef<'a> {
+    /// Copy into an owned Avro value.
+    pub fn to_value(&self) -> Value {
+        match self {
+            ValueRef::Null => Value::Null,
+            ValueRef::Boolean(x) => Value::Boolean(*x),
+            ValueRef::Int(x) => Value::Int(*x),
+            ValueRef::Long(x) => Value::Long(*x),
+            ValueRef::Float(x) => Value::Float(*x),
+            ValueRef::Double(x) => Value::Double(*x),
+            ValueRef::Bytes(b) => Value::Bytes(b.to_vec()),
+            ValueRef::String(s) => Value::String(s.to_string()),
+            ValueRef::Fixed(b) => Value::Fixed(b.len(), b.to_vec()),
+            ValueRef::Enum(i, s) => Value::Enum(*i, s.to_string()),
+            ValueRef::Union(i, v) => Value::Union(*i, Box::new(v.to_value())),
+            ValueRef::Array(items) => Value::Array(items.iter().map(|x| x.to_value()).collect()),
+            ValueRef::Map(items) => Value::Map(
+                items.iter().map(|(k, v)| (k.to_string(), v.to_value())).collect()),
+            ValueRef::Record(fields) => Value::Record(
+                fields.iter().map(|(k, v)| (k.to_string(), v.to_value())).collect()),
+            ValueRef::Date(x) => Value::Date(*x),
+            ValueRef::TimeMillis(x) => Value::TimeMillis(*x),
+            ValueRef::TimeMicros(x) => Value::TimeMicros(*x),
+            ValueRef::TimestampMillis(x) => Value::TimestampMillis(*x),
+            ValueRef::TimestampMicros(x) => Value::TimestampMicros(*x),
+            ValueRef::Decimal(b) => Value::Decimal(Decimal::from(b)),
+            // the string is validated when it is read
+            ValueRef::Uuid(s) => Value::String(s.to_string()).resolve(&Schema::Uuid)
+                .expect("Invalid UUID"),
+            ValueRef::Duration(b) => Value::Duration(Duration::from(**b)),
+        }
+    }
+
+    /// Return the borrowed slice of a bytes or fixed value.
+    pub fn as_bytes(&self) -> Option<&'a [u8]> {
+        match self {
+            ValueRef::Bytes(b) | ValueRef::Fixed(b) => Some(*b),
+            ValueRef::Union(_, v) => v.as_bytes(),
+            _ => None,
+        }
+    }
+
+    /// Return the value of a field of a record value.
+    pub fn field(&self, name: &str) -> Option<&ValueRef<'a>> {
+        match self {
+            ValueRef::Record(fields) => fields.iter().find(|(k, _)| *k == name).map(|(_, v)| v),
+            ValueRef::Union(_, v) => v.field(name),
+            _ => None,
+        }
+    }
+}
+
+/// Copy a borrowed record into an owned one.
+pub fn to_decoded(datum: &DecodedRef) -> Decoded {
+    datum.iter().map(|(k, v)| (k.to_string(), v.to_value())).collect()
+}
+
+fn eof_error() -> FcError {
+    FcError::CodecError("Unexpected end of Avro data".to_string())
+}
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn is_empty(&self) -> bool {
+        self.pos >= self.buf.len()
+    }
+
+    fn take(&mut self, n: usize) -> FcResult<&'a [u8]> {
+        let end = self.pos.checked_add(n).filter(|end| *end <= self.buf.len()).ok_or_else(eof_error)?;
+        let ret = &self.buf[self.pos..end];
+        self.pos = end;
+        Ok(ret)
+    }
+
+    /// Read a zigzag-encoded variable-length integer.
+    fn read_long(&mut self) -> FcResult<i64> {
+        let mut n: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let b = self.take(1)?[0];
+            n |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+            if shift > 63 {
+                return Err(FcError::CodecError("Invalid Avro varint".to_string()));
+            }
+        }
+        Ok((n >> 1) as i64 ^ -((n & 1) as i64))
+    }
+
+    /// Read an int, failing like the apache_avro reader if it is out of range.
+    fn read_int(&mut self) -> FcResult<i32> {
+        let n = self.read_long()?;
+        i32::try_from(n).map_err(|_| FcError::CodecError(format!("Int out of range in Avro data: {}", n)))
+    }
+
+    fn read_len(&mut self) -> FcResult<usize> {
+        usize::try_from(self.read_long()?).map_err(
+            |_| FcError::CodecError("Negative length in Avro data".to_string()))
+    }
+
+    fn read_bytes(&mut self) -> FcResult<&'a [u8]> {
+        let len = self.read_len()?;
+        self.take(len)
+    }
+
+    fn read_str(&mut self) -> FcResult<&'a str> {
+        std::str::from_utf8(self.read_bytes()?).map_err(
+            |e| FcError::CodecError(format!("Invalid UTF-8 in Avro data: {}", e)))
+    }
+
+    /// Read the item count of the next block of an array or a map.
+    fn read_block_count(&mut self) -> FcResult<usize> {
+        let count = self.read_long()?;
+        if count < 0 {
+            // the block size follows a negative count
+            self.read_long()?;
+        }
+        usize::try_from(count.unsigned_abs()).map_err(|_| eof_error())
+    }
+}
+
+fn read_value<'a>(cursor: &mut Cursor<'a>, schema: &'a Schema, names: &'a Names) -> FcResult<ValueRef<'a>> {
+    Ok(match names.resolve(schema)? {
+        Schema::Null => ValueRef::Null,
+        Schema::Boolean => ValueRef::Boolean(cursor.take(1)?[0] != 0),
+        Schema::Int => ValueRef::Int(cursor.read_int()?),
+        Schema::Long => ValueRef::Long(cursor.read_long()?),
+        Schema::Float => ValueRef::Float(f32::from_le_bytes(cursor.take(4)?.try_into().unwrap())),
+        Schema::Double => ValueRef::Double(f64::from_le_bytes(cursor.take(8)?.try_into().unwrap())),
+        Schema::Bytes => ValueRef::Bytes(cursor.read_bytes()?),
+        Schema::String => ValueRef::String(cursor.read_str()?),
+        Schema::Fixed(fs) => ValueRef::Fixed(cursor.take(fs.size)?),
+        Schema::Enum(es) => {
+            let index = cursor.read_len()?;
+            let symbol = es.symbols.get(index).ok_or_else(
+                || FcError::CodecError(format!("Invalid enum index: {}", index)))?;
+            ValueRef::Enum(index as u32, symbol)
+        },
+        Schema::Union(us) => {
+            let index = cursor.read_len()?;
+            let variant = us.variants().get(index).ok_or_else(
+                || FcError::CodecError(format!("Invalid union index: {}", index)))?;
+            ValueRef::Union(index as u32, Box::new(read_value(cursor, variant, names)?))
+        },
+        Schema::Array(items) => {
+            let mut values = Vec::new();
+            loop {
+                let count = cursor.read_block_count()?;
+                if count == 0 {
+                    break;
+                }
+                values.reserve(count.min(cursor.buf.len() - cursor.pos));
+                for _ in 0..count {
+                    values.push(read_value(cursor, items, names)?);
+                }
+            }
+            ValueRef::Array(values)
+        },
+        Schema::Map(values_schema) => {
+            let mut values = Vec::new();
+            loop {
+                let count = cursor.read_block_count()?;
+                if count == 0 {
+                    break;
+                }
+                for _ in 0..count {
+                    let key = cursor.read_str()?;
+                    values.push((key, read_value(cursor, values_schema, names)?));
+                }
+            }
+            ValueRef::Map(values)
+        },
+        Schema::Record(rs) => {
+            let mut fields = Vec::with_capacity(rs.fields.len());
+            for field in &rs.fields {
+                fields.push((field.name.as_str(), read_value(cursor, &field.schema, names)?));
+            }
+            ValueRef::Record(fields)
+        },
+        Schema::Date => ValueRef::Date(cursor.read_int()?),
+        Schema::TimeMillis => ValueRef::TimeMillis(cursor.read_int()?),
+        Schema::TimeMicros => ValueRef::TimeMicros(cursor.read_long()?),
+        Schema::TimestampMillis => ValueRef::TimestampMillis(cursor.read_long()?),
+        Schema::TimestampMicros => ValueRef::TimestampMicros(cursor.read_long()?),
+        Schema::Decimal(DecimalSchema { inner, .. }) => match names.resolve(inner)? {
+            Schema::Fixed(fs) => ValueRef::Decimal(cursor.take(fs.size)?),
+            _ => ValueRef::Decimal(cursor.read_bytes()?),
+        },
+        Schema::Uuid => {
+            let s = cursor.read_str()?;
+            Value::String(s.to_string()).resolve(&Schema::Uuid)?;
+            ValueRef::Uuid(s)
+        },
+        Schema::Duration => ValueRef::Duration(cursor.take(12)?.try_into().unwrap()),
+        s => return Err(FcError::CodecError(format!("Unsupported type for borrowed decoding: {:?}", s))),
+    })
+}
+
+/// Header of an Avro object container.
+pub(crate) struct Header<'a> {
+    pub(crate) schema: &'a [u8],
+    // None is the same as "null"
+    pub(crate) codec: Option<&'a [u8]>,
+    sync: &'a [u8],
+    // offset of the first data block
+    body: usize,
+}
+
+impl<'a> Header<'a> {
+    pub(crate) fn is_uncompressed(&self) -> bool {
+        matches!(self.codec, None | Some(b"null"))
+    }
+}
+
+pub(crate) fn read_header(bytes: &[u8]) -> FcResult<Header<'_>> {
+    let mut cursor = Cursor { buf: bytes, pos: 0 };
+    if cursor.take(4)? != b"Obj\x01" {
+        return Err(FcError::CodecError("Not an Avro object container".to_string()));
+    }
+
+    let mut schema = None;
+    let mut codec = None;
+    loop {
+        let count = cursor.read_block_count()?;
+        if count == 0 {
+            break;
+        }
+        for _ in 0..count {
+            let key = cursor.read_str()?;
+            let value = cursor.read_bytes()?;
+            match key {
+                "avro.schema" => schema = Some(value),
+                "avro.codec" => codec = Some(value),
+                _ => (),
+            }
+        }
+    }
+    let schema = schema.ok_or_else(|| FcError::CodecError("Missing schema in Avro header".to_string()))?;
+    let sync = cursor.take(16)?;
+
+    Ok(Header { schema, codec, sync, body: cursor.pos })
+}
+
+/// Return the number of records in each block without decoding them.
+pub(crate) fn read_block_counts(bytes: &[u8], header: &Header) -> FcResult<Vec<usize>> {
+    let mut cursor = Cursor { buf: bytes, pos: header.body };
+    let mut ret = Vec::new();
+    while !cursor.is_empty() {
+        ret.push(cursor.read_len()?);
+        let size = cursor.read_len()?;
+        cursor.take(size)?;
+        if cursor.take(16)? != header.sync {
+            return Err(FcError::CodecError("Invalid sync marker in Avro data".to_string()));
+        }
+    }
+    Ok(ret)
+}
+
+/// Read the records in an uncompressed Avro object container as lists of fields.
+///
+/// The data must have been written with the given schema.
+pub(crate) fn read_records<'a>(bytes: &'a [u8], header: &Header<'a>, schema: &'a Schema, names: &'a Names)
+        -> FcResult<Vec<Vec<(&'a str, ValueRef<'a>)>>> {
+    if !header.is_uncompressed() {
+        return Err(FcError::CodecError(format!(
+            "Borrowed decoding does not support codec: {}", String::from_utf8_lossy(header.codec.unwrap()))));
+    }
+
+    let mut cursor = Cursor { buf: bytes, pos: header.body };
+    let mut ret = Vec::new();
+    while !cursor.is_empty() {
+        let count = cursor.read_len()?;
+        let size = cursor.read_len()?;
+        let mut block = Cursor { buf: cursor.take(size)?, pos: 0 };
+        for _ in 0..count {
+            match read_value(&mut block, schema, names)? {
+                ValueRef::Record(fields) => ret.push(fields),
+                _ => return Err(FcError::SchemaError("Avro data must be records".to_string())),
+            }
+        }
+        if cursor.take(16)? != header.sync {
+            return Err(FcError::CodecError("Invalid sync marker in Avro data".to_string()));
+        }
+    }
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use apache_avro::types::Value;
+
+    use crate::borrowed::{Cursor, ValueRef, to_decoded};
+    use crate::decoder::{AvroDecoder, Decoder};
+    use crate::encoder::{AvroEncoder, Encoder};
+    use crate::schema::Decoded;
+
+    #[test]
+    fn test_borrowed_decoding() {
+        let schema = serde_json::json!({
+            "type": "record",
+            "name": "borrowed_test",
+            "fields": [
+                {"name": "index", "type": "long"},
+                {"name": "label", "type": ["null", "string"]},
+                {"name": "image", "type": {
+                    "type": "record",
+                    "name": "NDArray",
+                    "fields": [
+                        {"name": "shape", "type": {"type": "array", "items": "int"}},
+                        {"name": "dtype", "type": "string"},
+                        {"name": "data", "type": "bytes"}
+                    ]
+                }}
+            ]
+        });
+        let raw = Decoded::from([
+            ("index".to_string(), Value::Long(-3)),
+            ("label".to_string(), Value::Union(1, Box::new(Value::String("dark".to_string())))),
+            ("image".to_string(), Value::Record(vec![
+                ("shape".to_string(), Value::Array(vec![Value::Int(2), Value::Int(300)])),
+                ("dtype".to_string(), Value::String("|u1".to_string())),
+                ("data".to_string(), Value::Bytes((0..600).map(|x| x as u8).collect())),
+            ])),
+        ]);
+
+        let encoder = AvroEncoder::new(&schema);
+        let decoder = AvroDecoder::new(&schema);
+        let bytes = encoder.pack_batch(&[raw.clone(), raw.clone()]).unwrap();
+
+        let decoded = decoder.unpack_borrowed(&bytes).unwrap();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(to_decoded(&decoded[1]), raw);
+        assert_eq!(decoded.iter().map(to_decoded).collect::<Vec<_>>(), decoder.unpack(&bytes).unwrap());
+
+        // the data is a slice of the input buffer
+        let data = decoded[0]["image"].field("data").and_then(ValueRef::as_bytes).unwrap();
+        assert!(bytes.as_ptr_range().contains(&data.as_ptr()));
+
+        let other = AvroDecoder::new(&serde_json::json!({
+            "type": "record",
+            "name": "borrowed_test",
+            "fields": [{"name": "index", "type": "long"}]
+        }));
+        assert!(other.unpack_borrowed(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_int_out_of_range() {
+        // zigzag varint of 2^31
+        let buf = [0x80, 0x80, 0x80, 0x80, 0x10];
+        assert_eq!(Cursor { buf: &buf, pos: 0 }.read_long().unwrap(), 1 << 31);
+        assert!(Cursor { buf: &buf, pos: 0 }.read_int().is_err());
+    }
+}
@@ -0,0 +1,559 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::sync::Arc;
+
+use apache_avro::Schema;
+use apache_avro::schema::RecordSchema;
+use apache_avro::types::Value;
+use arrow::array::{
+    Array, ArrayData, ArrayRef, AsArray, BinaryArray, BooleanArray, Date32Array,
+    FixedSizeBinaryArray, FixedSizeListArray, Float32Array, Float64Array, Int32Array, Int64Array,
+    ListArray, NullArray, StringArray, StructArray, Time32MillisecondArray, Time64MicrosecondArray,
+    TimestampMicrosecondArray, TimestampMillisecondArray, make_array,
+};
+use arrow::buffer::{Buffer, NullBuffer, OffsetBuffer, ScalarBuffer};
+use arrow::datatypes::{
+    DataType, Date32Type, Field, Fields, Float32Type, Float64Type, Int32Type, Int64Type,
+    Schema as ArrowSchema, Time32MillisecondType, Time64MicrosecondType, TimeUnit,
+    TimestampMicrosecondType, TimestampMillisecondType,
+};
+use arrow::ipc::reader::StreamReader;
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+
+use crate::dtype::{DType, TypeDescr};
+use crate::json::{Names, is_ndarray};
+use crate::schema::{Decoded, Encoded};
+use crate::error::{FcError, FcResult};
+
+const EXTENSION_NAME: &str = "ARROW:extension:name";
+const EXTENSION_METADATA: &str = "ARROW:extension:metadata";
+/// Extension name of the fixed-shape tensor columns.
+pub const TENSOR_EXTENSION: &str = "arrow.fixed_shape_tensor";
+/// Extension name of the ndarray columns with varying shape or dtype.
+pub const NDARRAY_EXTENSION: &str = "foamcore.ndarray";
+/// Field metadata which keeps the original NumPy type string of a tensor column.
+pub const DTYPE_METADATA: &str = "foamcore.dtype";
+
+fn value_error(name: &str, value: &Value) -> FcError {
+    FcError::CodecError(format!("Unexpected value for column '{}': {:?}", name, value))
+}
+
+fn array_error(name: &str, data_type: &DataType) -> FcError {
+    FcError::CodecError(format!("Unexpected Arrow type for column '{}': {}", name, data_type))
+}
+
+/// Unwrap union values, mapping null to None.
+fn strip_union(value: &Value) -> Option<&Value> {
+    match value {
+        Value::Union(_, inner) => strip_union(inner),
+        Value::Null => None,
+        _ => Some(value),
+    }
+}
+
+/// Resolve the schema of a column and whether it is nullable.
+///
+/// Only unions of null and a single other type are supported.
+fn nullable_inner<'a>(schema: &'a Schema, names: &'a Names) -> FcResult<(&'a Schema, bool)> {
+    match names.resolve(schema)? {
+        Schema::Union(us) => {
+            let variants: Vec<_> = us.variants().iter().filter(|s| **s != Schema::Null).collect();
+            match variants[..] {
+                [inner] => Ok((names.resolve(inner)?, true)),
+                _ => Err(FcError::CodecError(format!("Unsupported union for Arrow: {:?}", schema))),
+            }
+        },
+        s => Ok((s, false)),
+    }
+}
+
+fn null_buffer(valid: Vec<bool>) -> Option<NullBuffer> {
+    if valid.iter().all(|x| *x) { None } else { Some(NullBuffer::from(valid)) }
+}
+
+pub(crate) fn ndarray_parts(value: &Value) -> Option<(Vec<i32>, &str, &[u8])> {
+    let fields = match value {
+        Value::Record(fields) => fields,
+        _ => return None,
+    };
+    let get = |name: &str| fields.iter().find(|(k, _)| k == name).map(|(_, v)| v);
+    let shape = match get("shape")? {
+        Value::Array(items) => items.iter().map(|x| match x {
+            Value::Int(i) => Some(*i),
+            _ => None,
+        }).collect::<Option<Vec<_>>>()?,
+        _ => return None,
+    };
+    match (get("dtype")?, get("data")?) {
+        (Value::String(dtype), Value::Bytes(data)) => Some((shape, dtype, data)),
+        _ => None,
+    }
+}
+
+fn tensor_value_type(dtype: DType) -> Option<DataType> {
+    Some(match dtype {
+        DType::Bool => return None,
+        DType::Int8 => DataType::Int8,
+        DType::UInt8 => DataType::UInt8,
+        DType::Int16 => DataType::Int16,
+        DType::UInt16 => DataType::UInt16,
+        DType::Int32 => DataType::Int32,
+        DType::UInt32 => DataType::UInt32,
+        DType::Int64 => DataType::Int64,
+        DType::UInt64 => DataType::UInt64,
+        DType::Float32 => DataType::Float32,
+        DType::Float64 => DataType::Float64,
+    })
+}
+
+/// Copy items between the byte order of the dtype and the native one of Arrow.
+fn copy_items(dst: &mut Vec<u8>, src: &[u8], descr: &TypeDescr) {
+    if descr.big_endian == cfg!(target_endian = "big") {
+        dst.extend_from_slice(src);
+    } else {
+        for item in src.chunks_exact(descr.itemsize()) {
+            dst.extend(item.iter().rev());
+        }
+    }
+}
+
+/// Build a fixed-shape tensor column if all the ndarrays share shape and dtype.
+fn build_tensor(name: &str, column: &[Option<&Value>]) -> FcResult<Option<(Field, ArrayRef)>> {
+    let mut parts = Vec::with_capacity(column.len());
+    for value in column {
+        match value.and_then(ndarray_parts) {
+            Some(p) => parts.push(p),
+            None => return Ok(None),
+        }
+    }
+    let (shape, dtype) = match parts.first() {
+        Some((shape, dtype, _)) => (shape.clone(), dtype.to_string()),
+        None => return Ok(None),
+    };
+    if parts.iter().any(|(s, d, _)| s != &shape || *d != dtype) {
+        return Ok(None);
+    }
+
+    let descr = match TypeDescr::parse(&dtype) {
+        Ok(descr) => descr,
+        Err(_) => return Ok(None),
+    };
+    let value_type = match tensor_value_type(descr.dtype) {
+        Some(t) => t,
+        None => return Ok(None),
+    };
+    // negative or too large dimensions are kept in a struct column
+    let size = match shape.iter().try_fold(1usize, |acc, &n| acc.checked_mul(usize::try_from(n).ok()?)) {
+        Some(size) => size,
+        None => return Ok(None),
+    };
+    let (list_size, nbytes, len) = match (
+        i32::try_from(size).ok(), size.checked_mul(descr.itemsize()), size.checked_mul(parts.len()),
+    ) {
+        (Some(list_size), Some(nbytes), Some(len)) => (list_size, nbytes, len),
+        _ => return Ok(None),
+    };
+    if parts.iter().any(|(_, _, data)| data.len() != nbytes) {
+        return Ok(None);
+    }
+
+    let mut buffer = Vec::with_capacity(nbytes * parts.len());
+    for (_, _, data) in &parts {
+        copy_items(&mut buffer, data, &descr);
+    }
+    let values = make_array(ArrayData::builder(value_type.clone())
+        .len(len)
+        .add_buffer(Buffer::from_vec(buffer))
+        .build()?);
+
+    let item = Arc::new(Field::new("item", value_type, false));
+    let array = FixedSizeListArray::try_new(item.clone(), list_size, values, None)?;
+    let metadata = HashMap::from([
+        (EXTENSION_NAME.to_string(), TENSOR_EXTENSION.to_string()),
+        (EXTENSION_METADATA.to_string(), serde_json::json!({"shape": shape}).to_string()),
+        (DTYPE_METADATA.to_string(), dtype),
+    ]);
+    let field = Field::new(name, DataType::FixedSizeList(item, list_size), false).with_metadata(metadata);
+    Ok(Some((field, Arc::new(array))))
+}
+
+fn build_struct(name: &str, column: &[Option<&Value>], rs: &RecordSchema, nullable: bool, names: &Names)
+        -> FcResult<(Field, ArrayRef)> {
+    let mut fields = Vec::with_capacity(rs.fields.len());
+    let mut arrays = Vec::with_capacity(rs.fields.len());
+    for f in &rs.fields {
+        let child: Vec<Option<&Value>> = column.iter().map(|x| match x {
+            Some(Value::Record(values)) => values.iter()
+                .find(|(k, _)| k == &f.name).and_then(|(_, v)| strip_union(v)),
+            _ => None,
+        }).collect();
+        let (field, array) = build_column(&f.name, &child, &f.schema, names)?;
+        fields.push(field);
+        arrays.push(array);
+    }
+
+    let valid = column.iter().map(|x| x.is_some()).collect();
+    let array = StructArray::try_new(Fields::from(fields), arrays, null_buffer(valid))?;
+    let mut field = Field::new(name, DataType::Struct(array.fields().clone()), nullable);
+    if is_ndarray(rs) {
+        field = field.with_metadata(HashMap::from([
+            (EXTENSION_NAME.to_string(), NDARRAY_EXTENSION.to_string()),
+        ]));
+    }
+    Ok((field, Arc::new(array)))
+}
+
+macro_rules! build_primitive {
+    ($name:expr, $column:expr, $nullable:expr, $array:ty, $data_type:expr, $pat:pat => $v:expr) => {{
+        let values = $column.iter().map(|x| match x {
+            None => Ok(None),
+            Some($pat) => Ok(Some($v)),
+            Some(other) => Err(value_error($name, other)),
+        }).collect::<FcResult<Vec<_>>>()?;
+        (Field::new($name, $data_type, $nullable), Arc::new(<$array>::from(values)) as ArrayRef)
+    }};
+}
+
+pub(crate) fn build_column(name: &str, column: &[Option<&Value>], schema: &Schema, names: &Names)
+        -> FcResult<(Field, ArrayRef)> {
+    let (schema, nullable) = nullable_inner(schema, names)?;
+
+    Ok(match schema {
+        Schema::Null => (Field::new(name, DataType::Null, true), Arc::new(NullArray::new(column.len()))),
+        Schema::Boolean => build_primitive!(
+            name, column, nullable, BooleanArray, DataType::Boolean, Value::Boolean(b) => *b),
+        Schema::Int => build_primitive!(
+            name, column, nullable, Int32Array, DataType::Int32, Value::Int(x) => *x),
+        Schema::Long => build_primitive!(
+            name, column, nullable, Int64Array, DataType::Int64, Value::Long(x) => *x),
+        Schema::Float => build_primitive!(
+            name, column, nullable, Float32Array, DataType::Float32, Value::Float(x) => *x),
+        Schema::Double => build_primitive!(
+            name, column, nullable, Float64Array, DataType::Float64, Value::Double(x) => *x),
+        Schema::String => build_primitive!(
+            name, column, nullable, StringArray, DataType::Utf8, Value::String(s) => s.as_str()),
+        Schema::Enum(_) => build_primitive!(
+            name, column, nullable, StringArray, DataType::Utf8, Value::Enum(_, s) => s.as_str()),
+        Schema::Bytes => build_primitive!(
+            name, column, nullable, BinaryArray, DataType::Binary, Value::Bytes(b) => b.as_slice()),
+        Schema::Date => build_primitive!(
+            name, column, nullable, Date32Array, DataType::Date32, Value::Date(x) => *x),
+        Schema::TimeMillis => build_primitive!(
+            name, column, nullable, Time32MillisecondArray, DataType::Time32(TimeUnit::Millisecond),
+            Value::TimeMillis(x) => *x),
+        Schema::TimeMicros => build_primitive!(
+            name, column, nullable, Time64MicrosecondArray, DataType::Time64(TimeUnit::Microsecond),
+            Value::TimeMicros(x) => *x),
+        Schema::TimestampMillis => build_primitive!(
+            name, column, nullable, TimestampMillisecondArray,
+            DataType::Timestamp(TimeUnit::Millisecond, None), Value::TimestampMillis(x) => *x),
+        Schema::TimestampMicros => build_primitive!(
+            name, column, nullable, TimestampMicrosecondArray,
+            DataType::Timestamp(TimeUnit::Microsecond, None), Value::TimestampMicros(x) => *x),
+        Schema::Fixed(fs) => {
+            let values = column.iter().map(|x| match x {
+                None => Ok(None),
+                Some(Value::Fixed(_, b)) => Ok(Some(b.as_slice())),
+                Some(other) => Err(value_error(name, other)),
+            }).collect::<FcResult<Vec<_>>>()?;
+            let array = FixedSizeBinaryArray::try_from_sparse_iter_with_size(
+                values.into_iter(), fs.size as i32)?;
+            (Field::new(name, DataType::FixedSizeBinary(fs.size as i32), nullable), Arc::new(array))
+        },
+        Schema::Array(items) => {
+            let mut offsets = vec![0i32];
+            let mut valid = Vec::with_capacity(column.len());
+            let mut child = Vec::new();
+            for x in column {
+                match x {
+                    None => valid.push(false),
+                    Some(Value::Array(values)) => {
+                        child.extend(values.iter().map(strip_union));
+                        valid.push(true);
+                    },
+                    Some(other) => return Err(value_error(name, other)),
+                }
+                offsets.push(child.len() as i32);
+            }
+            let (item, values) = build_column("item", &child, items, names)?;
+            let item = Arc::new(item);
+            let array = ListArray::try_new(
+                item.clone(), OffsetBuffer::new(ScalarBuffer::from(offsets)), values, null_buffer(valid))?;
+            (Field::new(name, DataType::List(item), nullable), Arc::new(array))
+        },
+        Schema::Record(rs) => {
+            if is_ndarray(rs) && !nullable {
+                if let Some(column) = build_tensor(name, column)? {
+                    return Ok(column);
+                }
+            }
+            build_struct(name, column, rs, nullable, names)?
+        },
+        _ => return Err(FcError::CodecError(format!(
+            "Unsupported Avro type for Arrow column '{}': {:?}", name, schema))),
+    })
+}
+
+/// Convert a batch of records into an Arrow record batch.
+///
+/// ndarray fields become fixed-shape tensor columns when all the arrays in the
+/// batch share shape and dtype, and struct columns of (shape, dtype, data) otherwise.
+pub fn records_to_batch(records: &[Decoded], schema: &Schema) -> FcResult<RecordBatch> {
+    let names = Names::new(schema);
+    let rs = match schema {
+        Schema::Record(rs) => rs,
+        _ => return Err(FcError::SchemaError("Arrow requires a record schema".to_string())),
+    };
+
+    let mut fields = Vec::with_capacity(rs.fields.len());
+    let mut arrays = Vec::with_capacity(rs.fields.len());
+    for f in &rs.fields {
+        let column: Vec<Option<&Value>> = records.iter()
+            .map(|r| r.get(&f.name).and_then(strip_union)).collect();
+        let (field, array) = build_column(&f.name, &column, &f.schema, &names)?;
+        fields.push(field);
+        arrays.push(array);
+    }
+
+    Ok(RecordBatch::try_new(Arc::new(ArrowSchema::new(fields)), arrays)?)
+}
+
+fn read_tensor(array: &dyn Array, field: &Field, row: usize) -> FcResult<Value> {
+    let list = array.as_fixed_size_list_opt().ok_or_else(|| array_error(field.name(), array.data_type()))?;
+    let metadata = field.metadata();
+    let shape: Vec<i32> = match metadata.get(EXTENSION_METADATA) {
+        Some(s) => serde_json::from_str::<serde_json::Value>(s)?
+            .get("shape").map(|x| serde_json::from_value(x.clone())).transpose()?
+            .unwrap_or_default(),
+        None => vec![list.value_length()],
+    };
+    let dtype = metadata.get(DTYPE_METADATA).ok_or_else(
+        || FcError::CodecError(format!("Missing dtype of tensor column '{}'", field.name())))?;
+    let descr = TypeDescr::parse(dtype)?;
+
+    let values = list.value(row).to_data();
+    let start = values.offset() * descr.itemsize();
+    let end = start + values.len() * descr.itemsize();
+    let mut data = Vec::with_capacity(end - start);
+    copy_items(&mut data, &values.buffers()[0].as_slice()[start..end], &descr);
+
+    Ok(Value::Record(vec![
+        ("shape".to_string(), Value::Array(shape.into_iter().map(Value::Int).collect())),
+        ("dtype".to_string(), Value::String(dtype.clone())),
+        ("data".to_string(), Value::Bytes(data)),
+    ]))
+}
+
+macro_rules! read_primitive {
+    ($array:expr, $field:expr, $row:expr, $t:ty, $variant:path) => {
+        $array.as_primitive_opt::<$t>().map(|a| $variant(a.value($row)))
+            .ok_or_else(|| array_error($field.name(), $array.data_type()))?
+    };
+}
+
+fn read_value(array: &dyn Array, field: &Field, row: usize, schema: &Schema, names: &Names)
+        -> FcResult<Value> {
+    let schema = names.resolve(schema)?;
+    if let Schema::Union(us) = schema {
+        let (index, inner) = if array.is_null(row) {
+            us.variants().iter().enumerate().find(|(_, s)| **s == Schema::Null)
+                .map(|(i, _)| (i, Value::Null))
+                .ok_or_else(|| FcError::CodecError(format!("Null in column '{}'", field.name())))?
+        } else {
+            let (i, s) = us.variants().iter().enumerate().find(|(_, s)| **s != Schema::Null).unwrap();
+            (i, read_value(array, field, row, s, names)?)
+        };
+        return Ok(Value::Union(index as u32, Box::new(inner)));
+    }
+
+    let err = || array_error(field.name(), array.data_type());
+    Ok(match schema {
+        Schema::Null => Value::Null,
+        Schema::Boolean => Value::Boolean(array.as_boolean_opt().ok_or_else(err)?.value(row)),
+        Schema::Int => read_primitive!(array, field, row, Int32Type, Value::Int),
+        Schema::Long => read_primitive!(array, field, row, Int64Type, Value::Long),
+        Schema::Float => read_primitive!(array, field, row, Float32Type, Value::Float),
+        Schema::Double => read_primitive!(array, field, row, Float64Type, Value::Double),
+        Schema::Date => read_primitive!(array, field, row, Date32Type, Value::Date),
+        Schema::TimeMillis => read_primitive!(array, field, row, Time32MillisecondType, Value::TimeMillis),
+        Schema::TimeMicros => read_primitive!(array, field, row, Time64MicrosecondType, Value::TimeMicros),
+        Schema::TimestampMillis => read_primitive!(
+            array, field, row, TimestampMillisecondType, Value::TimestampMillis),
+        Schema::TimestampMicros => read_primitive!(
+            array, field, row, TimestampMicrosecondType, Value::TimestampMicros),
+        Schema::String => Value::String(
+            array.as_string_opt::<i32>().ok_or_else(err)?.value(row).to_owned()),
+        Schema::Bytes => Value::Bytes(
+            array.as_binary_opt::<i32>().ok_or_else(err)?.value(row).to_vec()),
+        Schema::Enum(es) => {
+            let symbol = array.as_string_opt::<i32>().ok_or_else(err)?.value(row);
+            let index = es.symbols.iter().position(|s| s == symbol).ok_or_else(
+                || FcError::CodecError(format!("Unknown enum symbol: {}", symbol)))?;
+            Value::Enum(index as u32, symbol.to_owned())
+        },
+        Schema::Fixed(fs) => Value::Fixed(
+            fs.size, array.as_fixed_size_binary_opt().ok_or_else(err)?.value(row).to_vec()),
+        Schema::Array(items) => {
+            let list = array.as_list_opt::<i32>().ok_or_else(err)?;
+            let item = match field.data_type() {
+                DataType::List(item) => item.clone(),
+                _ => return Err(err()),
+            };
+            let values = list.value(row);
+            (0..values.len()).map(|i| read_value(&values, &item, i, items, names))
+                .collect::<FcResult<Vec<_>>>().map(Value::Array)?
+        },
+        Schema::Record(rs) => {
+            if let DataType::FixedSizeList(..) = field.data_type() {
+                return read_tensor(array, field, row);
+            }
+            let columns = array.as_struct_opt().ok_or_else(err)?;
+            let mut values = Vec::with_capacity(rs.fields.len());
+            for f in &rs.fields {
+                let (_, child_field) = columns.fields().find(&f.name).ok_or_else(
+                    || FcError::CodecError(format!("Missing column: {}", f.name)))?;
+                let child = columns.column_by_name(&f.name).unwrap();
+                values.push((f.name.clone(), read_value(child, child_field, row, &f.schema, names)?));
+            }
+            Value::Record(values)
+        },
+        _ => return Err(FcError::CodecError(format!(
+            "Unsupported Avro type for Arrow column '{}': {:?}", field.name(), schema))),
+    })
+}
+
+/// Convert an Arrow record batch back into records of the given schema.
+pub fn batch_to_records(batch: &RecordBatch, schema: &Schema) -> FcResult<Vec<Decoded>> {
+    let names = Names::new(schema);
+    let rs = match schema {
+        Schema::Record(rs) => rs,
+        _ => return Err(FcError::SchemaError("Arrow requires a record schema".to_string())),
+    };
+
+    let arrow_schema = batch.schema();
+    let mut columns = Vec::with_capacity(rs.fields.len());
+    for f in &rs.fields {
+        let field = arrow_schema.field_with_name(&f.name)?;
+        let array = batch.column_by_name(&f.name).unwrap();
+        columns.push((f, field, array));
+    }
+
+    (0..batch.num_rows()).map(|row| {
+        columns.iter().map(|(f, field, array)| {
+            Ok((f.name.clone(), read_value(array.as_ref(), field, row, &f.schema, &names)?))
+        }).collect()
+    }).collect()
+}
+
+/// Serialize a record batch in the Arrow IPC streaming format.
+pub fn write_ipc(batch: &RecordBatch) -> FcResult<Encoded> {
+    let mut encoded = Vec::new();
+    let mut writer = StreamWriter::try_new(&mut encoded, &batch.schema())?;
+    writer.write(batch)?;
+    writer.finish()?;
+    drop(writer);
+    Ok(encoded)
+}
+
+/// Read all the record batches in an Arrow IPC stream.
+pub fn read_ipc(bytes: &[u8]) -> FcResult<Vec<RecordBatch>> {
+    let reader = StreamReader::try_new(Cursor::new(bytes), None)?;
+    Ok(reader.collect::<Result<Vec<_>, _>>()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use apache_avro::types::Value;
+    use arrow::datatypes::DataType;
+
+    use crate::columnar::{
+        NDARRAY_EXTENSION, TENSOR_EXTENSION, batch_to_records, read_ipc, records_to_batch, write_ipc,
+    };
+    use crate::schema::{Decoded, json_to_avro_schema};
+
+    fn schema() -> apache_avro::Schema {
+        json_to_avro_schema(&serde_json::json!({
+            "type": "record",
+            "name": "columnar_test",
+            "fields": [
+                {"name": "index", "type": "long"},
+                {"name": "label", "type": ["null", "string"]},
+                {"name": "image", "type": {
+                    "type": "record",
+                    "name": "NDArray",
+                    "fields": [
+                        {"name": "shape", "type": {"type": "array", "items": "int"}},
+                        {"name": "dtype", "type": "string"},
+                        {"name": "data", "type": "bytes"}
+                    ]
+                }}
+            ]
+        }))
+    }
+
+    fn record(index: i64, shape: Vec<i32>, dtype: &str, data: Vec<u8>) -> Decoded {
+        let label = if index % 2 == 0 {
+            Value::Union(1, Box::new(Value::String(format!("{}", index))))
+        } else {
+            Value::Union(0, Box::new(Value::Null))
+        };
+        Decoded::from([
+            ("index".to_string(), Value::Long(index)),
+            ("label".to_string(), label),
+            ("image".to_string(), Value::Record(vec![
+                ("shape".to_string(), Value::Array(shape.into_iter().map(Value::Int).collect())),
+                ("dtype".to_string(), Value::String(dtype.to_string())),
+                ("data".to_string(), Value::Bytes(data)),
+            ])),
+        ])
+    }
+
+    #[test]
+    fn test_tensor_column() {
+        let schema = schema();
+        let records = vec![
+            record(0, vec![2], ">u2", vec![0, 1, 0, 2]),
+            record(1, vec![2], ">u2", vec![1, 0, 2, 0]),
+        ];
+
+        let batch = records_to_batch(&records, &schema).unwrap();
+        let field = batch.schema().field_with_name("image").unwrap().clone();
+        assert_eq!(field.metadata()["ARROW:extension:name"], TENSOR_EXTENSION);
+        assert!(matches!(field.data_type(), DataType::FixedSizeList(_, 2)));
+
+        let batches = read_ipc(&write_ipc(&batch).unwrap()).unwrap();
+        assert_eq!(batches.len(), 1);
+        assert_eq!(batch_to_records(&batches[0], &schema).unwrap(), records);
+    }
+
+    #[test]
+    fn test_ndarray_struct_column() {
+        let schema = schema();
+        let records = vec![
+            record(0, vec![2], "<f4", vec![0; 8]),
+            record(1, vec![1, 3], "<i1", vec![1, 2, 3]),
+        ];
+
+        let batch = records_to_batch(&records, &schema).unwrap();
+        let field = batch.schema().field_with_name("image").unwrap().clone();
+        assert_eq!(field.metadata()["ARROW:extension:name"], NDARRAY_EXTENSION);
+        assert!(matches!(field.data_type(), DataType::Struct(_)));
+
+        let batches = read_ipc(&write_ipc(&batch).unwrap()).unwrap();
+        assert_eq!(batch_to_records(&batches[0], &schema).unwrap(), records);
+
+        // negative dimensions are not valid for a tensor
+        let records = vec![record(0, vec![-2, -1], ">u2", vec![0, 1, 0, 2])];
+        let batch = records_to_batch(&records, &schema).unwrap();
+        let field = batch.schema().field_with_name("image").unwrap().clone();
+        assert!(matches!(field.data_type(), DataType::Struct(_)));
+    }
+}
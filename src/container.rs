@@ -0,0 +1,305 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::Mutex;
+
+use apache_avro::{Decimal, Schema};
+use apache_avro::schema::DecimalSchema;
+use apache_avro::types::Value;
+
+use crate::json::{Names, json_to_avro_impl};
+use crate::schema::Decoded;
+use crate::error::{FcError, FcResult};
+
+fn write_long(buf: &mut Vec<u8>, x: i64) {
+    let mut n = ((x << 1) ^ (x >> 63)) as u64;
+    while n >= 0x80 {
+        buf.push((n as u8) | 0x80);
+        n >>= 7;
+    }
+    buf.push(n as u8);
+}
+
+fn write_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    write_long(buf, bytes.len() as i64);
+    buf.extend_from_slice(bytes);
+}
+
+/// Sign-extend or shrink the big-endian two's complement bytes of a decimal to a fixed size.
+fn decimal_to_fixed(decimal: &Decimal, size: usize) -> FcResult<Vec<u8>> {
+    let bytes = Vec::<u8>::try_from(decimal)?;
+    let sign = if bytes.first().is_some_and(|b| b & 0x80 != 0) { 0xFF } else { 0 };
+    // leading sign bytes are redundant if the next byte has the same sign bit
+    let mut start = 0;
+    while bytes.len() - start > size && start + 1 < bytes.len()
+            && bytes[start] == sign && (bytes[start + 1] & 0x80) == (sign & 0x80) {
+        start += 1;
+    }
+    let n = bytes.len() - start;
+    if n > size {
+        return Err(FcError::SchemaError(format!("Decimal does not fit into {} bytes", size)));
+    }
+    let mut fixed = vec![sign; size - n];
+    fixed.extend_from_slice(&bytes[start..]);
+    Ok(fixed)
+}
+
+fn value_error(value: &Value, schema: &Schema) -> FcError {
+    FcError::SchemaError(format!("Value does not match the schema {:?}: {:?}", schema, value))
+}
+
+/// Whether a value which is not wrapped in a union can be written as a union variant.
+fn matches_variant(value: &Value, schema: &Schema) -> bool {
+    matches!((value, schema),
+        (Value::Null, Schema::Null)
+        | (Value::Boolean(_), Schema::Boolean)
+        | (Value::Int(_), Schema::Int | Schema::Long | Schema::Date | Schema::TimeMillis)
+        | (Value::Long(_), Schema::Long | Schema::TimeMicros | Schema::TimestampMillis | Schema::TimestampMicros)
+        | (Value::Float(_), Schema::Float | Schema::Double)
+        | (Value::Double(_), Schema::Double)
+        | (Value::Bytes(_), Schema::Bytes)
+        | (Value::String(_), Schema::String | Schema::Enum(_))
+        | (Value::Fixed(..), Schema::Fixed(_))
+        | (Value::Enum(..), Schema::Enum(_))
+        | (Value::Array(_), Schema::Array(_))
+        | (Value::Map(_), Schema::Map(_))
+        | (Value::Record(_), Schema::Record(_))
+        | (Value::Date(_), Schema::Date)
+        | (Value::TimeMillis(_), Schema::TimeMillis)
+        | (Value::TimeMicros(_), Schema::TimeMicros)
+        | (Value::TimestampMillis(_), Schema::TimestampMillis)
+        | (Value::TimestampMicros(_), Schema::TimestampMicros)
+        | (Value::Decimal(_), Schema::Decimal(_))
+        | (Value::Uuid(_), Schema::Uuid)
+        | (Value::Duration(_), Schema::Duration))
+}
+
+/// Write a value in the Avro binary encoding.
+pub(crate) fn write_value(buf: &mut Vec<u8>, value: &Value, schema: &Schema, names: &Names) -> FcResult<()> {
+    let schema = names.resolve(schema)?;
+    match (schema, value) {
+        (Schema::Null, Value::Null) => (),
+        (Schema::Boolean, Value::Boolean(b)) => buf.push(*b as u8),
+        (Schema::Int | Schema::Date | Schema::TimeMillis,
+         Value::Int(x) | Value::Date(x) | Value::TimeMillis(x)) => write_long(buf, *x as i64),
+        (Schema::Long | Schema::TimeMicros | Schema::TimestampMillis | Schema::TimestampMicros,
+         Value::Int(x)) => write_long(buf, *x as i64),
+        (Schema::Long | Schema::TimeMicros | Schema::TimestampMillis | Schema::TimestampMicros,
+         Value::Long(x) | Value::TimeMicros(x) | Value::TimestampMillis(x) | Value::TimestampMicros(x)) =>
+            write_long(buf, *x),
+        (Schema::Float, Value::Float(x)) => buf.extend_from_slice(&x.to_le_bytes()),
+        (Schema::Double, Value::Float(x)) => buf.extend_from_slice(&(*x as f64).to_le_bytes()),
+        (Schema::Double, Value::Double(x)) => buf.extend_from_slice(&x.to_le_bytes()),
+        (Schema::Bytes, Value::Bytes(b)) => write_bytes(buf, b),
+        (Schema::String, Value::String(s)) => write_bytes(buf, s.as_bytes()),
+        (Schema::Fixed(fs), Value::Fixed(size, b)) if *size == fs.size && b.len() == fs.size =>
+            buf.extend_from_slice(b),
+        (Schema::Decimal(DecimalSchema { inner, .. }), Value::Decimal(d)) => match names.resolve(inner)? {
+            Schema::Fixed(fs) => buf.extend_from_slice(&decimal_to_fixed(d, fs.size)?),
+            _ => write_bytes(buf, &Vec::<u8>::try_from(d)?),
+        },
+        (Schema::Decimal(DecimalSchema { inner, .. }), Value::Bytes(_) | Value::Fixed(..)) =>
+            write_value(buf, value, inner, names)?,
+        (Schema::Uuid, Value::Uuid(u)) => write_bytes(buf, u.to_string().as_bytes()),
+        (Schema::Uuid, Value::String(s)) => write_bytes(buf, s.as_bytes()),
+        (Schema::Duration, Value::Duration(d)) => buf.extend_from_slice(&<[u8; 12]>::from(*d)),
+        (Schema::Duration, Value::Fixed(12, b)) if b.len() == 12 => buf.extend_from_slice(b),
+        (Schema::Enum(es), Value::Enum(_, s) | Value::String(s)) => {
+            let index = es.symbols.iter().position(|x| x == s).ok_or_else(|| value_error(value, schema))?;
+            write_long(buf, index as i64);
+        },
+        (Schema::Union(us), Value::Union(index, v)) => {
+            let variant = us.variants().get(*index as usize).ok_or_else(|| value_error(value, schema))?;
+            write_long(buf, *index as i64);
+            write_value(buf, v, variant, names)?;
+        },
+        (Schema::Union(us), _) => {
+            let mut found = None;
+            for (i, variant) in us.variants().iter().enumerate() {
+                if matches_variant(value, names.resolve(variant)?) {
+                    found = Some((i, variant));
+                    break;
+                }
+            }
+            let (index, variant) = found.ok_or_else(|| value_error(value, schema))?;
+            write_long(buf, index as i64);
+            write_value(buf, value, variant, names)?;
+        },
+        (Schema::Array(items), Value::Array(values)) => {
+            if !values.is_empty() {
+                write_long(buf, values.len() as i64);
+                for v in values {
+                    write_value(buf, v, items, names)?;
+                }
+            }
+            write_long(buf, 0);
+        },
+        (Schema::Map(values_schema), Value::Map(values)) => {
+            if !values.is_empty() {
+                write_long(buf, values.len() as i64);
+                for (k, v) in values {
+                    write_bytes(buf, k.as_bytes());
+                    write_value(buf, v, values_schema, names)?;
+                }
+            }
+            write_long(buf, 0);
+        },
+        (Schema::Record(rs), Value::Record(fields)) => {
+            for field in &rs.fields {
+                match fields.iter().find(|(k, _)| *k == field.name) {
+                    Some((_, v)) => write_value(buf, v, &field.schema, names)?,
+                    None => write_value(buf, &Value::Null, &field.schema, names).map_err(
+                        |_| FcError::SchemaError(format!("Missing field: {}", field.name)))?,
+                }
+            }
+        },
+        _ => return Err(value_error(value, schema)),
+    }
+    Ok(())
+}
+
+/// A top-level field with its default value.
+struct FieldPlan {
+    name: String,
+    schema: Schema,
+    default: Option<Value>,
+}
+
+/// Writes records of a schema into Avro object containers.
+///
+/// The container header and the field order are computed once and the block
+/// buffer is reused across calls.
+pub(crate) struct ContainerWriter {
+    names: Names,
+    fields: Vec<FieldPlan>,
+    header: Vec<u8>,
+    sync: [u8; 16],
+    block: Mutex<Vec<u8>>,
+}
+
+impl ContainerWriter {
+    pub(crate) fn new(schema: &Schema) -> Self {
+        let names = Names::new(schema);
+        let rs = match schema {
+            Schema::Record(rs) => rs,
+            _ => panic!("Avro encoder requires a record schema"),
+        };
+        let fields = rs.fields.iter().map(|field| FieldPlan {
+            name: field.name.clone(),
+            schema: field.schema.clone(),
+            default: field.default.as_ref().and_then(|d| json_to_avro_impl(d, &field.schema, &names).ok()),
+        }).collect();
+
+        let mut sync = [0u8; 16];
+        for chunk in sync.chunks_mut(8) {
+            let mut hasher = RandomState::new().build_hasher();
+            hasher.write_u128(std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_nanos());
+            chunk.copy_from_slice(&hasher.finish().to_le_bytes());
+        }
+
+        let mut header = b"Obj\x01".to_vec();
+        write_long(&mut header, 2);
+        write_bytes(&mut header, b"avro.schema");
+        write_bytes(&mut header, serde_json::to_string(schema).expect("Failed to serialize schema").as_bytes());
+        write_bytes(&mut header, b"avro.codec");
+        write_bytes(&mut header, b"null");
+        write_long(&mut header, 0);
+        header.extend_from_slice(&sync);
+
+        ContainerWriter {
+            names,
+            fields,
+            header,
+            sync,
+            block: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn write_record(&self, buf: &mut Vec<u8>, datum: &Decoded) -> FcResult<()> {
+        for field in &self.fields {
+            match (datum.get(&field.name), &field.default) {
+                (Some(v), _) | (None, Some(v)) => write_value(buf, v, &field.schema, &self.names)?,
+                (None, None) => write_value(buf, &Value::Null, &field.schema, &self.names).map_err(
+                    |_| FcError::SchemaError(format!("Missing field: {}", field.name)))?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Write the records into a container with a single block.
+    pub(crate) fn write(&self, data: &[Decoded]) -> FcResult<Vec<u8>> {
+        let mut block = self.block.lock().unwrap();
+        block.clear();
+        for datum in data {
+            self.write_record(&mut block, datum)?;
+        }
+
+        // header + 2 varints + block + sync
+        let mut encoded = Vec::with_capacity(self.header.len() + 20 + block.len() + 16);
+        encoded.extend_from_slice(&self.header);
+        if !data.is_empty() {
+            write_long(&mut encoded, data.len() as i64);
+            write_long(&mut encoded, block.len() as i64);
+            encoded.extend_from_slice(&block);
+            encoded.extend_from_slice(&self.sync);
+        }
+        Ok(encoded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apache_avro::Reader;
+    use apache_avro::types::Value;
+
+    use crate::container::ContainerWriter;
+    use crate::schema::{Decoded, json_to_avro_schema};
+
+    #[test]
+    fn test_container_writer() {
+        let schema = json_to_avro_schema(&serde_json::json!({
+            "type": "record",
+            "name": "container_test",
+            "fields": [
+                {"name": "index", "type": "long"},
+                {"name": "label", "type": ["null", "string"]},
+                {"name": "gain", "type": "double", "default": 1.0},
+                {"name": "mode", "type": {"type": "enum", "name": "Mode", "symbols": ["LOW", "HIGH"]}},
+                {"name": "tags", "type": {"type": "map", "values": "int"}}
+            ]
+        }));
+        let writer = ContainerWriter::new(&schema);
+        let datum = Decoded::from([
+            ("index".to_string(), Value::Int(-7)),
+            ("label".to_string(), Value::String("dark".to_string())),
+            ("mode".to_string(), Value::String("HIGH".to_string())),
+            ("tags".to_string(), Value::Map([("run".to_string(), Value::Int(3))].into())),
+        ]);
+
+        // the containers are readable by the reference implementation
+        let bytes = writer.write(&[datum.clone(), datum.clone()]).unwrap();
+        let records: Vec<Value> = Reader::new(&bytes[..]).unwrap().map(Result::unwrap).collect();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1], Value::Record(vec![
+            ("index".to_string(), Value::Long(-7)),
+            ("label".to_string(), Value::Union(1, Box::new(Value::String("dark".to_string())))),
+            ("gain".to_string(), Value::Double(1.0)),
+            ("mode".to_string(), Value::Enum(1, "HIGH".to_string())),
+            ("tags".to_string(), Value::Map([("run".to_string(), Value::Int(3))].into())),
+        ]));
+
+        let mut missing = datum.clone();
+        missing.remove("index");
+        assert!(writer.write(&[missing]).is_err());
+        let mut invalid = datum;
+        invalid.insert("mode".to_string(), Value::String("MEDIUM".to_string()));
+        assert!(writer.write(&[invalid]).is_err());
+    }
+}
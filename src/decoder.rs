@@ -6,20 +6,41 @@
  * Author: Jun Zhu
  */
 use std::collections::HashMap;
+use std::sync::Mutex;
 
 use apache_avro;
 use apache_avro::Reader;
 use apache_avro::types::{Value};
+use serde::de::DeserializeOwned;
 
-use crate::schema::{Encoded, Decoded, json_to_avro_schema};
+#[cfg(feature = "arrow")]
+use crate::columnar::{batch_to_records, read_ipc};
+use crate::borrowed::{DecodedRef, read_block_counts, read_header, read_records};
+use crate::json::{Names, json_to_decoded};
+use crate::node::{cbor, msgpack, node_to_decoded};
+use crate::schema::{Encoded, Decoded, json_to_avro_schema, from_decoded};
+#[cfg(feature = "wasm")]
+use crate::wasm_decoder::{WasmDecoder, WasmLimits};
 use crate::error::{FcError, FcResult};
 
 pub trait Decoder {
     fn unpack(&self, bytes: &Encoded) -> FcResult<Vec<Decoded>>;
 }
 
+/// Typed unpacking on top of any `Decoder`, including boxed trait objects.
+pub trait DecoderExt: Decoder {
+    fn unpack_as<T: DeserializeOwned>(&self, bytes: &Encoded) -> FcResult<Vec<T>> {
+        self.unpack(bytes)?.into_iter().map(from_decoded).collect()
+    }
+}
+
+impl<D: Decoder + ?Sized> DecoderExt for D {}
+
 pub struct AvroDecoder {
     schema: apache_avro::Schema,
+    names: Names,
+    // writer schema which was last checked to match the schema
+    writer_schema: Mutex<Vec<u8>>,
 }
 
 impl AvroDecoder {
@@ -27,14 +48,62 @@ impl AvroDecoder {
         let avro_schema = json_to_avro_schema(&schema);
 
         AvroDecoder {
+            names: Names::new(&avro_schema),
             schema: avro_schema,
+            writer_schema: Mutex::new(Vec::new()),
         }
     }
+
+    /// Check once per writer schema that it matches the schema of the decoder.
+    fn check_writer_schema(&self, writer_schema: &[u8]) -> FcResult<()> {
+        let mut checked = self.writer_schema.lock().unwrap();
+        if checked.as_slice() != writer_schema {
+            let parsed = apache_avro::Schema::parse_str(&String::from_utf8_lossy(writer_schema))?;
+            if parsed.canonical_form() != self.schema.canonical_form() {
+                return Err(FcError::SchemaError(
+                    "Writer schema does not match the schema of the decoder".to_string()));
+            }
+            *checked = writer_schema.to_vec();
+        }
+        Ok(())
+    }
+
+    /// Check that the bytes are an Avro container written with the schema of
+    /// the decoder and return the number of records in each block, without
+    /// decoding the records.
+    pub fn block_counts(&self, bytes: &[u8]) -> FcResult<Vec<usize>> {
+        let header = read_header(bytes)?;
+        self.check_writer_schema(header.schema)?;
+        read_block_counts(bytes, &header)
+    }
+
+    /// Decode without copying bytes and strings, which borrow from the input.
+    ///
+    /// Only uncompressed containers written with the same schema are supported.
+    pub fn unpack_borrowed<'a>(&'a self, bytes: &'a [u8]) -> FcResult<Vec<DecodedRef<'a>>> {
+        let header = read_header(bytes)?;
+        self.check_writer_schema(header.schema)?;
+        Ok(read_records(bytes, &header, &self.schema, &self.names)?
+            .into_iter().map(|fields| fields.into_iter().collect()).collect())
+    }
 }
 
 impl Decoder for AvroDecoder {
     fn unpack(&self, bytes: &Encoded) -> FcResult<Vec<Decoded>> {
-        let reader = Reader::with_schema(&self.schema, &bytes[..]).unwrap();
+        // uncompressed data written with the same schema is decoded without
+        // resolving the writer schema. The reference implementation is used
+        // if that fails
+        if let Ok(header) = read_header(bytes) {
+            if header.is_uncompressed() && self.check_writer_schema(header.schema).is_ok() {
+                if let Ok(records) = read_records(bytes, &header, &self.schema, &self.names) {
+                    return Ok(records.into_iter()
+                        .map(|fields| fields.into_iter().map(|(k, v)| (k.to_owned(), v.to_value())).collect())
+                        .collect());
+                }
+            }
+        }
+
+        let reader = Reader::with_schema(&self.schema, &bytes[..])?;
 
         let mut ret = Vec::new();
         for record in reader {
@@ -55,6 +124,113 @@ impl Decoder for AvroDecoder {
     }
 }
 
+/// Decodes a Json object or an array of Json objects.
+///
+/// The schema determines the Avro type of each field.
+pub struct JsonDecoder {
+    schema: apache_avro::Schema,
+}
+
+impl JsonDecoder {
+    pub fn new(schema: &serde_json::Value) -> Self {
+        JsonDecoder {
+            schema: json_to_avro_schema(schema),
+        }
+    }
+}
+
+impl Decoder for JsonDecoder {
+    fn unpack(&self, bytes: &Encoded) -> FcResult<Vec<Decoded>> {
+        let value: serde_json::Value = serde_json::from_slice(bytes)?;
+        match value {
+            serde_json::Value::Array(items) => {
+                items.iter().map(|x| json_to_decoded(x, &self.schema)).collect()
+            },
+            _ => Ok(vec![json_to_decoded(&value, &self.schema)?]),
+        }
+    }
+}
+
+/// Decodes one or more concatenated MessagePack records.
+///
+/// ndarray fields can be encoded by msgpack-numpy.
+pub struct MsgpackDecoder {
+    schema: apache_avro::Schema,
+}
+
+impl MsgpackDecoder {
+    pub fn new(schema: &serde_json::Value) -> Self {
+        MsgpackDecoder {
+            schema: json_to_avro_schema(schema),
+        }
+    }
+}
+
+impl Decoder for MsgpackDecoder {
+    fn unpack(&self, bytes: &Encoded) -> FcResult<Vec<Decoded>> {
+        let mut reader = &bytes[..];
+        let mut ret = Vec::new();
+        while !reader.is_empty() {
+            let value = rmpv::decode::read_value(&mut reader).map_err(
+                |e| FcError::CodecError(format!("Invalid MessagePack data: {}", e)))?;
+            ret.extend(node_to_decoded(&msgpack::from_value(value)?, &self.schema)?);
+        }
+        Ok(ret)
+    }
+}
+
+/// Decodes one or more concatenated CBOR records.
+pub struct CborDecoder {
+    schema: apache_avro::Schema,
+}
+
+impl CborDecoder {
+    pub fn new(schema: &serde_json::Value) -> Self {
+        CborDecoder {
+            schema: json_to_avro_schema(schema),
+        }
+    }
+}
+
+impl Decoder for CborDecoder {
+    fn unpack(&self, bytes: &Encoded) -> FcResult<Vec<Decoded>> {
+        let mut reader = &bytes[..];
+        let mut ret = Vec::new();
+        while !reader.is_empty() {
+            let value: ciborium::value::Value = ciborium::de::from_reader(&mut reader).map_err(
+                |e| FcError::CodecError(format!("Invalid CBOR data: {}", e)))?;
+            ret.extend(node_to_decoded(&cbor::from_value(value)?, &self.schema)?);
+        }
+        Ok(ret)
+    }
+}
+
+/// Decodes all the record batches in an Arrow IPC stream.
+#[cfg(feature = "arrow")]
+pub struct ArrowDecoder {
+    schema: apache_avro::Schema,
+}
+
+#[cfg(feature = "arrow")]
+impl ArrowDecoder {
+    pub fn new(schema: &serde_json::Value) -> Self {
+        ArrowDecoder {
+            schema: json_to_avro_schema(schema),
+        }
+    }
+}
+
+#[cfg(feature = "arrow")]
+impl Decoder for ArrowDecoder {
+    fn unpack(&self, bytes: &Encoded) -> FcResult<Vec<Decoded>> {
+        let mut ret = Vec::new();
+        for batch in read_ipc(bytes)? {
+            ret.extend(batch_to_records(&batch, &self.schema)?);
+        }
+        Ok(ret)
+    }
+}
+
 pub struct PickleDecoder;
 
 impl Decoder for PickleDecoder {
@@ -67,8 +243,19 @@ impl Decoder for PickleDecoder {
 }
 
 pub fn create_decoder(name: &str, schema: Option<&serde_json::Value>) -> Box<dyn Decoder + Send> {
+    // the path of the module is case-sensitive
+    #[cfg(feature = "wasm")]
+    if let Some(path) = name.strip_prefix("wasm:") {
+        return Box::new(WasmDecoder::new(path, schema.unwrap(), WasmLimits::default())
+            .expect("Failed to load the WebAssembly decoder"));
+    }
     match name.to_lowercase().as_str() {
         "avro" => Box::new(AvroDecoder::new(schema.unwrap())),
+        "json" => Box::new(JsonDecoder::new(schema.unwrap())),
+        "msgpack" => Box::new(MsgpackDecoder::new(schema.unwrap())),
+        "cbor" => Box::new(CborDecoder::new(schema.unwrap())),
+        #[cfg(feature = "arrow")]
+        "arrow" => Box::new(ArrowDecoder::new(schema.unwrap())),
         "pickle" => Box::new(PickleDecoder),
         _ => panic!("Unknown decoder name: {}", name),
     }
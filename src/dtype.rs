@@ -0,0 +1,222 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use crate::error::{FcError, FcResult};
+
+/// Element types supported in the "dtype" field of ndarray records.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DType {
+    Bool,
+    Int8,
+    UInt8,
+    Int16,
+    UInt16,
+    Int32,
+    UInt32,
+    Int64,
+    UInt64,
+    Float32,
+    Float64,
+}
+
+/// Parsed NumPy type string, e.g. "<f4", ">i2", "|u1" or "float64".
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TypeDescr {
+    pub dtype: DType,
+    pub big_endian: bool,
+}
+
+macro_rules! decode_items {
+    ($t:ty, $data:expr, $big_endian:expr) => {
+        $data.chunks_exact(std::mem::size_of::<$t>()).map(|c| {
+            let bytes = c.try_into().unwrap();
+            (if $big_endian { <$t>::from_be_bytes(bytes) } else { <$t>::from_le_bytes(bytes) }) as f64
+        }).collect()
+    };
+}
+
+macro_rules! encode_items {
+    ($t:ty, $values:expr, $big_endian:expr) => {
+        $values.iter().flat_map(|&v| {
+            let v = v as $t;
+            if $big_endian { v.to_be_bytes() } else { v.to_le_bytes() }
+        }).collect()
+    };
+}
+
+fn range_error<T: std::fmt::Display>(value: T, dtype: DType) -> FcError {
+    FcError::SchemaError(format!("Value {} cannot be converted to {:?}", value, dtype))
+}
+
+macro_rules! encode_floats_as_integers {
+    ($t:ty, $values:expr, $big_endian:expr, $dtype:expr) => {{
+        // the bounds are powers of two, which are exact as f64
+        let (min, end) = if <$t>::MIN == 0 {
+            (0., <$t>::MAX as f64 + 1.)
+        } else {
+            (<$t>::MIN as f64, -(<$t>::MIN as f64))
+        };
+        let mut ret = Vec::with_capacity($values.len() * std::mem::size_of::<$t>());
+        for &v in $values {
+            if v.fract() != 0. || v < min || v >= end {
+                return Err(range_error(v, $dtype));
+            }
+            let v = v as $t;
+            ret.extend(if $big_endian { v.to_be_bytes() } else { v.to_le_bytes() });
+        }
+        ret
+    }};
+}
+
+macro_rules! encode_integers {
+    ($t:ty, $values:expr, $big_endian:expr, $dtype:expr) => {{
+        let mut ret = Vec::with_capacity($values.len() * std::mem::size_of::<$t>());
+        for &v in $values {
+            let v = <$t>::try_from(v).map_err(|_| range_error(v, $dtype))?;
+            ret.extend(if $big_endian { v.to_be_bytes() } else { v.to_le_bytes() });
+        }
+        ret
+    }};
+}
+
+impl TypeDescr {
+    pub fn parse(s: &str) -> FcResult<Self> {
+        let (big_endian, code) = match s.chars().next() {
+            Some('>') => (true, &s[1..]),
+            Some('<') | Some('|') => (false, &s[1..]),
+            Some('=') => (cfg!(target_endian = "big"), &s[1..]),
+            _ => (cfg!(target_endian = "big"), s),
+        };
+
+        let dtype = match code {
+            "?" | "b1" | "bool" => DType::Bool,
+            "i1" | "int8" => DType::Int8,
+            "u1" | "uint8" => DType::UInt8,
+            "i2" | "int16" => DType::Int16,
+            "u2" | "uint16" => DType::UInt16,
+            "i4" | "int32" => DType::Int32,
+            "u4" | "uint32" => DType::UInt32,
+            "i8" | "int64" => DType::Int64,
+            "u8" | "uint64" => DType::UInt64,
+            "f4" | "float32" => DType::Float32,
+            "f8" | "float64" => DType::Float64,
+            _ => return Err(FcError::SchemaError(format!("Unsupported dtype: {}", s))),
+        };
+
+        Ok(TypeDescr { dtype, big_endian })
+    }
+
+    /// Size of a single element in bytes.
+    pub fn itemsize(&self) -> usize {
+        match self.dtype {
+            DType::Bool | DType::Int8 | DType::UInt8 => 1,
+            DType::Int16 | DType::UInt16 => 2,
+            DType::Int32 | DType::UInt32 | DType::Float32 => 4,
+            DType::Int64 | DType::UInt64 | DType::Float64 => 8,
+        }
+    }
+
+    /// Convert the raw bytes of an array into f64 values.
+    pub fn to_f64(&self, data: &[u8]) -> FcResult<Vec<f64>> {
+        if !data.len().is_multiple_of(self.itemsize()) {
+            return Err(FcError::SchemaError(format!(
+                "Data size {} is not a multiple of the item size {}", data.len(), self.itemsize())));
+        }
+
+        let be = self.big_endian;
+        Ok(match self.dtype {
+            DType::Bool => data.iter().map(|&x| if x != 0 { 1. } else { 0. }).collect(),
+            DType::Int8 => data.iter().map(|&x| x as i8 as f64).collect(),
+            DType::UInt8 => data.iter().map(|&x| x as f64).collect(),
+            DType::Int16 => decode_items!(i16, data, be),
+            DType::UInt16 => decode_items!(u16, data, be),
+            DType::Int32 => decode_items!(i32, data, be),
+            DType::UInt32 => decode_items!(u32, data, be),
+            DType::Int64 => decode_items!(i64, data, be),
+            DType::UInt64 => decode_items!(u64, data, be),
+            DType::Float32 => decode_items!(f32, data, be),
+            DType::Float64 => decode_items!(f64, data, be),
+        })
+    }
+
+    /// Convert f64 values into the raw bytes of an array.
+    ///
+    /// Values which are not integral or out of range are rejected for integer
+    /// dtypes.
+    pub fn from_f64(&self, values: &[f64]) -> FcResult<Vec<u8>> {
+        let (be, dtype) = (self.big_endian, self.dtype);
+        Ok(match dtype {
+            DType::Bool => values.iter().map(|&x| (x != 0.) as u8).collect(),
+            DType::Int8 => encode_floats_as_integers!(i8, values, be, dtype),
+            DType::UInt8 => encode_floats_as_integers!(u8, values, be, dtype),
+            DType::Int16 => encode_floats_as_integers!(i16, values, be, dtype),
+            DType::UInt16 => encode_floats_as_integers!(u16, values, be, dtype),
+            DType::Int32 => encode_floats_as_integers!(i32, values, be, dtype),
+            DType::UInt32 => encode_floats_as_integers!(u32, values, be, dtype),
+            DType::Int64 => encode_floats_as_integers!(i64, values, be, dtype),
+            DType::UInt64 => encode_floats_as_integers!(u64, values, be, dtype),
+            DType::Float32 => encode_items!(f32, values, be),
+            DType::Float64 => encode_items!(f64, values, be),
+        })
+    }
+
+    /// Convert integers into the raw bytes of an array without going through
+    /// f64, which cannot represent all 64-bit integers.
+    ///
+    /// Values out of range are rejected.
+    pub fn from_integers(&self, values: &[i128]) -> FcResult<Vec<u8>> {
+        let (be, dtype) = (self.big_endian, self.dtype);
+        Ok(match dtype {
+            DType::Bool => values.iter().map(|&x| (x != 0) as u8).collect(),
+            DType::Int8 => encode_integers!(i8, values, be, dtype),
+            DType::UInt8 => encode_integers!(u8, values, be, dtype),
+            DType::Int16 => encode_integers!(i16, values, be, dtype),
+            DType::UInt16 => encode_integers!(u16, values, be, dtype),
+            DType::Int32 => encode_integers!(i32, values, be, dtype),
+            DType::UInt32 => encode_integers!(u32, values, be, dtype),
+            DType::Int64 => encode_integers!(i64, values, be, dtype),
+            DType::UInt64 => encode_integers!(u64, values, be, dtype),
+            DType::Float32 => encode_items!(f32, values, be),
+            DType::Float64 => encode_items!(f64, values, be),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dtype::{DType, TypeDescr};
+
+    #[test]
+    fn test_parse_dtype() {
+        assert_eq!(TypeDescr::parse(">f4").unwrap(), TypeDescr { dtype: DType::Float32, big_endian: true });
+        assert_eq!(TypeDescr::parse("<u2").unwrap(), TypeDescr { dtype: DType::UInt16, big_endian: false });
+        assert_eq!(TypeDescr::parse("|u1").unwrap().itemsize(), 1);
+        assert_eq!(TypeDescr::parse("float64").unwrap().dtype, DType::Float64);
+        assert!(TypeDescr::parse("c16").is_err());
+    }
+
+    #[test]
+    fn test_dtype_conversion() {
+        let values = vec![1., -2., 3.5];
+
+        let descr = TypeDescr::parse(">f4").unwrap();
+        let data = descr.from_f64(&values).unwrap();
+        assert_eq!(&data[..4], &1f32.to_be_bytes());
+        assert_eq!(descr.to_f64(&data).unwrap(), values);
+
+        // integer dtypes reject values which are not integral or out of range
+        let descr = TypeDescr::parse("<i2").unwrap();
+        assert!(descr.from_f64(&values).is_err());
+        assert!(descr.from_f64(&[32768.]).is_err());
+        assert_eq!(descr.to_f64(&descr.from_f64(&[1., -2., -32768.]).unwrap()).unwrap(), vec![1., -2., -32768.]);
+        assert!(descr.to_f64(&[0, 1, 2]).is_err());
+
+        let descr = TypeDescr::parse("<u8").unwrap();
+        assert_eq!(descr.from_integers(&[u64::MAX as i128]).unwrap(), u64::MAX.to_le_bytes());
+        assert!(descr.from_integers(&[-1]).is_err());
+    }
+}
@@ -5,18 +5,43 @@
  *
  * Author: Jun Zhu
  */
-use apache_avro::Writer;
-use apache_avro::types::{Record};
+use serde::Serialize;
 
-use crate::schema::{Encoded, Decoded, json_to_avro_schema};
-use crate::error::{FcResult};
+#[cfg(feature = "arrow")]
+use crate::columnar::{records_to_batch, write_ipc};
+use crate::container::ContainerWriter;
+use crate::json::decoded_to_json;
+use crate::node::{cbor, decoded_to_node, msgpack};
+use crate::schema::{Encoded, Decoded, json_to_avro_schema, to_decoded};
+use crate::error::{FcError, FcResult};
 
 pub trait Encoder {
     fn pack(&self, data: &Decoded) -> FcResult<Encoded>;
+
+    /// Pack a batch of records into a single message.
+    ///
+    /// By default, the packed records are concatenated.
+    fn pack_batch(&self, data: &[Decoded]) -> FcResult<Encoded> {
+        let mut encoded = Vec::new();
+        for datum in data {
+            encoded.extend(self.pack(datum)?);
+        }
+        Ok(encoded)
+    }
 }
 
+/// Typed packing on top of any `Encoder`, including boxed trait objects.
+pub trait EncoderExt: Encoder {
+    fn pack_serde<T: Serialize>(&self, datum: &T) -> FcResult<Encoded> {
+        self.pack(&to_decoded(datum)?)
+    }
+}
+
+impl<E: Encoder + ?Sized> EncoderExt for E {}
+
+/// Encodes records into an uncompressed Avro object container.
 pub struct AvroEncoder {
-    schema: apache_avro::Schema,
+    writer: ContainerWriter,
 }
 
 impl AvroEncoder {
@@ -24,25 +49,95 @@ impl AvroEncoder {
         let avro_schema = json_to_avro_schema(&schema);
 
         AvroEncoder {
-            schema: avro_schema,
+            writer: ContainerWriter::new(&avro_schema),
         }
     }
 }
 
 impl Encoder for AvroEncoder {
     fn pack(&self, datum: &Decoded) -> FcResult<Encoded> {
-        let mut writer = Writer::new(&self.schema, Vec::new());
-        let mut record = Record::new(&self.schema).unwrap();
-        for (k, v) in datum {
-            record.put(&k, v.clone());
-        }
-        let _ = writer.append(record);
+        self.writer.write(std::slice::from_ref(datum))
+    }
+
+    fn pack_batch(&self, data: &[Decoded]) -> FcResult<Encoded> {
+        self.writer.write(data)
+    }
+}
+
+/// Encodes a record as a Json object.
+///
+/// Bytes are encoded as base64 strings.
+pub struct JsonEncoder;
+
+impl Encoder for JsonEncoder {
+    fn pack(&self, datum: &Decoded) -> FcResult<Encoded> {
+        Ok(serde_json::to_vec(&decoded_to_json(datum)?)?)
+    }
+
+    fn pack_batch(&self, data: &[Decoded]) -> FcResult<Encoded> {
+        let items = data.iter().map(decoded_to_json).collect::<FcResult<Vec<_>>>()?;
+        Ok(serde_json::to_vec(&items)?)
+    }
+}
+
+/// Encodes a record as a MessagePack map.
+///
+/// ndarray fields are encoded in the layout of msgpack-numpy.
+pub struct MsgpackEncoder;
 
-        let encoded = writer.into_inner()?;
+impl Encoder for MsgpackEncoder {
+    fn pack(&self, datum: &Decoded) -> FcResult<Encoded> {
+        let value = msgpack::to_value(decoded_to_node(datum, true)?)?;
+        let mut encoded = Vec::new();
+        rmpv::encode::write_value(&mut encoded, &value).map_err(
+            |e| FcError::CodecError(format!("Failed to encode MessagePack data: {}", e)))?;
         Ok(encoded)
     }
 }
 
+/// Encodes a record as a CBOR map.
+pub struct CborEncoder;
+
+impl Encoder for CborEncoder {
+    fn pack(&self, datum: &Decoded) -> FcResult<Encoded> {
+        let value = cbor::to_value(decoded_to_node(datum, false)?)?;
+        let mut encoded = Vec::new();
+        ciborium::ser::into_writer(&value, &mut encoded).map_err(
+            |e| FcError::CodecError(format!("Failed to encode CBOR data: {}", e)))?;
+        Ok(encoded)
+    }
+}
+
+/// Encodes records as an Arrow IPC stream with a single record batch.
+///
+/// The producers publish one entry per record, so each entry written with
+/// `pack` is a stream with a one-row batch. Use `pack_batch` to get larger
+/// batches, e.g. for files.
+#[cfg(feature = "arrow")]
+pub struct ArrowEncoder {
+    schema: apache_avro::Schema,
+}
+
+#[cfg(feature = "arrow")]
+impl ArrowEncoder {
+    pub fn new(schema: &serde_json::Value) -> Self {
+        ArrowEncoder {
+            schema: json_to_avro_schema(schema),
+        }
+    }
+}
+
+#[cfg(feature = "arrow")]
+impl Encoder for ArrowEncoder {
+    fn pack(&self, datum: &Decoded) -> FcResult<Encoded> {
+        self.pack_batch(std::slice::from_ref(datum))
+    }
+
+    fn pack_batch(&self, data: &[Decoded]) -> FcResult<Encoded> {
+        write_ipc(&records_to_batch(data, &self.schema)?)
+    }
+}
+
 pub struct PickleEncoder;
 
 impl Encoder for PickleEncoder {
@@ -54,6 +149,11 @@ impl Encoder for PickleEncoder {
 pub fn create_encoder(name: &str, schema: Option<&serde_json::Value>) -> Box<dyn Encoder + Send> {
     match name.to_lowercase().as_str() {
         "avro" => Box::new(AvroEncoder::new(schema.unwrap())),
+        "json" => Box::new(JsonEncoder),
+        "msgpack" => Box::new(MsgpackEncoder),
+        "cbor" => Box::new(CborEncoder),
+        #[cfg(feature = "arrow")]
+        "arrow" => Box::new(ArrowEncoder::new(schema.unwrap())),
         "pickle" => {
             assert!(schema.is_none());
             Box::new(PickleEncoder)
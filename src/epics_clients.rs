@@ -0,0 +1,320 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Lines};
+use std::process::{Child, ChildStdout, Command, Stdio};
+
+use apache_avro::Schema;
+use apache_avro::types::Value;
+
+use crate::dtype::{DType, TypeDescr};
+use crate::json::{Names, is_ndarray};
+use crate::schema::{Decoded, parse_avro_schema};
+use crate::error::{FcError, FcResult};
+
+/// Alarm status printed by the monitor tools before the severity.
+const ALARM_STATUS: [&str; 22] = [
+    "READ", "WRITE", "HIHI", "HIGH", "LOLO", "LOW", "STATE", "COS", "COMM", "TIMEOUT", "HWLIMIT",
+    "CALC", "SCAN", "LINK", "SOFT", "BAD_SUB", "UDF", "DISABLE", "SIMM", "READ_ACCESS",
+    "WRITE_ACCESS", "NO_ALARM",
+];
+
+/// Alarm severity printed by the monitor tools after the value.
+const ALARM_SEVERITY: [&str; 4] = ["NO_ALARM", "MINOR", "MAJOR", "INVALID"];
+
+/// Parse a line printed by camonitor or pvmonitor, e.g.
+///
+/// "BL:MOTOR.RBV 2023-08-01 12:00:00.123456 1.5" or
+/// "BL:WAVEFORM 2023-08-01 12:00:00.123456 3 1 2 3".
+///
+/// Returns the PV name and the tokens after the timestamp, without the alarm
+/// status and severity (e.g. "HIGH MAJOR"), or None as the tokens if the PV
+/// is disconnected.
+pub fn parse_monitor_line(line: &str) -> Option<(String, Option<Vec<String>>)> {
+    let tokens: Vec<&str> = line.split_whitespace().collect();
+    match tokens[..] {
+        [pv, "***", ..] | [pv, "<undefined>", ..] => Some((pv.to_owned(), None)),
+        [pv, _date, _time, ref values @ ..] if !values.is_empty() => {
+            let values = match values {
+                [rest @ .., status, severity]
+                    if !rest.is_empty() && ALARM_STATUS.contains(status) && ALARM_SEVERITY.contains(severity) => rest,
+                _ => values,
+            };
+            Some((pv.to_owned(), Some(values.iter().map(|s| s.to_string()).collect())))
+        },
+        _ => None,
+    }
+}
+
+fn parse_error(pv: &str, tokens: &[String]) -> FcError {
+    FcError::CodecError(format!("Cannot convert the value of PV {}: {:?}", pv, tokens))
+}
+
+fn parse_token<T: std::str::FromStr>(pv: &str, tokens: &[String]) -> FcResult<T> {
+    tokens.first().and_then(|s| s.parse().ok()).ok_or_else(|| parse_error(pv, tokens))
+}
+
+/// Split the tokens of an array value, which start with the number of elements.
+fn array_tokens<'a>(pv: &str, tokens: &'a [String]) -> FcResult<&'a [String]> {
+    let count: usize = parse_token(pv, tokens)?;
+    tokens.get(1..count + 1).ok_or_else(|| parse_error(pv, tokens))
+}
+
+/// Convert the tokens of a PV value to an Avro value of the given schema.
+///
+/// Arrays of numbers can be converted to ndarray records of float64.
+pub(crate) fn tokens_to_value(pv: &str, tokens: &[String], schema: &Schema, names: &Names) -> FcResult<Value> {
+    Ok(match names.resolve(schema)? {
+        Schema::Boolean => match tokens.first().map(|s| s.to_ascii_lowercase()).as_deref() {
+            Some("1") | Some("true") | Some("on") => Value::Boolean(true),
+            Some("0") | Some("false") | Some("off") => Value::Boolean(false),
+            _ => return Err(parse_error(pv, tokens)),
+        },
+        Schema::Int => Value::Int(parse_token(pv, tokens)?),
+        Schema::Long => Value::Long(parse_token(pv, tokens)?),
+        Schema::Float => Value::Float(parse_token(pv, tokens)?),
+        Schema::Double => Value::Double(parse_token(pv, tokens)?),
+        Schema::String => Value::String(tokens.join(" ")),
+        Schema::Enum(es) => {
+            let symbol = tokens.join(" ");
+            let index = es.symbols.iter().position(|s| *s == symbol).ok_or_else(
+                || parse_error(pv, tokens))?;
+            Value::Enum(index as u32, symbol)
+        },
+        Schema::Array(items) => Value::Array(array_tokens(pv, tokens)?.iter()
+            .map(|t| tokens_to_value(pv, std::slice::from_ref(t), items, names))
+            .collect::<FcResult<Vec<_>>>()?),
+        Schema::Record(rs) if is_ndarray(rs) => {
+            let values = array_tokens(pv, tokens)?.iter()
+                .map(|t| t.parse::<f64>().map_err(|_| parse_error(pv, tokens)))
+                .collect::<FcResult<Vec<_>>>()?;
+            let descr = TypeDescr { dtype: DType::Float64, big_endian: false };
+            Value::Record(vec![
+                ("shape".to_string(), Value::Array(vec![Value::Int(values.len() as i32)])),
+                ("dtype".to_string(), Value::String("<f8".to_string())),
+                ("data".to_string(), Value::Bytes(descr.from_f64(&values)?)),
+            ])
+        },
+        Schema::Union(us) => {
+            let (index, inner) = us.variants().iter().enumerate()
+                .find(|(_, s)| **s != Schema::Null).ok_or_else(|| parse_error(pv, tokens))?;
+            Value::Union(index as u32, Box::new(tokens_to_value(pv, tokens, inner, names)?))
+        },
+        _ => return Err(FcError::SchemaError(format!("Unsupported type for PV {}: {:?}", pv, schema))),
+    })
+}
+
+/// Value of a disconnected PV: null if the field is nullable.
+fn null_value(schema: &Schema, names: &Names) -> Option<Value> {
+    match names.resolve(schema).ok()? {
+        Schema::Union(us) => us.variants().iter().position(|s| *s == Schema::Null)
+            .map(|i| Value::Union(i as u32, Box::new(Value::Null))),
+        _ => None,
+    }
+}
+
+struct Channel {
+    field: String,
+    schema: Schema,
+    value: Option<Value>,
+}
+
+/// Monitors EPICS PVs and assembles their values into records.
+///
+/// The PVs are monitored with the EPICS base tools "camonitor" (Channel Access)
+/// or "pvmonitor" (PV Access). A record is produced on every update once all
+/// the PVs have a value.
+pub struct EpicsConsumer {
+    child: Child,
+    lines: Lines<BufReader<ChildStdout>>,
+    names: Names,
+    channels: HashMap<String, Channel>,
+    // nullable fields without PV
+    defaults: Vec<(String, Value)>,
+    // updates which could not be converted
+    skipped: u64,
+}
+
+impl EpicsConsumer {
+    /// Monitor the PVs given as (field, PV name) pairs.
+    ///
+    /// Protocol is either "ca" or "pva".
+    pub fn new(schema: &serde_json::Value, pvs: &[(String, String)], protocol: &str) -> FcResult<Self> {
+        let avro_schema = parse_avro_schema(schema)?;
+        let names = Names::new(&avro_schema);
+        let rs = match &avro_schema {
+            Schema::Record(rs) => rs,
+            _ => return Err(FcError::SchemaError("EPICS source requires a record schema".to_string())),
+        };
+
+        let mut channels = HashMap::new();
+        for (field, pv) in pvs {
+            let schema = match rs.lookup.get(field) {
+                Some(&i) => rs.fields[i].schema.clone(),
+                None => return Err(FcError::SchemaError(format!("Unknown field for PV {}: {}", pv, field))),
+            };
+            channels.insert(pv.clone(), Channel { field: field.clone(), schema, value: None });
+        }
+        let mut defaults = Vec::new();
+        for field in rs.fields.iter().filter(|f| !pvs.iter().any(|(name, _)| name == &f.name)) {
+            match null_value(&field.schema, &names) {
+                Some(v) => defaults.push((field.name.clone(), v)),
+                None => return Err(FcError::SchemaError(
+                    format!("No PV for the non-nullable field: {}", field.name))),
+            }
+        }
+
+        let program = match protocol.to_ascii_lowercase().as_str() {
+            "ca" => "camonitor",
+            "pva" => "pvmonitor",
+            _ => return Err(FcError::IoError(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput, format!("Unknown EPICS protocol: {}", protocol)))),
+        };
+        let mut child = Command::new(program)
+            .args(pvs.iter().map(|(_, pv)| pv))
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let lines = BufReader::new(child.stdout.take().unwrap()).lines();
+
+        Ok(EpicsConsumer {
+            child,
+            lines,
+            names,
+            channels,
+            defaults,
+            skipped: 0,
+        })
+    }
+
+    /// Update the value of a PV from a monitor line and return whether it changed.
+    fn update(&mut self, line: &str) -> FcResult<bool> {
+        let (pv, tokens) = match parse_monitor_line(line) {
+            Some(x) => x,
+            None => return Ok(false),
+        };
+        let channel = match self.channels.get_mut(&pv) {
+            Some(c) => c,
+            None => return Ok(false),
+        };
+        channel.value = match tokens {
+            Some(tokens) => Some(tokens_to_value(&pv, &tokens, &channel.schema, &self.names)?),
+            None => null_value(&channel.schema, &self.names),
+        };
+        Ok(true)
+    }
+
+    fn record(&self) -> Option<Decoded> {
+        let mut record: Decoded = self.channels.values()
+            .map(|c| c.value.clone().map(|v| (c.field.clone(), v)))
+            .collect::<Option<_>>()?;
+        record.extend(self.defaults.iter().cloned());
+        Some(record)
+    }
+
+    /// Block until a PV is updated and return the latest values of all the PVs.
+    ///
+    /// Updates which cannot be converted are skipped and counted.
+    pub fn consume(&mut self) -> FcResult<Decoded> {
+        loop {
+            let line = match self.lines.next() {
+                Some(line) => line?,
+                None => return Err(FcError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::UnexpectedEof, "EPICS monitor exited"))),
+            };
+            match self.update(&line) {
+                Ok(true) => if let Some(record) = self.record() {
+                    return Ok(record);
+                },
+                Ok(false) => (),
+                Err(_) => self.skipped += 1,
+            }
+        }
+    }
+
+    /// Number of updates skipped because their values could not be converted.
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+}
+
+impl Drop for EpicsConsumer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apache_avro::types::Value;
+
+    use crate::epics_clients::{EpicsConsumer, parse_monitor_line, tokens_to_value};
+    use crate::error::FcError;
+    use crate::json::Names;
+    use crate::schema::json_to_avro_schema;
+
+    #[test]
+    fn test_parse_monitor_line() {
+        let (pv, tokens) = parse_monitor_line("BL:MOTOR.RBV 2023-08-01 12:00:00.123456 1.5").unwrap();
+        assert_eq!(pv, "BL:MOTOR.RBV");
+        assert_eq!(tokens.unwrap(), vec!["1.5"]);
+
+        let (pv, tokens) = parse_monitor_line("BL:MOTOR.RBV *** disconnected").unwrap();
+        assert_eq!(pv, "BL:MOTOR.RBV");
+        assert!(tokens.is_none());
+
+        let (_, tokens) = parse_monitor_line(
+            "BL:STATUS 2023-08-01 12:00:00.123456 too hot HIHI MAJOR").unwrap();
+        assert_eq!(tokens.unwrap(), vec!["too", "hot"]);
+
+        assert!(parse_monitor_line("").is_none());
+    }
+
+    #[test]
+    fn test_tokens_to_value() {
+        let schema = json_to_avro_schema(&serde_json::json!({
+            "type": "record",
+            "name": "epics_test",
+            "fields": [
+                {"name": "waveform", "type": {"type": "array", "items": "int"}},
+                {"name": "temperature", "type": ["null", "double"]}
+            ]
+        }));
+        let names = Names::new(&schema);
+        let fields = match &schema {
+            apache_avro::Schema::Record(rs) => &rs.fields,
+            _ => unreachable!(),
+        };
+        let tokens = |s: &str| s.split(' ').map(|x| x.to_string()).collect::<Vec<_>>();
+
+        assert_eq!(tokens_to_value("PV", &tokens("3 1 2 3 HIGH MINOR"), &fields[0].schema, &names).unwrap(),
+                   Value::Array(vec![Value::Int(1), Value::Int(2), Value::Int(3)]));
+        assert_eq!(tokens_to_value("PV", &tokens("21.5"), &fields[1].schema, &names).unwrap(),
+                   Value::Union(1, Box::new(Value::Double(21.5))));
+        assert!(tokens_to_value("PV", &tokens("abc"), &fields[1].schema, &names).is_err());
+    }
+
+    #[test]
+    fn test_epics_consumer_configuration_errors() {
+        let schema = serde_json::json!({
+            "type": "record",
+            "name": "epics_test",
+            "fields": [
+                {"name": "temperature", "type": "double"},
+                {"name": "comment", "type": ["null", "string"]}
+            ]
+        });
+        let pvs = |field: &str| vec![(field.to_string(), "BL:TEMPERATURE".to_string())];
+
+        assert!(matches!(EpicsConsumer::new(&schema, &pvs("pressure"), "ca"), Err(FcError::SchemaError(_))));
+        assert!(matches!(EpicsConsumer::new(&schema, &pvs("comment"), "ca"), Err(FcError::SchemaError(_))));
+        assert!(matches!(EpicsConsumer::new(&schema, &pvs("temperature"), "xyz"), Err(FcError::IoError(_))));
+        assert!(matches!(EpicsConsumer::new(&serde_json::json!("double"), &[], "ca"),
+                         Err(FcError::SchemaError(_))));
+    }
+}
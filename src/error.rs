@@ -20,4 +20,35 @@ pub enum FcError {
     RedisError(#[from] redis::RedisError),
     #[error("Zmq error")]
     ZmqError(#[from] zmq::Error),
+    #[error("Json error")]
+    JsonError(#[from] serde_json::Error),
+    #[error("Schema error: {0}")]
+    SchemaError(String),
+    #[error("Codec error: {0}")]
+    CodecError(String),
+    #[error("IO error")]
+    IoError(#[from] std::io::Error),
+    #[error("Http error: {0}")]
+    HttpError(String),
+    #[cfg(feature = "arrow")]
+    #[error("Arrow error")]
+    ArrowError(#[from] arrow::error::ArrowError),
+    #[cfg(feature = "hdf5")]
+    #[error("HDF5 error")]
+    Hdf5Error(#[from] hdf5::Error),
+    #[cfg(feature = "parquet")]
+    #[error("Parquet error")]
+    ParquetError(#[from] parquet::errors::ParquetError),
+    #[cfg(feature = "script")]
+    #[error("Script error")]
+    ScriptError(#[from] Box<rhai::EvalAltResult>),
+    #[cfg(feature = "wasm")]
+    #[error("Wasm error: {0}")]
+    WasmError(String),
+    #[cfg(feature = "kafka")]
+    #[error("Kafka error")]
+    KafkaError(#[from] rdkafka::error::KafkaError),
+    #[cfg(feature = "mqtt")]
+    #[error("Mqtt error")]
+    MqttError(#[from] rumqttc::ClientError),
 }
@@ -0,0 +1,250 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::collections::{BTreeMap, HashMap};
+use std::time::{Duration, Instant};
+
+use apache_avro::types::Value;
+
+use crate::schema::{Decoded, field_value, record_schema};
+use crate::error::{FcError, FcResult};
+
+/// Return the field name of a stream in the merged records, e.g. "ns:det-1" -> "ns_det_1".
+pub fn stream_field(stream: &str) -> String {
+    stream.chars().map(|c| if c.is_ascii_alphanumeric() { c } else { '_' }).collect()
+}
+
+/// Return the field names of the streams, failing if two of them, or one of
+/// them and the key, are the same.
+fn stream_fields(key: &str, streams: &[(String, serde_json::Value)]) -> FcResult<Vec<String>> {
+    let mut names: Vec<String> = Vec::with_capacity(streams.len());
+    for (stream, _) in streams {
+        let field = stream_field(stream);
+        if field == key || names.contains(&field) {
+            return Err(FcError::SchemaError(format!(
+                "Field of stream {} collides with another field: {}", stream, field)));
+        }
+        names.push(field);
+    }
+    Ok(names)
+}
+
+/// Build the schema of the merged records.
+///
+/// The merged record has the key field and a nullable record field per stream,
+/// which is null if the stream did not contribute to an incomplete event.
+pub fn combined_schema(namespace: &str, name: &str, key: &str,
+                       streams: &[(String, serde_json::Value)]) -> FcResult<serde_json::Value> {
+    let names = stream_fields(key, streams)?;
+    let mut fields = vec![(key, serde_json::json!("long"))];
+    for ((_, schema), field) in streams.iter().zip(&names) {
+        fields.push((field.as_str(), serde_json::json!(["null", schema])));
+    }
+    Ok(record_schema(namespace, name, fields))
+}
+
+/// An event which did not receive records from all the streams in time.
+#[derive(Debug)]
+pub struct IncompleteEvent {
+    pub key: i64,
+    pub missing: Vec<String>,
+    /// Merged record with null for the missing streams
+    pub record: Decoded,
+}
+
+struct PendingEvent {
+    records: HashMap<String, Decoded>,
+    first_seen: Instant,
+}
+
+/// Matches records of several streams on a key field (e.g. "pulse_id").
+///
+/// A merged record is emitted as soon as all the streams have contributed a
+/// record with the same key. Events which are not complete within the window
+/// after their first record are dropped and reported by `expire`.
+pub struct EventBuilder {
+    key: String,
+    // stream names and their field names in schema order
+    streams: Vec<(String, Vec<String>)>,
+    window: Duration,
+    pending: BTreeMap<i64, PendingEvent>,
+    complete: u64,
+    incomplete: u64,
+}
+
+impl EventBuilder {
+    /// Streams are given as (stream name, Json schema) pairs.
+    pub fn new(key: &str, streams: &[(String, serde_json::Value)], window: Duration) -> FcResult<Self> {
+        stream_fields(key, streams)?;
+        let streams = streams.iter().map(|(stream, schema)| {
+            let fields = schema["fields"].as_array()
+                .expect("Schema of a stream must be a record")
+                .iter().map(|f| f["name"].as_str().unwrap().to_owned()).collect();
+            (stream.clone(), fields)
+        }).collect();
+
+        Ok(EventBuilder {
+            key: key.to_owned(),
+            streams,
+            window,
+            pending: BTreeMap::new(),
+            complete: 0,
+            incomplete: 0,
+        })
+    }
+
+    /// Number of complete and incomplete events so far.
+    pub fn counts(&self) -> (u64, u64) {
+        (self.complete, self.incomplete)
+    }
+
+    /// Number of events waiting for records.
+    pub fn num_pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn merge(&self, key: i64, mut records: HashMap<String, Decoded>) -> Decoded {
+        let mut merged = Decoded::from([(self.key.clone(), Value::Long(key))]);
+        for (stream, fields) in &self.streams {
+            let value = match records.remove(stream) {
+                Some(mut record) => Value::Union(1, Box::new(Value::Record(
+                    fields.iter().filter_map(|f| record.remove(f).map(|v| (f.clone(), v))).collect()))),
+                None => Value::Union(0, Box::new(Value::Null)),
+            };
+            merged.insert(stream_field(stream), value);
+        }
+        merged
+    }
+
+    /// Add a record of a stream and return the merged record if the event is complete.
+    pub fn push(&mut self, stream: &str, record: Decoded, now: Instant) -> FcResult<Option<Decoded>> {
+        if !self.streams.iter().any(|(s, _)| s == stream) {
+            return Err(FcError::SchemaError(format!("Unknown stream for event building: {}", stream)));
+        }
+        let key = match field_value(&record, std::slice::from_ref(&self.key)) {
+            Some(Value::Long(x)) => *x,
+            Some(Value::Int(x)) => *x as i64,
+            other => return Err(FcError::SchemaError(format!(
+                "Invalid key field '{}' in stream {}: {:?}", self.key, stream, other))),
+        };
+
+        let event = self.pending.entry(key).or_insert_with(
+            || PendingEvent { records: HashMap::new(), first_seen: now });
+        event.records.insert(stream.to_owned(), record);
+        if event.records.len() < self.streams.len() {
+            return Ok(None);
+        }
+
+        let event = self.pending.remove(&key).unwrap();
+        self.complete += 1;
+        Ok(Some(self.merge(key, event.records)))
+    }
+
+    /// Drop the events which are older than the window and return them.
+    pub fn expire(&mut self, now: Instant) -> Vec<IncompleteEvent> {
+        let expired: Vec<i64> = self.pending.iter()
+            .filter(|(_, e)| now.duration_since(e.first_seen) > self.window)
+            .map(|(k, _)| *k)
+            .collect();
+
+        let mut ret = Vec::with_capacity(expired.len());
+        for key in expired {
+            let event = self.pending.remove(&key).unwrap();
+            self.incomplete += 1;
+            let missing = self.streams.iter()
+                .filter(|(s, _)| !event.records.contains_key(s))
+                .map(|(s, _)| s.clone())
+                .collect();
+            ret.push(IncompleteEvent { key, missing, record: self.merge(key, event.records) });
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use apache_avro::types::Value;
+
+    use crate::event_builder::{EventBuilder, combined_schema};
+    use crate::schema::{Decoded, NDArray, AvroType, json_to_avro_schema, record_schema};
+
+    fn streams() -> Vec<(String, serde_json::Value)> {
+        vec![
+            ("det1:raw".to_string(), record_schema("det1", "raw", vec![
+                ("pulse_id", serde_json::json!("long")),
+                ("image", NDArray::avro_type()),
+            ])),
+            ("det2:raw".to_string(), record_schema("det2", "raw", vec![
+                ("pulse_id", serde_json::json!("long")),
+                ("energy", serde_json::json!("double")),
+            ])),
+        ]
+    }
+
+    fn record(pulse_id: i64) -> Decoded {
+        Decoded::from([
+            ("pulse_id".to_string(), Value::Long(pulse_id)),
+            ("energy".to_string(), Value::Double(1.5)),
+        ])
+    }
+
+    #[test]
+    fn test_combined_schema() {
+        let schema = combined_schema("events", "raw", "pulse_id", &streams()).unwrap();
+        json_to_avro_schema(&schema);
+        assert_eq!(schema["fields"][1]["name"], "det1_raw");
+        assert_eq!(schema["fields"][2]["type"][1]["name"], "raw");
+    }
+
+    #[test]
+    fn test_stream_field_collisions() {
+        let mut streams = streams();
+        streams[0].0 = "ns:det-1".to_string();
+        streams[1].0 = "ns_det:1".to_string();
+        let window = Duration::from_millis(100);
+        assert!(combined_schema("events", "raw", "pulse_id", &streams).is_err());
+        assert!(EventBuilder::new("pulse_id", &streams, window).is_err());
+
+        streams[1].0 = "pulse:id".to_string();
+        assert!(combined_schema("events", "raw", "pulse_id", &streams).is_err());
+        assert!(EventBuilder::new("pulse_id", &streams, window).is_err());
+    }
+
+    #[test]
+    fn test_event_builder() {
+        let streams = streams();
+        let window = Duration::from_millis(100);
+        let mut builder = EventBuilder::new("pulse_id", &streams[1..], window).unwrap();
+        let t0 = Instant::now();
+
+        // a single stream completes each event
+        let merged = builder.push("det2:raw", record(1), t0).unwrap().unwrap();
+        assert_eq!(merged["pulse_id"], Value::Long(1));
+
+        let mut builder = EventBuilder::new("pulse_id", &streams, window).unwrap();
+        let image = Decoded::from([("pulse_id".to_string(), Value::Long(1))]);
+        assert!(builder.push("det1:raw", image, t0).unwrap().is_none());
+        assert!(builder.push("det2:raw", record(2), t0).unwrap().is_none());
+        let merged = builder.push("det2:raw", record(1), t0).unwrap().unwrap();
+        assert_eq!(merged["det2_raw"], Value::Union(1, Box::new(Value::Record(vec![
+            ("pulse_id".to_string(), Value::Long(1)),
+            ("energy".to_string(), Value::Double(1.5)),
+        ]))));
+        assert!(builder.push("det3:raw", record(1), t0).is_err());
+
+        assert!(builder.expire(t0 + window / 2).is_empty());
+        let incomplete = builder.expire(t0 + window * 2);
+        assert_eq!(incomplete.len(), 1);
+        assert_eq!(incomplete[0].key, 2);
+        assert_eq!(incomplete[0].missing, vec!["det1:raw"]);
+        assert_eq!(incomplete[0].record["det1_raw"], Value::Union(0, Box::new(Value::Null)));
+        assert_eq!(builder.counts(), (1, 1));
+        assert_eq!(builder.num_pending(), 0);
+    }
+}
@@ -0,0 +1,361 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::Path;
+use std::sync::Arc;
+
+use apache_avro::Schema;
+use apache_avro::types::Value;
+use arrow::array::{ArrayRef, BinaryArray};
+use arrow::datatypes::{DataType, Field, Schema as ArrowSchema};
+use arrow::record_batch::RecordBatch;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+
+use crate::columnar::{DTYPE_METADATA, build_column, ndarray_parts};
+use crate::json::{Names, is_ndarray};
+use crate::schema::{Decoded, field_value};
+use crate::error::{FcError, FcResult};
+
+/// Field metadata which keeps the shape (a Json array) of an ndarray column.
+pub const SHAPE_METADATA: &str = "foamcore.shape";
+
+enum Leaf {
+    Value(Schema),
+    NDArray,
+}
+
+/// A column of the flattened record, e.g. "parent.child".
+struct FlatColumn {
+    path: Vec<String>,
+    leaf: Leaf,
+}
+
+fn flatten_schema(prefix: &[String], schema: &Schema, names: &Names, columns: &mut Vec<FlatColumn>)
+        -> FcResult<()> {
+    let rs = match names.resolve(schema)? {
+        Schema::Record(rs) => rs,
+        _ => return Err(FcError::SchemaError("Export requires a record schema".to_string())),
+    };
+    for field in &rs.fields {
+        let mut path = prefix.to_vec();
+        path.push(field.name.clone());
+
+        // records which are nullable are flattened as well
+        let mut inner = names.resolve(&field.schema)?;
+        if let Schema::Union(us) = inner {
+            if let [s] = us.variants().iter().filter(|s| **s != Schema::Null).collect::<Vec<_>>()[..] {
+                if let Schema::Record(_) = names.resolve(s)? {
+                    inner = names.resolve(s)?;
+                }
+            }
+        }
+
+        match inner {
+            Schema::Record(rs) if is_ndarray(rs) => columns.push(FlatColumn { path, leaf: Leaf::NDArray }),
+            Schema::Record(_) => flatten_schema(&path, inner, names, columns)?,
+            _ => columns.push(FlatColumn { path, leaf: Leaf::Value(field.schema.clone()) }),
+        }
+    }
+    Ok(())
+}
+
+/// Layout of a flattened column, collected over all the records of an export.
+struct ColumnLayout {
+    has_null: bool,
+    // shape and dtype of the first ndarray
+    ndarray: Option<(Vec<i32>, String)>,
+    // whether all the ndarrays have the same shape and dtype
+    uniform: bool,
+}
+
+/// Build the data column of an ndarray field.
+///
+/// Shape and dtype are kept in the field metadata if they are the same for
+/// all the records. Otherwise, they are written to the "<name>.shape" and
+/// "<name>.dtype" columns.
+fn build_ndarray(name: &str, values: &[Option<&Value>], layout: &ColumnLayout)
+        -> FcResult<Vec<(Field, ArrayRef)>> {
+    let mut parts = Vec::with_capacity(values.len());
+    for value in values {
+        parts.push(match value {
+            Some(v) => Some(ndarray_parts(v).ok_or_else(
+                || FcError::CodecError(format!("Invalid ndarray: {}", name)))?),
+            None => None,
+        });
+    }
+
+    let data = BinaryArray::from(parts.iter().map(|p| p.as_ref().map(|(_, _, d)| *d)).collect::<Vec<_>>());
+    let nullable = layout.has_null;
+
+    if layout.uniform {
+        let mut field = Field::new(name, DataType::Binary, nullable);
+        if let Some((shape, dtype)) = &layout.ndarray {
+            // the records may have changed since the layout was collected
+            if parts.iter().flatten().any(|(s, d, _)| s != shape || d != dtype) {
+                return Err(FcError::CodecError(format!("Layout of ndarray '{}' changed during the export", name)));
+            }
+            field = field.with_metadata(HashMap::from([
+                (SHAPE_METADATA.to_string(), serde_json::to_string(shape)?),
+                (DTYPE_METADATA.to_string(), dtype.to_string()),
+            ]));
+        }
+        return Ok(vec![(field, Arc::new(data))]);
+    }
+
+    let names = Names::new(&Schema::Null);
+    let shapes: Vec<Option<Value>> = parts.iter().map(|p| p.as_ref().map(
+        |(s, _, _)| Value::Array(s.iter().map(|x| Value::Int(*x)).collect()))).collect();
+    let dtypes: Vec<Option<Value>> = parts.iter().map(
+        |p| p.as_ref().map(|(_, d, _)| Value::String(d.to_string()))).collect();
+    let nullable_schema = |s: Schema| if nullable {
+        Schema::Union(apache_avro::schema::UnionSchema::new(vec![Schema::Null, s]).unwrap())
+    } else {
+        s
+    };
+
+    Ok(vec![
+        (Field::new(name, DataType::Binary, nullable), Arc::new(data) as ArrayRef),
+        build_column(&format!("{}.shape", name), &shapes.iter().map(Option::as_ref).collect::<Vec<_>>(),
+                     &nullable_schema(Schema::Array(Box::new(Schema::Int))), &names)?,
+        build_column(&format!("{}.dtype", name), &dtypes.iter().map(Option::as_ref).collect::<Vec<_>>(),
+                     &nullable_schema(Schema::String), &names)?,
+    ])
+}
+
+/// Flattened columns of a record schema and their layout.
+///
+/// The layout is collected from all the records before any of them is
+/// flattened, so that the records can be flattened in batches which share
+/// the same Arrow schema.
+pub struct ExportLayout {
+    names: Names,
+    columns: Vec<FlatColumn>,
+    layouts: Vec<ColumnLayout>,
+}
+
+impl ExportLayout {
+    pub fn new(schema: &Schema) -> FcResult<Self> {
+        let names = Names::new(schema);
+        let mut columns = Vec::new();
+        flatten_schema(&[], schema, &names, &mut columns)?;
+        let layouts = columns.iter().map(|_| ColumnLayout { has_null: false, ndarray: None, uniform: true }).collect();
+
+        Ok(ExportLayout { names, columns, layouts })
+    }
+
+    /// Collect the layout of the columns from a batch of records.
+    pub fn update(&mut self, records: &[Decoded]) -> FcResult<()> {
+        for (column, layout) in self.columns.iter().zip(self.layouts.iter_mut()) {
+            for record in records {
+                let value = match field_value(record, &column.path) {
+                    Some(v) => v,
+                    None => {
+                        layout.has_null = true;
+                        continue;
+                    },
+                };
+                if let Leaf::NDArray = column.leaf {
+                    let (shape, dtype, _) = ndarray_parts(value).ok_or_else(
+                        || FcError::CodecError(format!("Invalid ndarray: {}", column.path.join("."))))?;
+                    match &layout.ndarray {
+                        None => layout.ndarray = Some((shape, dtype.to_string())),
+                        Some((s, d)) => layout.uniform &= *s == shape && d == dtype,
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Convert records into a flat record batch.
+    pub fn flatten(&self, records: &[Decoded]) -> FcResult<RecordBatch> {
+        let mut fields = Vec::with_capacity(self.columns.len());
+        let mut arrays = Vec::with_capacity(self.columns.len());
+        for (column, layout) in self.columns.iter().zip(&self.layouts) {
+            let name = column.path.join(".");
+            let values: Vec<Option<&Value>> = records.iter()
+                .map(|r| field_value(r, &column.path)).collect();
+            let built = match &column.leaf {
+                Leaf::Value(s) => vec![build_column(&name, &values, s, &self.names)?],
+                Leaf::NDArray => build_ndarray(&name, &values, layout)?,
+            };
+            for (field, array) in built {
+                // a column is null whenever one of its parent records is null
+                let nullable = field.is_nullable() || layout.has_null;
+                fields.push(field.with_nullable(nullable));
+                arrays.push(array);
+            }
+        }
+
+        Ok(RecordBatch::try_new(Arc::new(ArrowSchema::new(fields)), arrays)?)
+    }
+}
+
+/// Convert records into a flat record batch.
+///
+/// Nested records are flattened with dotted names and ndarray fields are
+/// stored as binary columns.
+pub fn flatten_records(records: &[Decoded], schema: &Schema) -> FcResult<RecordBatch> {
+    let mut layout = ExportLayout::new(schema)?;
+    layout.update(records)?;
+    layout.flatten(records)
+}
+
+/// Writes batches of records into a Parquet file.
+///
+/// Rows are buffered until a row group is complete, so the memory does not
+/// grow with the number of records.
+pub struct ParquetExporter {
+    layout: ExportLayout,
+    writer: ArrowWriter<File>,
+    rows: usize,
+}
+
+impl ParquetExporter {
+    /// The layout must have been collected from all the records to write.
+    pub fn new(layout: ExportLayout, path: &Path, row_group_size: usize) -> FcResult<Self> {
+        let props = WriterProperties::builder()
+            .set_compression(Compression::SNAPPY)
+            .set_max_row_group_size(row_group_size)
+            .build();
+        let schema = layout.flatten(&[])?.schema();
+        let writer = ArrowWriter::try_new(File::create(path)?, schema, Some(props))?;
+
+        Ok(ParquetExporter { layout, writer, rows: 0 })
+    }
+
+    pub fn write(&mut self, records: &[Decoded]) -> FcResult<()> {
+        self.writer.write(&self.layout.flatten(records)?)?;
+        self.rows += records.len();
+        Ok(())
+    }
+
+    /// Finish the file and return the number of rows.
+    pub fn close(self) -> FcResult<usize> {
+        self.writer.close()?;
+        Ok(self.rows)
+    }
+}
+
+/// Write records into a Parquet file and return the number of rows.
+pub fn write_parquet(records: &[Decoded], schema: &Schema, path: &Path, row_group_size: usize)
+        -> FcResult<usize> {
+    let mut layout = ExportLayout::new(schema)?;
+    layout.update(records)?;
+    let mut exporter = ParquetExporter::new(layout, path, row_group_size)?;
+    exporter.write(records)?;
+    exporter.close()
+}
+
+#[cfg(test)]
+mod tests {
+    use apache_avro::types::Value;
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    use crate::export::{ExportLayout, ParquetExporter, SHAPE_METADATA, flatten_records, write_parquet};
+    use crate::schema::{Decoded, json_to_avro_schema};
+
+    fn schema() -> apache_avro::Schema {
+        json_to_avro_schema(&serde_json::json!({
+            "type": "record",
+            "name": "export_test",
+            "fields": [
+                {"name": "index", "type": "long"},
+                {"name": "motor", "type": {
+                    "type": "record",
+                    "name": "Motor",
+                    "fields": [{"name": "position", "type": "double"}]
+                }},
+                {"name": "image", "type": {
+                    "type": "record",
+                    "name": "NDArray",
+                    "fields": [
+                        {"name": "shape", "type": {"type": "array", "items": "int"}},
+                        {"name": "dtype", "type": "string"},
+                        {"name": "data", "type": "bytes"}
+                    ]
+                }}
+            ]
+        }))
+    }
+
+    fn record(index: i64, shape: Vec<i32>) -> Decoded {
+        let size: i32 = shape.iter().product();
+        Decoded::from([
+            ("index".to_string(), Value::Long(index)),
+            ("motor".to_string(), Value::Record(vec![
+                ("position".to_string(), Value::Double(index as f64 * 0.5)),
+            ])),
+            ("image".to_string(), Value::Record(vec![
+                ("shape".to_string(), Value::Array(shape.into_iter().map(Value::Int).collect())),
+                ("dtype".to_string(), Value::String("|u1".to_string())),
+                ("data".to_string(), Value::Bytes(vec![index as u8; size as usize])),
+            ])),
+        ])
+    }
+
+    #[test]
+    fn test_flatten_records() {
+        let schema = schema();
+
+        let batch = flatten_records(&[record(0, vec![2]), record(1, vec![2])], &schema).unwrap();
+        let names: Vec<_> = batch.schema().fields().iter().map(|f| f.name().clone()).collect();
+        assert_eq!(names, vec!["index", "motor.position", "image"]);
+        assert_eq!(batch.schema().field(2).metadata()[SHAPE_METADATA], "[2]");
+
+        let batch = flatten_records(&[record(0, vec![2]), record(1, vec![1, 3])], &schema).unwrap();
+        let names: Vec<_> = batch.schema().fields().iter().map(|f| f.name().clone()).collect();
+        assert_eq!(names, vec!["index", "motor.position", "image", "image.shape", "image.dtype"]);
+    }
+
+    #[test]
+    fn test_write_parquet() {
+        let schema = schema();
+        let path = std::env::temp_dir().join(format!("export_test_{}.parquet", std::process::id()));
+
+        let records: Vec<_> = (0..10).map(|i| record(i, vec![2, 2])).collect();
+        assert_eq!(write_parquet(&records, &schema, &path, 4).unwrap(), 10);
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(builder.metadata().num_row_groups(), 3);
+        let field = builder.schema().field_with_name("image").unwrap().clone();
+        assert_eq!(field.metadata()[SHAPE_METADATA], "[2,2]");
+        let rows: usize = builder.build().unwrap().map(|b| b.unwrap().num_rows()).sum();
+        assert_eq!(rows, 10);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parquet_exporter() {
+        let schema = schema();
+        let path = std::env::temp_dir().join(format!("export_test_pages_{}.parquet", std::process::id()));
+
+        // the shape of the ndarrays differs between the pages
+        let pages = vec![vec![record(0, vec![2]), record(1, vec![2])], vec![record(2, vec![1, 3])]];
+        let mut layout = ExportLayout::new(&schema).unwrap();
+        for page in &pages {
+            layout.update(page).unwrap();
+        }
+        let mut exporter = ParquetExporter::new(layout, &path, 4).unwrap();
+        for page in &pages {
+            exporter.write(page).unwrap();
+        }
+        assert_eq!(exporter.close().unwrap(), 3);
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert!(builder.schema().field_with_name("image.shape").is_ok());
+        let rows: usize = builder.build().unwrap().map(|b| b.unwrap().num_rows()).sum();
+        assert_eq!(rows, 3);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
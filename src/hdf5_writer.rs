@@ -0,0 +1,449 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::path::{Path, PathBuf};
+
+use apache_avro::Schema;
+use apache_avro::types::Value;
+use hdf5::{Dataset, Extent, File, Group, H5Type, Hyperslab, Selection, SliceOrIndex};
+use hdf5::types::{VarLenArray, VarLenUnicode};
+use ndarray::{ArrayView, IxDyn};
+
+use crate::dtype::{DType, TypeDescr};
+use crate::json::{Names, is_ndarray};
+use crate::schema::{Decoded, field_value, json_to_avro_schema, stream_name};
+use crate::error::{FcError, FcResult};
+
+/// Target size of a chunk of the datasets in bytes.
+const CHUNK_BYTES: usize = 1 << 20;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Kind {
+    Bool,
+    Int,
+    Long,
+    Float,
+    Double,
+    String,
+    Bytes,
+    NDArray,
+}
+
+/// A schema field written into a dataset of the "entry/data" group.
+///
+/// Nested record fields are flattened as "parent.child".
+struct Column {
+    path: Vec<String>,
+    kind: Kind,
+    dataset: Option<Dataset>,
+    // shape and dtype of a single ndarray
+    frame: Vec<usize>,
+    dtype: String,
+}
+
+impl Column {
+    fn name(&self) -> String {
+        self.path.join(".")
+    }
+}
+
+fn collect_columns(prefix: &[String], schema: &Schema, names: &Names, columns: &mut Vec<Column>)
+        -> FcResult<()> {
+    let rs = match names.resolve(schema)? {
+        Schema::Record(rs) => rs,
+        _ => return Ok(()),
+    };
+    for field in &rs.fields {
+        let mut path = prefix.to_vec();
+        path.push(field.name.clone());
+
+        let mut schema = names.resolve(&field.schema)?;
+        if let Schema::Union(us) = schema {
+            let variants: Vec<_> = us.variants().iter().filter(|s| **s != Schema::Null).collect();
+            match variants[..] {
+                [inner] => schema = names.resolve(inner)?,
+                _ => continue,
+            }
+        }
+
+        let kind = match schema {
+            Schema::Boolean => Kind::Bool,
+            Schema::Int => Kind::Int,
+            Schema::Long => Kind::Long,
+            Schema::Float => Kind::Float,
+            Schema::Double => Kind::Double,
+            Schema::String => Kind::String,
+            Schema::Bytes => Kind::Bytes,
+            Schema::Record(rs) if is_ndarray(rs) => Kind::NDArray,
+            Schema::Record(_) => {
+                collect_columns(&path, schema, names, columns)?;
+                continue;
+            },
+            // other types are not written
+            _ => continue,
+        };
+        columns.push(Column { path, kind, dataset: None, frame: Vec::new(), dtype: String::new() });
+    }
+    Ok(())
+}
+
+fn value_error(column: &Column, value: &Value) -> FcError {
+    FcError::CodecError(format!("Unexpected value for dataset '{}': {:?}", column.name(), value))
+}
+
+fn set_nx_class(group: &Group, nx_class: &str) -> FcResult<()> {
+    let value: VarLenUnicode = nx_class.parse().unwrap();
+    group.new_attr::<VarLenUnicode>().create("NX_class")?.write_scalar(&value)?;
+    Ok(())
+}
+
+/// Create a dataset which grows along the first axis by one frame per record.
+fn create_dataset<T: H5Type>(group: &Group, name: &str, frame: &[usize], len: usize)
+        -> FcResult<Dataset> {
+    let nbytes = frame.iter().product::<usize>() * std::mem::size_of::<T>();
+    let mut chunk = vec![(CHUNK_BYTES / nbytes.max(1)).max(1)];
+    chunk.extend(frame);
+    let mut extents = vec![Extent::resizable(len)];
+    extents.extend(frame.iter().map(|&n| Extent::fixed(n)));
+
+    Ok(group.new_dataset::<T>().chunk(chunk).shape(extents).create(name)?)
+}
+
+/// Select the frame at the given index.
+fn frame_selection(index: usize, ndim: usize) -> Selection {
+    let mut slices = vec![SliceOrIndex::from(index..index + 1)];
+    slices.extend((0..ndim).map(|_| SliceOrIndex::from(..)));
+    Selection::from(Hyperslab::from(slices))
+}
+
+fn write_frame<T: H5Type>(dataset: &Dataset, index: usize, frame: &[usize], values: &[T])
+        -> FcResult<()> {
+    let mut shape = vec![1];
+    shape.extend(frame);
+    let view = ArrayView::from_shape(IxDyn(&shape), values).map_err(
+        |e| FcError::CodecError(format!("Invalid ndarray shape: {}", e)))?;
+    dataset.write_slice(view, frame_selection(index, frame.len()))?;
+    Ok(())
+}
+
+macro_rules! write_items {
+    ($t:ty, $column:expr, $group:expr, $index:expr, $data:expr, $big_endian:expr) => {{
+        let values: Vec<$t> = $data.chunks_exact(std::mem::size_of::<$t>()).map(|c| {
+            let bytes = c.try_into().unwrap();
+            if $big_endian { <$t>::from_be_bytes(bytes) } else { <$t>::from_le_bytes(bytes) }
+        }).collect();
+        let dataset = column_dataset::<$t>($column, $group, $index)?;
+        write_frame(&dataset, $index, &$column.frame, &values)?;
+    }};
+}
+
+/// Return the dataset of a column, creating it on the first value, and
+/// extend it to hold the frame at the given index.
+fn column_dataset<T: H5Type>(column: &mut Column, group: &Group, index: usize) -> FcResult<Dataset> {
+    if column.dataset.is_none() {
+        let dataset = create_dataset::<T>(group, &column.name(), &column.frame, index)?;
+        if column.kind == Kind::NDArray {
+            let value: VarLenUnicode = column.dtype.parse().unwrap();
+            dataset.new_attr::<VarLenUnicode>().create("dtype")?.write_scalar(&value)?;
+        }
+        column.dataset = Some(dataset);
+    }
+    let dataset = column.dataset.clone().unwrap();
+    let mut shape = vec![index + 1];
+    shape.extend(&column.frame);
+    dataset.resize(shape)?;
+    Ok(dataset)
+}
+
+fn write_value(column: &mut Column, group: &Group, index: usize, value: &Value) -> FcResult<()> {
+    match (column.kind, value) {
+        (Kind::Bool, Value::Boolean(x)) =>
+            write_frame(&column_dataset::<bool>(column, group, index)?, index, &[], &[*x]),
+        (Kind::Int, Value::Int(x)) =>
+            write_frame(&column_dataset::<i32>(column, group, index)?, index, &[], &[*x]),
+        (Kind::Long, Value::Long(x)) =>
+            write_frame(&column_dataset::<i64>(column, group, index)?, index, &[], &[*x]),
+        (Kind::Float, Value::Float(x)) =>
+            write_frame(&column_dataset::<f32>(column, group, index)?, index, &[], &[*x]),
+        (Kind::Double, Value::Double(x)) =>
+            write_frame(&column_dataset::<f64>(column, group, index)?, index, &[], &[*x]),
+        (Kind::String, Value::String(s)) => {
+            let s: VarLenUnicode = s.parse().map_err(
+                |e| FcError::CodecError(format!("Invalid string for HDF5: {:?}", e)))?;
+            write_frame(&column_dataset::<VarLenUnicode>(column, group, index)?, index, &[], &[s])
+        },
+        (Kind::Bytes, Value::Bytes(b)) => {
+            let b = VarLenArray::from_slice(b);
+            write_frame(&column_dataset::<VarLenArray<u8>>(column, group, index)?, index, &[], &[b])
+        },
+        (Kind::NDArray, Value::Record(fields)) => write_ndarray(column, group, index, fields),
+        _ => Err(value_error(column, value)),
+    }
+}
+
+fn write_ndarray(column: &mut Column, group: &Group, index: usize, fields: &[(String, Value)])
+        -> FcResult<()> {
+    let get = |name: &str| fields.iter().find(|(k, _)| k == name).map(|(_, v)| v);
+    let (shape, dtype, data) = match (get("shape"), get("dtype"), get("data")) {
+        (Some(Value::Array(shape)), Some(Value::String(dtype)), Some(Value::Bytes(data))) =>
+            (shape, dtype, data),
+        _ => return Err(FcError::CodecError(format!("Invalid ndarray: {}", column.name()))),
+    };
+    let frame = shape.iter().map(|x| match x {
+        Value::Int(n) if *n >= 0 => Ok(*n as usize),
+        _ => Err(FcError::CodecError(format!("Invalid ndarray shape: {:?}", shape))),
+    }).collect::<FcResult<Vec<_>>>()?;
+
+    if column.dataset.is_none() {
+        column.frame = frame;
+        column.dtype = dtype.clone();
+    } else if column.frame != frame || &column.dtype != dtype {
+        return Err(FcError::CodecError(format!(
+            "ndarray '{}' changed from {:?} ({}) to {:?} ({})",
+            column.name(), column.frame, column.dtype, frame, dtype)));
+    }
+
+    let descr = TypeDescr::parse(dtype)?;
+    let nbytes = column.frame.iter().try_fold(descr.itemsize(), |acc, &n| acc.checked_mul(n));
+    if nbytes != Some(data.len()) {
+        return Err(FcError::CodecError(format!(
+            "Data size of ndarray '{}' does not match its shape", column.name())));
+    }
+
+    let be = descr.big_endian;
+    match descr.dtype {
+        DType::Bool => {
+            let values: Vec<bool> = data.iter().map(|&x| x != 0).collect();
+            let dataset = column_dataset::<bool>(column, group, index)?;
+            write_frame(&dataset, index, &column.frame, &values)?;
+        },
+        DType::Int8 => write_items!(i8, column, group, index, data, be),
+        DType::UInt8 => write_items!(u8, column, group, index, data, be),
+        DType::Int16 => write_items!(i16, column, group, index, data, be),
+        DType::UInt16 => write_items!(u16, column, group, index, data, be),
+        DType::Int32 => write_items!(i32, column, group, index, data, be),
+        DType::UInt32 => write_items!(u32, column, group, index, data, be),
+        DType::Int64 => write_items!(i64, column, group, index, data, be),
+        DType::UInt64 => write_items!(u64, column, group, index, data, be),
+        DType::Float32 => write_items!(f32, column, group, index, data, be),
+        DType::Float64 => write_items!(f64, column, group, index, data, be),
+    }
+    Ok(())
+}
+
+/// Writes the records of a stream into NeXus-style HDF5 files.
+///
+/// Each schema field is written to a dataset in the "entry/data" group:
+/// scalars are appended to 1-D datasets and ndarrays to chunked N-D datasets.
+/// Null values are left as the fill value. A new file is started whenever
+/// the run number changes.
+pub struct Hdf5Writer {
+    directory: PathBuf,
+    prefix: String,
+    columns: Vec<Column>,
+    run_field: Option<Vec<String>>,
+    run: Option<i64>,
+    file: Option<(File, Group, PathBuf)>,
+    len: usize,
+}
+
+impl Hdf5Writer {
+    pub fn new(directory: &str, schema: &serde_json::Value) -> Self {
+        let avro_schema = json_to_avro_schema(schema);
+        let mut columns = Vec::new();
+        collect_columns(&[], &avro_schema, &Names::new(&avro_schema), &mut columns)
+            .expect("Failed to map the schema to HDF5 datasets");
+
+        Hdf5Writer {
+            directory: PathBuf::from(directory),
+            prefix: stream_name(schema).replace(':', "_"),
+            columns,
+            run_field: None,
+            run: None,
+            file: None,
+            len: 0,
+        }
+    }
+
+    /// Sets the (nested) field holding the run number, e.g. "run" or "meta.run".
+    pub fn set_run_field(&mut self, name: &str) {
+        self.run_field = Some(name.split('.').map(|s| s.to_owned()).collect());
+    }
+
+    /// Path of the file being written.
+    pub fn path(&self) -> Option<&Path> {
+        self.file.as_ref().map(|(_, _, path)| path.as_path())
+    }
+
+    /// Close the current file and start the file of a new run.
+    ///
+    /// Existing files are never overwritten.
+    pub fn start_run(&mut self, run: i64) -> FcResult<()> {
+        self.close()?;
+
+        let mut path = self.directory.join(format!("{}_run{:04}.h5", self.prefix, run));
+        let mut n = 0;
+        while path.exists() {
+            n += 1;
+            path = self.directory.join(format!("{}_run{:04}_{}.h5", self.prefix, run, n));
+        }
+
+        let file = File::create_excl(&path)?;
+        let entry = file.create_group("entry")?;
+        set_nx_class(&entry, "NXentry")?;
+        entry.new_attr::<i64>().create("run")?.write_scalar(&run)?;
+        let data = entry.create_group("data")?;
+        set_nx_class(&data, "NXdata")?;
+
+        self.file = Some((file, data, path));
+        self.run = Some(run);
+        self.len = 0;
+        Ok(())
+    }
+
+    /// Flush and close the current file.
+    pub fn close(&mut self) -> FcResult<()> {
+        for column in self.columns.iter_mut() {
+            column.dataset = None;
+        }
+        if let Some((file, _, _)) = self.file.take() {
+            file.flush()?;
+        }
+        Ok(())
+    }
+
+    fn current_run(&self, datum: &Decoded) -> FcResult<Option<i64>> {
+        let path = match &self.run_field {
+            Some(path) => path,
+            None => return Ok(None),
+        };
+        match field_value(datum, path) {
+            Some(Value::Int(x)) => Ok(Some(*x as i64)),
+            Some(Value::Long(x)) => Ok(Some(*x)),
+            None => Ok(None),
+            Some(other) => Err(FcError::CodecError(format!("Invalid run number: {:?}", other))),
+        }
+    }
+
+    /// Append a record and return its index in the current file.
+    pub fn write(&mut self, datum: &Decoded) -> FcResult<usize> {
+        match self.current_run(datum)? {
+            Some(run) if self.run != Some(run) => self.start_run(run)?,
+            _ if self.file.is_none() => self.start_run(self.run.unwrap_or(0))?,
+            _ => (),
+        }
+
+        let index = self.len;
+        let group = self.file.as_ref().unwrap().1.clone();
+        for column in self.columns.iter_mut() {
+            if let Some(value) = field_value(datum, &column.path) {
+                write_value(column, &group, index, value)?;
+            } else if let Some(dataset) = &column.dataset {
+                let mut shape = vec![index + 1];
+                shape.extend(&column.frame);
+                dataset.resize(shape)?;
+            }
+        }
+        self.len += 1;
+        Ok(index)
+    }
+
+    /// Write records and return "<file>:<index>" of each record.
+    ///
+    /// An error of flushing the file is appended to the results.
+    pub fn produce(&mut self, records: &[Decoded], _stream: &str) -> Vec<FcResult<String>> {
+        let mut ret: Vec<FcResult<String>> = records.iter().map(|x| {
+            let index = self.write(x)?;
+            Ok(format!("{}:{}", self.path().unwrap().display(), index))
+        }).collect();
+
+        if let Some((file, _, _)) = &self.file {
+            if let Err(e) = file.flush() {
+                ret.push(Err(e.into()));
+            }
+        }
+        ret
+    }
+}
+
+impl Drop for Hdf5Writer {
+    fn drop(&mut self) {
+        let _ = self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apache_avro::types::Value;
+
+    use crate::hdf5_writer::Hdf5Writer;
+    use crate::schema::Decoded;
+
+    #[test]
+    fn test_hdf5_writer() {
+        let raw_schema = r#"
+            {
+                "namespace": "hdf5_writer_test",
+                "type": "record",
+                "name": "raw",
+                "fields": [
+                    {"name": "run", "type": "long"},
+                    {"name": "energy", "type": ["null", "double"]},
+                    {"name": "image", "type": {
+                        "type": "record",
+                        "name": "NDArray",
+                        "fields": [
+                            {"name": "shape", "type": {"type": "array", "items": "int"}},
+                            {"name": "dtype", "type": "string"},
+                            {"name": "data", "type": "bytes"}
+                        ]
+                    }}
+                ]
+            }"#;
+        let json_schema: serde_json::Value = serde_json::from_str(raw_schema).unwrap();
+        let directory = std::env::temp_dir().join(format!("hdf5_writer_test_{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+
+        let mut writer = Hdf5Writer::new(directory.to_str().unwrap(), &json_schema);
+        writer.set_run_field("run");
+
+        let record = |run: i64, energy: Option<f64>, pixel: u16| Decoded::from([
+            ("run".to_string(), Value::Long(run)),
+            ("energy".to_string(), match energy {
+                Some(x) => Value::Union(1, Box::new(Value::Double(x))),
+                None => Value::Union(0, Box::new(Value::Null)),
+            }),
+            ("image".to_string(), Value::Record(vec![
+                ("shape".to_string(), Value::Array(vec![Value::Int(2), Value::Int(1)])),
+                ("dtype".to_string(), Value::String(">u2".to_string())),
+                ("data".to_string(), Value::Bytes([pixel.to_be_bytes(), [0, 1]].concat())),
+            ])),
+        ]);
+
+        assert_eq!(writer.write(&record(1, Some(1.5), 10)).unwrap(), 0);
+        assert_eq!(writer.write(&record(1, None, 20)).unwrap(), 1);
+        let first = writer.path().unwrap().to_owned();
+        assert_eq!(writer.write(&record(2, Some(3.0), 30)).unwrap(), 0);
+        writer.close().unwrap();
+
+        let file = hdf5::File::open(&first).unwrap();
+        let energy = file.dataset("entry/data/energy").unwrap().read_1d::<f64>().unwrap();
+        assert_eq!(energy.to_vec(), vec![1.5, 0.]);
+        let image = file.dataset("entry/data/image").unwrap().read_dyn::<u16>().unwrap();
+        assert_eq!(image.shape(), &[2, 2, 1]);
+        assert_eq!(image.iter().cloned().collect::<Vec<_>>(), vec![10, 1, 20, 1]);
+
+        // negative dimensions are rejected
+        let mut invalid = record(2, None, 40);
+        if let Some(Value::Record(fields)) = invalid.get_mut("image") {
+            fields[0].1 = Value::Array(vec![Value::Int(-2), Value::Int(-1)]);
+        }
+        assert!(writer.write(&invalid).is_err());
+
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,336 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::collections::HashMap;
+
+use apache_avro::Schema;
+use apache_avro::schema::{Name, RecordSchema};
+use apache_avro::types::Value;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+
+use crate::dtype::{DType, TypeDescr};
+use crate::schema::Decoded;
+use crate::error::{FcError, FcResult};
+
+fn type_error(expected: &str, value: &serde_json::Value) -> FcError {
+    FcError::SchemaError(format!("Expected {}, got: {}", expected, value))
+}
+
+/// Named types of a schema, used to resolve references to them.
+pub(crate) struct Names(HashMap<String, Schema>);
+
+impl Names {
+    pub(crate) fn new(schema: &Schema) -> Self {
+        let mut names = Names(HashMap::new());
+        names.collect(schema);
+        names
+    }
+
+    fn collect(&mut self, schema: &Schema) {
+        match schema {
+            Schema::Record(rs) => {
+                self.0.insert(rs.name.fullname(None), schema.clone());
+                for field in &rs.fields {
+                    self.collect(&field.schema);
+                }
+            },
+            Schema::Enum(es) => { self.0.insert(es.name.fullname(None), schema.clone()); },
+            Schema::Fixed(fs) => { self.0.insert(fs.name.fullname(None), schema.clone()); },
+            Schema::Array(items) => self.collect(items),
+            Schema::Map(values) => self.collect(values),
+            Schema::Union(us) => {
+                for variant in us.variants() {
+                    self.collect(variant);
+                }
+            },
+            _ => (),
+        }
+    }
+
+    pub(crate) fn resolve<'a>(&'a self, schema: &'a Schema) -> FcResult<&'a Schema> {
+        match schema {
+            Schema::Ref { name } => self.get(name),
+            _ => Ok(schema),
+        }
+    }
+
+    fn get(&self, name: &Name) -> FcResult<&Schema> {
+        self.0.get(&name.fullname(None)).ok_or_else(
+            || FcError::SchemaError(format!("Unknown named type: {}", name.fullname(None))))
+    }
+}
+
+/// Whether a record schema has the layout of an ndarray record.
+pub(crate) fn is_ndarray(rs: &RecordSchema) -> bool {
+    rs.fields.len() == 3 && ["shape", "dtype", "data"].iter().all(|n| rs.lookup.contains_key(*n))
+}
+
+/// Flatten nested Json arrays of numbers, returning the numbers and the shape.
+fn flatten_numbers<'a>(value: &'a serde_json::Value, values: &mut Vec<&'a serde_json::Value>,
+                       shape: &mut Vec<i32>, depth: usize) -> FcResult<()> {
+    match value {
+        serde_json::Value::Array(items) => {
+            if shape.len() == depth {
+                shape.push(items.len() as i32);
+            } else if shape[depth] != items.len() as i32 {
+                return Err(FcError::SchemaError("Ragged nested arrays".to_string()));
+            }
+            for item in items {
+                flatten_numbers(item, values, shape, depth + 1)?;
+            }
+            Ok(())
+        },
+        serde_json::Value::Number(_) | serde_json::Value::Bool(_) => {
+            values.push(value);
+            Ok(())
+        },
+        _ => Err(type_error("number", value)),
+    }
+}
+
+/// Convert an ndarray given as {"dtype": ..., "data": [[...], ...]} with an
+/// optional "shape" into an ndarray record.
+///
+/// Integers are converted directly for integer dtypes, which do not accept
+/// numbers with a fractional part.
+fn ndarray_from_numbers(obj: &serde_json::Map<String, serde_json::Value>) -> FcResult<Value> {
+    let dtype = obj.get("dtype").and_then(|d| d.as_str()).ok_or_else(
+        || FcError::SchemaError("ndarray requires 'dtype'".to_string()))?;
+    let descr = TypeDescr::parse(dtype)?;
+
+    let mut values = Vec::new();
+    let mut shape = Vec::new();
+    flatten_numbers(&obj["data"], &mut values, &mut shape, 0)?;
+    if let Some(s) = obj.get("shape") {
+        shape = serde_json::from_value(s.clone())?;
+        let size = shape.iter().try_fold(1usize, |acc, &n| acc.checked_mul(usize::try_from(n).ok()?));
+        if size != Some(values.len()) {
+            return Err(FcError::SchemaError(format!(
+                "Shape {:?} does not match the {} elements of the ndarray", shape, values.len())));
+        }
+    }
+
+    let data = match descr.dtype {
+        DType::Float32 | DType::Float64 => descr.from_f64(&values.iter().map(|x| match x {
+            serde_json::Value::Bool(b) => Ok(if *b { 1. } else { 0. }),
+            _ => x.as_f64().ok_or_else(|| type_error("number", x)),
+        }).collect::<FcResult<Vec<_>>>()?)?,
+        _ => descr.from_integers(&values.iter().map(|x| match x {
+            serde_json::Value::Bool(b) => Ok(*b as i128),
+            _ => x.as_i64().map(i128::from).or_else(|| x.as_u64().map(i128::from))
+                .ok_or_else(|| type_error(&format!("integer for dtype {}", dtype), x)),
+        }).collect::<FcResult<Vec<_>>>()?)?,
+    };
+
+    Ok(Value::Record(vec![
+        ("shape".to_string(), Value::Array(shape.into_iter().map(Value::Int).collect())),
+        ("dtype".to_string(), Value::String(dtype.to_owned())),
+        ("data".to_string(), Value::Bytes(data)),
+    ]))
+}
+
+pub(crate) fn json_to_avro_impl(value: &serde_json::Value, schema: &Schema, names: &Names) -> FcResult<Value> {
+    use serde_json::Value as Json;
+
+    let schema = names.resolve(schema)?;
+    Ok(match (schema, value) {
+        (Schema::Null, Json::Null) => Value::Null,
+        (Schema::Boolean, Json::Bool(b)) => Value::Boolean(*b),
+        (Schema::Int, Json::Number(n)) => Value::Int(
+            n.as_i64().and_then(|x| i32::try_from(x).ok()).ok_or_else(|| type_error("int", value))?),
+        (Schema::Long, Json::Number(n)) => Value::Long(
+            n.as_i64().ok_or_else(|| type_error("long", value))?),
+        (Schema::Float, Json::Number(n)) => Value::Float(n.as_f64().unwrap() as f32),
+        (Schema::Double, Json::Number(n)) => Value::Double(n.as_f64().unwrap()),
+        (Schema::Bytes, Json::String(s)) => Value::Bytes(
+            BASE64.decode(s).map_err(|_| type_error("base64 string", value))?),
+        (Schema::String, Json::String(s)) => Value::String(s.clone()),
+        (Schema::Date, Json::Number(n)) => Value::Date(
+            n.as_i64().and_then(|x| i32::try_from(x).ok()).ok_or_else(|| type_error("date", value))?),
+        (Schema::TimeMillis, Json::Number(n)) => Value::TimeMillis(
+            n.as_i64().and_then(|x| i32::try_from(x).ok()).ok_or_else(|| type_error("time-millis", value))?),
+        (Schema::TimeMicros, Json::Number(n)) => Value::TimeMicros(
+            n.as_i64().ok_or_else(|| type_error("time-micros", value))?),
+        (Schema::TimestampMillis, Json::Number(n)) => Value::TimestampMillis(
+            n.as_i64().ok_or_else(|| type_error("timestamp-millis", value))?),
+        (Schema::TimestampMicros, Json::Number(n)) => Value::TimestampMicros(
+            n.as_i64().ok_or_else(|| type_error("timestamp-micros", value))?),
+        (Schema::Array(items), Json::Array(a)) => Value::Array(
+            a.iter().map(|x| json_to_avro_impl(x, items, names)).collect::<FcResult<_>>()?),
+        (Schema::Map(values), Json::Object(m)) => Value::Map(
+            m.iter().map(|(k, v)| Ok((k.clone(), json_to_avro_impl(v, values, names)?)))
+                .collect::<FcResult<_>>()?),
+        (Schema::Union(us), _) => {
+            let mut ret = None;
+            for (i, variant) in us.variants().iter().enumerate() {
+                if let Ok(v) = json_to_avro_impl(value, variant, names) {
+                    ret = Some(Value::Union(i as u32, Box::new(v)));
+                    break;
+                }
+            }
+            ret.ok_or_else(|| type_error("one of the union types", value))?
+        },
+        (Schema::Enum(es), Json::String(s)) => {
+            let index = es.symbols.iter().position(|x| x == s).ok_or_else(
+                || type_error("enum symbol", value))?;
+            Value::Enum(index as u32, s.clone())
+        },
+        (Schema::Fixed(fs), Json::String(s)) => {
+            let bytes = BASE64.decode(s).map_err(|_| type_error("base64 string", value))?;
+            if bytes.len() != fs.size {
+                return Err(type_error(&format!("{} bytes", fs.size), value));
+            }
+            Value::Fixed(fs.size, bytes)
+        },
+        (Schema::Record(rs), Json::Object(obj)) => {
+            if is_ndarray(rs) && obj.get("data").is_some_and(|d| d.is_array()) {
+                return ndarray_from_numbers(obj);
+            }
+            Value::Record(json_to_avro_fields(obj, rs, names)?)
+        },
+        _ => return Err(type_error(&format!("{:?}", schema), value)),
+    })
+}
+
+fn json_to_avro_fields(obj: &serde_json::Map<String, serde_json::Value>, rs: &RecordSchema, names: &Names)
+        -> FcResult<Vec<(String, Value)>> {
+    rs.fields.iter().map(|field| {
+        let value = match (obj.get(&field.name), &field.default) {
+            (Some(v), _) => json_to_avro_impl(v, &field.schema, names)?,
+            (None, Some(default)) => json_to_avro_impl(default, &field.schema, names)?,
+            (None, None) => json_to_avro_impl(&serde_json::Value::Null, &field.schema, names)
+                .map_err(|_| FcError::SchemaError(format!("Missing field: {}", field.name)))?,
+        };
+        Ok((field.name.clone(), value))
+    }).collect()
+}
+
+/// Convert a Json value into an Avro value of a given schema.
+///
+/// Bytes and fixed values are expected as base64 strings. The data of an
+/// ndarray record can be given either as a base64 string or as (nested)
+/// arrays of numbers.
+pub fn json_to_avro(value: &serde_json::Value, schema: &Schema) -> FcResult<Value> {
+    json_to_avro_impl(value, schema, &Names::new(schema))
+}
+
+/// Convert a Json object into a decoded record of a given record schema.
+pub fn json_to_decoded(value: &serde_json::Value, schema: &Schema) -> FcResult<Decoded> {
+    let names = Names::new(schema);
+    match (names.resolve(schema)?, value) {
+        (Schema::Record(rs), serde_json::Value::Object(obj)) => {
+            Ok(json_to_avro_fields(obj, rs, &names)?.into_iter().collect())
+        },
+        _ => Err(type_error("object", value)),
+    }
+}
+
+/// Convert an Avro value into a Json value.
+///
+/// Bytes and fixed values are encoded as base64 strings.
+pub fn avro_to_json(value: &Value) -> FcResult<serde_json::Value> {
+    use serde_json::Value as Json;
+
+    Ok(match value {
+        Value::Null => Json::Null,
+        Value::Boolean(b) => Json::Bool(*b),
+        Value::Int(x) | Value::Date(x) | Value::TimeMillis(x) => Json::from(*x),
+        Value::Long(x) | Value::TimeMicros(x)
+            | Value::TimestampMillis(x) | Value::TimestampMicros(x) => Json::from(*x),
+        Value::Float(x) => serde_json::Number::from_f64(*x as f64).map_or(Json::Null, Json::Number),
+        Value::Double(x) => serde_json::Number::from_f64(*x).map_or(Json::Null, Json::Number),
+        Value::Bytes(b) | Value::Fixed(_, b) => Json::String(BASE64.encode(b)),
+        Value::String(s) | Value::Enum(_, s) => Json::String(s.clone()),
+        Value::Union(_, v) => avro_to_json(v)?,
+        Value::Array(items) => Json::Array(items.iter().map(avro_to_json).collect::<FcResult<_>>()?),
+        Value::Map(m) => Json::Object(
+            m.iter().map(|(k, v)| Ok((k.clone(), avro_to_json(v)?))).collect::<FcResult<_>>()?),
+        Value::Record(fields) => Json::Object(
+            fields.iter().map(|(k, v)| Ok((k.clone(), avro_to_json(v)?))).collect::<FcResult<_>>()?),
+        _ => return Err(FcError::SchemaError(format!("Unsupported Avro value: {:?}", value))),
+    })
+}
+
+/// Convert a decoded record into a Json object.
+pub fn decoded_to_json(datum: &Decoded) -> FcResult<serde_json::Value> {
+    Ok(serde_json::Value::Object(
+        datum.iter().map(|(k, v)| Ok((k.clone(), avro_to_json(v)?))).collect::<FcResult<_>>()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use apache_avro::types::Value;
+
+    use crate::json::{json_to_decoded, decoded_to_json};
+    use crate::schema::{Decoded, json_to_avro_schema};
+
+    #[test]
+    fn test_json_to_decoded() {
+        let schema = json_to_avro_schema(&serde_json::json!({
+            "namespace": "testcase",
+            "type": "record",
+            "name": "raw",
+            "fields": [
+                {"name": "index", "type": "int"},
+                {"name": "timestamp", "type": "long"},
+                {"name": "comment", "type": ["null", "string"]},
+                {"name": "image", "type": {
+                    "type": "record",
+                    "name": "NDArray",
+                    "fields": [
+                        {"name": "shape", "type": {"items": "int", "type": "array"}},
+                        {"name": "dtype", "type": "string"},
+                        {"name": "data", "type": "bytes"}
+                    ]
+                }}
+            ]
+        }));
+
+        let decoded = json_to_decoded(&serde_json::json!({
+            "index": 1,
+            "timestamp": 1_700_000_000_000i64,
+            "image": {"dtype": "|u1", "data": [[1, 2], [3, 4]]}
+        }), &schema).unwrap();
+
+        let image = Value::Record(vec![
+            ("shape".to_string(), Value::Array(vec![Value::Int(2), Value::Int(2)])),
+            ("dtype".to_string(), Value::String("|u1".to_string())),
+            ("data".to_string(), Value::Bytes(vec![1, 2, 3, 4])),
+        ]);
+        assert_eq!(decoded, Decoded::from([
+            ("index".to_string(), Value::Int(1)),
+            ("timestamp".to_string(), Value::Long(1_700_000_000_000)),
+            ("comment".to_string(), Value::Union(0, Box::new(Value::Null))),
+            ("image".to_string(), image),
+        ]));
+
+        let json = decoded_to_json(&decoded).unwrap();
+        assert_eq!(json["image"]["data"], "AQIDBA==");
+        assert_eq!(json_to_decoded(&json, &schema).unwrap(), decoded);
+
+        assert!(json_to_decoded(&serde_json::json!({"index": 1}), &schema).is_err());
+        assert!(json_to_decoded(&serde_json::json!({
+            "index": 1i64 << 40, "timestamp": 0, "image": json["image"]
+        }), &schema).is_err());
+
+        let image = |value: serde_json::Value| json_to_decoded(&serde_json::json!({
+            "index": 1, "timestamp": 0, "image": value
+        }), &schema);
+        // integers are not converted through f64
+        let decoded = image(serde_json::json!({"dtype": "<i8", "data": [i64::MAX]})).unwrap();
+        assert_eq!(decoded["image"], Value::Record(vec![
+            ("shape".to_string(), Value::Array(vec![Value::Int(1)])),
+            ("dtype".to_string(), Value::String("<i8".to_string())),
+            ("data".to_string(), Value::Bytes(i64::MAX.to_le_bytes().to_vec())),
+        ]));
+        assert!(image(serde_json::json!({"dtype": "|u1", "data": [3.5]})).is_err());
+        assert!(image(serde_json::json!({"dtype": "|u1", "data": [256]})).is_err());
+        assert!(image(serde_json::json!({"dtype": "|u1", "shape": [3], "data": [1, 2]})).is_err());
+        assert!(image(serde_json::json!({"dtype": "|u1", "shape": [2, 1], "data": [1, 2]})).is_ok());
+    }
+}
@@ -0,0 +1,245 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::collections::{BTreeSet, HashMap};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use rdkafka::Message;
+use rdkafka::config::ClientConfig;
+use rdkafka::consumer::{BaseConsumer, Consumer};
+use rdkafka::error::KafkaError;
+use rdkafka::producer::{BaseProducer, BaseRecord, DeliveryResult, Producer, ProducerContext};
+use rdkafka::ClientContext;
+
+use crate::decoder::{create_decoder, Decoder};
+use crate::encoder::{create_encoder, Encoder};
+use crate::schema::Decoded;
+use crate::error::{FcError, FcResult};
+
+/// Collects the delivery reports of the records sent by a producer.
+#[derive(Default)]
+struct DeliveryContext {
+    reports: Mutex<HashMap<usize, Result<String, KafkaError>>>,
+}
+
+impl ClientContext for DeliveryContext {}
+
+impl ProducerContext for DeliveryContext {
+    type DeliveryOpaque = usize;
+
+    fn delivery(&self, result: &DeliveryResult<'_>, index: usize) {
+        let report = match result {
+            Ok(msg) => Ok(format!("{}-{}", msg.partition(), msg.offset())),
+            Err((e, _)) => Err(e.clone()),
+        };
+        self.reports.lock().unwrap().insert(index, report);
+    }
+}
+
+pub struct KafkaProducer {
+    producer: BaseProducer<DeliveryContext>,
+    timeout: Duration,
+    encoder: Option<Box<dyn Encoder + Send>>,
+    // ids of the records which were sent but not reported yet
+    pending: BTreeSet<usize>,
+    next_id: usize,
+}
+
+impl KafkaProducer {
+    pub fn new(brokers: &str) -> Self {
+        let producer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("message.timeout.ms", "5000")
+            .create_with_context(DeliveryContext::default())
+            .expect("Failed to create a Kafka producer");
+
+        KafkaProducer {
+            producer,
+            timeout: Duration::from_secs(10),
+            encoder: None,
+            pending: BTreeSet::new(),
+            next_id: 0,
+        }
+    }
+
+    pub fn set_encoder(&mut self, name: &str, schema: Option<&serde_json::Value>) {
+        self.encoder = Some(create_encoder(name, schema));
+    }
+
+    /// Sets the maximum time `flush` waits for the delivery reports.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Queue records for a given topic.
+    ///
+    /// Returns the errors of the records which could not be queued and
+    /// "partition-offset" of the records delivered since the last call.
+    pub fn produce(&mut self, records: &[Decoded], topic: &str) -> Vec<FcResult<String>> {
+        let mut entries = Vec::new();
+        for x in records {
+            let ret = self.encoder.as_ref().unwrap().pack(x).and_then(|encoded| {
+                self.producer.send(BaseRecord::<(), _, _>::with_opaque_to(topic, self.next_id).payload(&encoded))
+                    .map_err(|(e, _)| FcError::KafkaError(e))
+            });
+            match ret {
+                Ok(_) => {
+                    self.pending.insert(self.next_id);
+                    self.next_id += 1;
+                },
+                Err(e) => entries.push(Err(e)),
+            }
+        }
+
+        self.producer.poll(Duration::ZERO);
+        entries.extend(self.take_reports());
+        entries
+    }
+
+    /// Wait for the delivery of all the queued records and return
+    /// "partition-offset" of the records delivered since the last call.
+    pub fn flush(&mut self) -> Vec<FcResult<String>> {
+        let ret = self.producer.flush(self.timeout);
+        let mut entries = self.take_reports();
+        // the records which are still pending are reported later
+        if let Err(e) = ret {
+            entries.push(Err(FcError::KafkaError(e)));
+        }
+        entries
+    }
+
+    fn take_reports(&mut self) -> Vec<FcResult<String>> {
+        let mut reports = self.producer.context().reports.lock().unwrap();
+        let mut entries = Vec::new();
+        self.pending.retain(|id| match reports.remove(id) {
+            Some(report) => {
+                entries.push(report.map_err(FcError::KafkaError));
+                false
+            },
+            None => true,
+        });
+        entries
+    }
+}
+
+impl Drop for KafkaProducer {
+    fn drop(&mut self) {
+        // the queued records are lost otherwise
+        let _ = self.producer.flush(self.timeout);
+    }
+}
+
+pub struct KafkaConsumer {
+    consumer: BaseConsumer,
+    timeout: Duration,
+    topic: Option<String>,
+    decoder: Option<Box<dyn Decoder + Send>>,
+}
+
+impl KafkaConsumer {
+    pub fn new(brokers: &str, group_id: &str) -> Self {
+        let consumer = ClientConfig::new()
+            .set("bootstrap.servers", brokers)
+            .set("group.id", group_id)
+            .set("auto.offset.reset", "earliest")
+            .create()
+            .expect("Failed to create a Kafka consumer");
+
+        KafkaConsumer {
+            consumer,
+            timeout: Duration::from_millis(100),
+            topic: None,
+            decoder: None,
+        }
+    }
+
+    pub fn set_decoder(&mut self, name: &str, schema: Option<&serde_json::Value>) {
+        self.decoder = Some(create_decoder(name, schema));
+    }
+
+    /// Sets the maximum time to wait for a record in `consume`.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Consumes a single record from a given topic and returns a tuple of
+    /// ("partition-offset", decoded record).
+    pub fn consume(&mut self, topic: &str) -> FcResult<(String, Decoded)> {
+        if self.topic.as_deref() != Some(topic) {
+            self.consumer.subscribe(&[topic])?;
+            self.topic = Some(topic.to_owned());
+        }
+
+        let msg = match self.consumer.poll(self.timeout) {
+            Some(msg) => msg?,
+            None => return Err(FcError::KafkaError(KafkaError::NoMessageReceived)),
+        };
+        let bytes = msg.payload().unwrap_or_default().to_vec();
+
+        let decoded = self.decoder.as_ref().unwrap().unpack(&bytes)?;
+        assert_eq!(decoded.len(), 1);
+
+        Ok((format!("{}-{}", msg.partition(), msg.offset()),
+            decoded.into_iter().next().unwrap()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use apache_avro::types::Value;
+
+    use crate::schema::Decoded;
+    use crate::kafka_clients::{KafkaConsumer, KafkaProducer};
+
+    #[test]
+    fn test_kafka_consumer_and_producer() {
+        let raw_schema = r#"
+            {
+                "namespace": "kafka_clients_test",
+                "type": "record",
+                "name": "raw",
+                "fields": [
+                    {
+                      "name": "index",
+                      "type": "int"
+                    }
+                ]
+            }"#;
+        let json_schema: serde_json::Value = serde_json::from_str(raw_schema).unwrap();
+        let topic = format!("kafka_clients_test.raw_{}", std::process::id());
+
+        let brokers = "127.0.0.1:9092";
+
+        let mut producer = KafkaProducer::new(brokers);
+        producer.set_encoder("avro", Some(&json_schema));
+
+        let mut consumer = KafkaConsumer::new(brokers, &topic);
+        consumer.set_decoder("avro", Some(&json_schema));
+        consumer.set_timeout(Duration::from_secs(5));
+
+        const NUM_RECORDS: i32 = 3;
+        let items: Vec<Decoded> = (0..NUM_RECORDS).map(|i| {
+            Decoded::from([("index".to_string(), Value::Int(i))])
+        }).collect();
+
+        let mut entries = producer.produce(&items, &topic);
+        entries.extend(producer.flush());
+        if let Some(Err(e)) = entries.first() {
+            println!("Test skipped: no Kafka broker: {:?}", e);
+            return;
+        }
+        assert_eq!(entries.len(), NUM_RECORDS as usize);
+
+        for i in 0..NUM_RECORDS {
+            let (_, ret) = consumer.consume(&topic).unwrap();
+            assert_eq!(ret, Decoded::from([("index".to_string(), Value::Int(i))]));
+        }
+    }
+}
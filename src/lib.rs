@@ -5,9 +5,41 @@
  *
  * Author: Jun Zhu
  */
+pub mod borrowed;
+#[cfg(feature = "arrow")]
+pub mod columnar;
+mod container;
 pub mod decoder;
+pub mod dtype;
 pub mod encoder;
+#[cfg(feature = "epics")]
+pub mod epics_clients;
+pub mod event_builder;
+#[cfg(feature = "parquet")]
+pub mod export;
+#[cfg(feature = "hdf5")]
+pub mod hdf5_writer;
+#[cfg(feature = "kafka")]
+pub mod kafka_clients;
+#[cfg(feature = "mqtt")]
+pub mod mqtt_clients;
+#[cfg(feature = "nats")]
+pub mod nats_clients;
+pub mod passthrough;
+pub mod pipeline;
+pub mod rate_limit;
 pub mod redis_clients;
 pub mod schema;
+pub mod schema_store;
+#[cfg(feature = "script")]
+pub mod script;
+pub mod transform;
 pub mod error;
-pub mod zmq_clients;
\ No newline at end of file
+pub mod json;
+mod node;
+#[cfg(feature = "wasm")]
+pub mod wasm_decoder;
+pub mod zmq_clients;
+
+#[doc(hidden)]
+pub use serde_json;
@@ -5,71 +5,1062 @@
  *
  * Author: Jun Zhu
  */
-use clap::Parser;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use foamcore::zmq_clients::ZmqConsumer;
-use foamcore::redis_clients::RedisProducer;
-use foamcore::schema::{SchemaRegistry, load_schema};
+use clap::{Args, Parser, Subcommand};
+
+#[cfg(feature = "nats")]
+use foamcore::error::FcError;
+use foamcore::error::FcResult;
+use foamcore::zmq_clients::{ZmqConsumer, ZmqSocketOptions};
+use foamcore::redis_clients::{RedisConsumer, RedisProducer, StatusWriter};
+#[cfg(feature = "epics")]
+use foamcore::epics_clients::EpicsConsumer;
+use foamcore::event_builder::{EventBuilder, combined_schema};
+#[cfg(feature = "hdf5")]
+use foamcore::hdf5_writer::Hdf5Writer;
+#[cfg(feature = "kafka")]
+use foamcore::kafka_clients::KafkaProducer;
+#[cfg(feature = "mqtt")]
+use foamcore::mqtt_clients::MqttProducer;
+#[cfg(feature = "nats")]
+use foamcore::nats_clients::{NatsConsumer, NatsProducer};
+#[cfg(feature = "kafka")]
+use foamcore::schema::topic_name;
+use foamcore::decoder::create_decoder;
+use foamcore::encoder::create_encoder;
+#[cfg(feature = "parquet")]
+use foamcore::decoder::Decoder;
+#[cfg(feature = "parquet")]
+use foamcore::export::{ExportLayout, ParquetExporter};
+#[cfg(feature = "parquet")]
+use foamcore::schema::parse_avro_schema;
+use foamcore::passthrough::Passthrough;
+use foamcore::pipeline::{Pipeline, transcode};
+use foamcore::rate_limit::RatePolicy;
+use foamcore::schema::{Decoded, SchemaRegistry, diff_schemas, load_schema, read_schema};
+#[cfg(feature = "script")]
+use foamcore::script::ScriptStage;
+use foamcore::transform::Transformer;
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    run: RunArgs,
+    /// Hostname of the Redis server
+    #[arg(long, global = true, default_value_t = String::from("127.0.0.1"))]
+    redis_host: String,
+    /// Port of the Redis server
+    #[arg(long, global = true, default_value_t = 6379)]
+    redis_port: i32,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Manage the schemas registered in Redis
+    #[command(subcommand)]
+    Schema(SchemaCommand),
+    /// Export a range of a Redis stream or recorded files
+    Export(ExportArgs),
+    /// Merge the records of several streams which have the same key
+    BuildEvents(BuildEventsArgs),
+}
+
+#[derive(Args)]
+struct BuildEventsArgs {
+    /// Input streams (namespace:name)
+    #[arg(required = true, num_args = 1..)]
+    streams: Vec<String>,
+    /// Output stream (namespace:name)
+    #[arg(short, long)]
+    output: String,
+    /// Field used to match the records, e.g. train or pulse ID
+    #[arg(long, default_value_t = String::from("pulse_id"))]
+    key: String,
+    /// Time in milliseconds to wait for the records of an event
+    #[arg(long, default_value_t = 1000)]
+    window: u64,
+    /// Also publish incomplete events with null for the missing streams
+    #[arg(long)]
+    emit_incomplete: bool,
+    /// Decoder name for the input streams
+    #[arg(long, default_value_t = String::from("avro"))]
+    decoder: String,
+    /// Encoder name for the output stream
+    #[arg(long, default_value_t = String::from("avro"))]
+    encoder: String,
+}
+
+#[derive(Args)]
+struct ExportArgs {
+    /// Output file
+    #[arg(short, long)]
+    output: String,
+    /// Output format
+    #[arg(long, default_value_t = String::from("parquet"))]
+    format: String,
+    /// Path of the Avro schema file. The registered schema of the stream is used if omitted
+    #[arg(long)]
+    schema_file: Option<String>,
+    /// Stream name (namespace:name). Derived from the schema file if omitted
+    #[arg(long)]
+    stream: Option<String>,
+    /// First stream ID of the range
+    #[arg(long, default_value_t = String::from("-"))]
+    start: String,
+    /// Last stream ID of the range
+    #[arg(long, default_value_t = String::from("+"))]
+    end: String,
+    /// Decoder name for the data in the stream
+    #[arg(long, default_value_t = String::from("avro"))]
+    decoder: String,
+    /// Recorded files to export instead of the stream, decoded with the decoder
+    #[arg(long, num_args = 1..)]
+    input: Vec<String>,
+    /// Maximum number of rows in a row group
+    #[arg(long, default_value_t = 65536)]
+    row_group_size: usize,
+}
+
+#[derive(Subcommand)]
+enum SchemaCommand {
+    /// List the streams which have a registered schema
+    List,
+    /// Print the registered schema of a stream
+    Get {
+        /// Stream name (namespace:name)
+        stream: String,
+        /// Schema version
+        #[arg(long, default_value_t = String::from("0"))]
+        version: String,
+    },
+    /// Register the schema in a local file
+    Set {
+        /// Path of the Avro schema file
+        schema_file: String,
+    },
+    /// Compare a local schema file with the registered one
+    Diff {
+        /// Path of the Avro schema file
+        schema_file: String,
+    },
+    /// Delete the registered schema of a stream
+    Delete {
+        /// Stream name (namespace:name)
+        stream: String,
+    },
+}
+
+#[derive(Args)]
+struct RunArgs {
     /// Path of the Avro schema file
     #[arg(default_value_t = String::from(""))]
     schema_file: String,
     /// Decoder name for the incoming data
     #[arg(long, default_value_t = String::from("avro"))]
     decoder: String,
-    /// Encoder name for the data pushed to Redis
+    /// Encoder name for the published data
     #[arg(long, default_value_t = String::from("avro"))]
     encoder: String,
+    /// Origin of the data (zmq, redis, nats or epics)
+    #[arg(long, default_value_t = String::from("zmq"))]
+    source: String,
+    /// Destination of the data (redis, kafka, nats, mqtt or hdf5)
+    #[arg(long, default_value_t = String::from("redis"))]
+    sink: String,
+    /// Comma-separated list of Kafka brokers
+    #[cfg(feature = "kafka")]
+    #[arg(long, default_value_t = String::from("127.0.0.1:9092"))]
+    kafka_brokers: String,
+    /// NATS server URL
+    #[cfg(feature = "nats")]
+    #[arg(long, default_value_t = String::from("nats://127.0.0.1:4222"))]
+    nats_url: String,
+    /// Persist the data published to NATS in JetStream
+    #[cfg(feature = "nats")]
+    #[arg(long)]
+    nats_jetstream: bool,
+    /// Name of the JetStream durable consumer when consuming from NATS
+    #[cfg(feature = "nats")]
+    #[arg(long)]
+    nats_durable: Option<String>,
+    /// Hostname of the MQTT broker
+    #[cfg(feature = "mqtt")]
+    #[arg(long, default_value_t = String::from("127.0.0.1"))]
+    mqtt_host: String,
+    /// Port of the MQTT broker
+    #[cfg(feature = "mqtt")]
+    #[arg(long, default_value_t = 1883)]
+    mqtt_port: u16,
+    /// MQTT quality of service level (0, 1 or 2)
+    #[cfg(feature = "mqtt")]
+    #[arg(long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=2))]
+    mqtt_qos: u8,
+    /// Do not publish retained MQTT messages
+    #[cfg(feature = "mqtt")]
+    #[arg(long)]
+    mqtt_no_retain: bool,
+    /// EPICS PV of a schema field given as FIELD=PV (repeatable)
+    #[cfg(feature = "epics")]
+    #[arg(long)]
+    epics_pv: Vec<String>,
+    /// EPICS protocol (ca or pva)
+    #[cfg(feature = "epics")]
+    #[arg(long, default_value_t = String::from("ca"))]
+    epics_protocol: String,
+    /// Directory of the HDF5 files
+    #[cfg(feature = "hdf5")]
+    #[arg(long, default_value_t = String::from("."))]
+    hdf5_dir: String,
+    /// Field holding the run number. A new HDF5 file is started for each run
+    #[cfg(feature = "hdf5")]
+    #[arg(long)]
+    hdf5_run_field: Option<String>,
     /// ZeroMQ endpoint
     #[arg(long, default_value_t = String::from("tcp://127.0.0.1:45454"))]
     zmq_endpoint: String,
     /// ZeroMQ socket type (REQ, PULL or SUB)
     #[arg(long, default_value_t = String::from("SUB"))]
     zmq_sock: String,
-    /// Hostname of the Redis server
-    #[arg(long, default_value_t = String::from("127.0.0.1"))]
-    redis_host: String,
-    /// Port of the Redis server
-    #[arg(long, default_value_t = 6379)]
-    redis_port: i32,
+    /// Maximum number of messages queued by the ZeroMQ socket
+    #[arg(long)]
+    zmq_hwm: Option<i32>,
+    /// Milliseconds pending ZeroMQ messages are kept after closing
+    #[arg(long)]
+    zmq_linger: Option<i32>,
+    /// Milliseconds a ZeroMQ receive waits before checking for deferred records
+    #[arg(long)]
+    zmq_receive_timeout: Option<i32>,
+    /// Size of the kernel buffers of the ZeroMQ socket in bytes
+    #[arg(long)]
+    zmq_buffer_size: Option<i32>,
+    /// Milliseconds between attempts to reconnect to the ZeroMQ endpoint
+    #[arg(long)]
+    zmq_reconnect_interval: Option<i32>,
+    /// Enable TCP keepalive on the ZeroMQ socket
+    #[arg(long)]
+    zmq_tcp_keepalive: bool,
+    /// Keep only the latest message in the ZeroMQ queue
+    #[arg(long)]
+    zmq_conflate: bool,
+    /// Integer field of the records used to detect dropped ZeroMQ messages.
+    /// Not supported with passthrough or parallel workers
+    #[arg(long)]
+    zmq_sequence_field: Option<String>,
+    /// Report ZeroMQ connection events and write the status of the source
+    /// into the Redis hash "<stream>:_status"
+    #[arg(long)]
+    zmq_monitor: bool,
+    /// Report when no ZeroMQ message has arrived for the given number of seconds
+    #[arg(long)]
+    zmq_watchdog: Option<u64>,
+    /// Number of threads which decode and encode in parallel (zmq source and
+    /// redis sink only). Records are processed on the main thread if 0
+    #[arg(long, default_value_t = 0)]
+    workers: usize,
+    /// Maximum number of messages waiting for a worker
+    #[arg(long, default_value_t = 64)]
+    queue_size: usize,
+    /// Forward Avro messages from ZeroMQ to Redis without decoding them. The
+    /// writer schema of each message must match the schema file
+    #[arg(long)]
+    passthrough: bool,
+    /// Fraction of the forwarded messages which are fully decoded for validation
+    #[arg(long, default_value_t = 0.0)]
+    passthrough_sample: f64,
+    /// Json file with a list of transforms applied to the records before publishing
+    #[arg(long)]
+    transform_file: Option<String>,
+    /// Rhai script with `fn process(record)` run on each record before the
+    /// transforms. The script is reloaded when the file changes
+    #[cfg(feature = "script")]
+    #[arg(long)]
+    script: Option<String>,
+    /// Limit the records published to Redis, given as [STREAM=]POLICY
+    /// (repeatable). POLICY is rate:<records per second>[:<burst>],
+    /// every:<n> or latest:<milliseconds>. STREAM defaults to the output stream
+    #[arg(long)]
+    rate_limit: Vec<String>,
 }
 
-fn main() {
-    let cli = Cli::parse();
+enum Source {
+    Zmq(ZmqConsumer),
+    /// Tails a Redis stream from the last consumed ID.
+    Redis(RedisConsumer, Option<String>),
+    #[cfg(feature = "nats")]
+    Nats(NatsConsumer),
+    #[cfg(feature = "epics")]
+    Epics(EpicsConsumer),
+}
+
+impl Source {
+    fn set_decoder(&mut self, name: &str, schema: Option<&serde_json::Value>) {
+        match self {
+            Source::Zmq(c) => c.set_decoder(name, schema),
+            Source::Redis(c, _) => c.set_decoder(name, schema),
+            #[cfg(feature = "nats")]
+            Source::Nats(c) => c.set_decoder(name, schema),
+            // PV values are converted according to the schema
+            #[cfg(feature = "epics")]
+            Source::Epics(_) => (),
+        }
+    }
+
+    /// Consume a single record. Returns None if no record arrived in time.
+    fn consume(&mut self, _stream: &str) -> FcResult<Option<Decoded>> {
+        match self {
+            Source::Zmq(c) => {
+                let dropped = c.dropped();
+                let decoded = c.try_consume()?;
+                if c.dropped() > dropped {
+                    println!("Detected {} dropped messages from ZeroMQ", c.dropped() - dropped);
+                }
+                Ok(decoded)
+            },
+            Source::Redis(c, id) => {
+                let (sid, decoded) = c.consume(_stream, id.as_deref())?;
+                *id = Some(sid);
+                Ok(Some(decoded))
+            },
+            #[cfg(feature = "nats")]
+            Source::Nats(c) => match c.consume(_stream) {
+                Ok(x) => Ok(Some(x)),
+                Err(FcError::IoError(e)) if e.kind() == std::io::ErrorKind::TimedOut => Ok(None),
+                Err(e) => Err(e),
+            },
+            #[cfg(feature = "epics")]
+            Source::Epics(c) => {
+                let skipped = c.skipped();
+                let decoded = c.consume()?;
+                if c.skipped() > skipped {
+                    println!("Skipped {} EPICS monitor updates", c.skipped() - skipped);
+                }
+                Ok(Some(decoded))
+            },
+        }
+    }
+}
+
+enum Sink {
+    Redis(RedisProducer),
+    #[cfg(feature = "kafka")]
+    Kafka(KafkaProducer),
+    #[cfg(feature = "nats")]
+    Nats(NatsProducer),
+    #[cfg(feature = "mqtt")]
+    Mqtt(MqttProducer),
+    #[cfg(feature = "hdf5")]
+    Hdf5(Hdf5Writer),
+}
+
+impl Sink {
+    fn name(&self) -> &'static str {
+        match self {
+            Sink::Redis(_) => "Redis stream",
+            #[cfg(feature = "kafka")]
+            Sink::Kafka(_) => "Kafka topic",
+            #[cfg(feature = "nats")]
+            Sink::Nats(_) => "NATS subject",
+            #[cfg(feature = "mqtt")]
+            Sink::Mqtt(_) => "MQTT topic",
+            #[cfg(feature = "hdf5")]
+            Sink::Hdf5(_) => "HDF5 file",
+        }
+    }
+
+    fn set_encoder(&mut self, name: &str, schema: Option<&serde_json::Value>) {
+        match self {
+            Sink::Redis(p) => p.set_encoder(name, schema),
+            #[cfg(feature = "kafka")]
+            Sink::Kafka(p) => p.set_encoder(name, schema),
+            #[cfg(feature = "nats")]
+            Sink::Nats(p) => p.set_encoder(name, schema),
+            #[cfg(feature = "mqtt")]
+            Sink::Mqtt(p) => p.set_encoder(name, schema),
+            // records are written as HDF5 datasets
+            #[cfg(feature = "hdf5")]
+            Sink::Hdf5(_) => (),
+        }
+    }
+
+    fn produce(&mut self, records: &[Decoded], stream: &str) -> Vec<FcResult<String>> {
+        match self {
+            Sink::Redis(p) => p.produce(records, stream),
+            #[cfg(feature = "kafka")]
+            Sink::Kafka(p) => p.produce(records, &topic_name(stream)),
+            #[cfg(feature = "nats")]
+            Sink::Nats(p) => p.produce(records, stream),
+            #[cfg(feature = "mqtt")]
+            Sink::Mqtt(p) => p.produce(records, stream),
+            #[cfg(feature = "hdf5")]
+            Sink::Hdf5(w) => w.produce(records, stream),
+        }
+    }
+
+    /// Publish the records which are deferred or queued by the sink.
+    fn flush(&mut self) -> Vec<FcResult<String>> {
+        match self {
+            Sink::Redis(p) => p.flush(),
+            #[cfg(feature = "kafka")]
+            Sink::Kafka(p) => p.flush(),
+            #[cfg(feature = "nats")]
+            Sink::Nats(_) => Vec::new(),
+            #[cfg(feature = "mqtt")]
+            Sink::Mqtt(_) => Vec::new(),
+            #[cfg(feature = "hdf5")]
+            Sink::Hdf5(_) => Vec::new(),
+        }
+    }
+
+    fn redis(&mut self) -> Option<&mut RedisProducer> {
+        match self {
+            Sink::Redis(p) => Some(p),
+            #[cfg(feature = "kafka")]
+            Sink::Kafka(_) => None,
+            #[cfg(feature = "nats")]
+            Sink::Nats(_) => None,
+            #[cfg(feature = "mqtt")]
+            Sink::Mqtt(_) => None,
+            #[cfg(feature = "hdf5")]
+            Sink::Hdf5(_) => None,
+        }
+    }
+}
+
+fn to_pretty_string(schema: &serde_json::Value) -> String {
+    serde_json::to_string_pretty(schema).unwrap()
+}
+
+/// Execute a schema subcommand and return whether it succeeded.
+fn run_schema_command(command: SchemaCommand, host: &str, port: i32) -> FcResult<bool> {
+    let mut registry = SchemaRegistry::new(host, port);
+
+    match command {
+        SchemaCommand::List => {
+            for stream in registry.list()? {
+                println!("{} (versions: {})", stream, registry.versions(&stream)?.join(", "));
+            }
+        },
+        SchemaCommand::Get { stream, version } => {
+            match registry.get_version(&stream, &version)? {
+                Some(schema) => {
+                    println!("Versions: {}", registry.versions(&stream)?.join(", "));
+                    println!("{}", to_pretty_string(&schema));
+                },
+                None => {
+                    eprintln!("No schema (version {}) registered for stream: {}", version, stream);
+                    return Ok(false);
+                },
+            }
+        },
+        SchemaCommand::Set { schema_file } => {
+            let (json_schema, stream) = read_schema(&schema_file)?;
+            registry.set(&stream, json_schema.as_ref())?;
+            println!("Registered schema for stream: {}", stream);
+        },
+        SchemaCommand::Diff { schema_file } => {
+            let (json_schema, stream) = read_schema(&schema_file)?;
+            let local = json_schema.unwrap_or(serde_json::Value::Null);
+            let registered = match registry.get_version(&stream, "0")? {
+                Some(schema) => schema,
+                None => {
+                    eprintln!("No schema registered for stream: {}", stream);
+                    return Ok(false);
+                },
+            };
+
+            let diffs = diff_schemas(&registered, &local);
+            if diffs.is_empty() {
+                println!("Schema is identical to the registered one for stream: {}", stream);
+            } else {
+                println!("Schema differs from the registered one for stream: {}", stream);
+                for diff in &diffs {
+                    println!("  {}", diff);
+                }
+                return Ok(false);
+            }
+        },
+        SchemaCommand::Delete { stream } => {
+            registry.delete(&stream)?;
+            println!("Deleted schema for stream: {}", stream);
+        },
+    }
+
+    Ok(true)
+}
+
+/// Maximum number of entries of each stream read by the event builder at once.
+const EVENT_READ_COUNT: usize = 100;
+
+/// Execute the build-events subcommand. It runs until an error occurs.
+fn run_event_builder(args: BuildEventsArgs, host: &str, port: i32) -> FcResult<bool> {
+    let mut registry = SchemaRegistry::new(host, port);
+    let mut streams = Vec::with_capacity(args.streams.len());
+    let mut decoders = HashMap::with_capacity(args.streams.len());
+    for stream in &args.streams {
+        let schema = match registry.get_version(stream, "0")? {
+            Some(schema) => schema,
+            None => {
+                eprintln!("No schema registered for stream: {}", stream);
+                return Ok(false);
+            },
+        };
+        decoders.insert(stream.clone(), create_decoder(&args.decoder, Some(&schema)));
+        streams.push((stream.clone(), schema));
+    }
+    // all the streams are read with a single XREAD, starting from new entries
+    let mut consumer = RedisConsumer::new(host, port);
+    consumer.set_block(100);
+    let mut ids: HashMap<String, String> = args.streams.iter().map(|s| (s.clone(), "$".to_string())).collect();
+
+    let (namespace, name) = match args.output.split_once(':') {
+        Some(x) => x,
+        None => {
+            eprintln!("Output stream must be given as namespace:name: {}", args.output);
+            return Ok(false);
+        },
+    };
+    let schema = combined_schema(namespace, name, &args.key, &streams)?;
+    registry.set(&args.output, Some(&schema))?;
+
+    let mut producer = RedisProducer::new(host, port);
+    producer.set_encoder(&args.encoder, Some(&schema));
+    let mut builder = EventBuilder::new(&args.key, &streams, Duration::from_millis(args.window))?;
+    let mut last_report = Instant::now();
+
+    loop {
+        let names: Vec<&str> = args.streams.iter().map(String::as_str).collect();
+        let last_ids: Vec<&str> = args.streams.iter().map(|s| ids[s].as_str()).collect();
+        let entries = consumer.read_raw(&names, &last_ids, EVENT_READ_COUNT)?;
+
+        let mut merged = Vec::new();
+        for (stream, sid, bytes) in entries {
+            for record in decoders[&stream].unpack(&bytes)? {
+                merged.extend(builder.push(&stream, record, Instant::now())?);
+            }
+            ids.insert(stream, sid);
+        }
+
+        for event in builder.expire(Instant::now()) {
+            println!("Incomplete event {}: missing {}", event.key, event.missing.join(", "));
+            if args.emit_incomplete {
+                merged.push(event.record);
+            }
+        }
+
+        for entry in producer.produce(&merged, &args.output) {
+            if let Err(e) = entry {
+                println!("Error while publishing merged data to {}: {:?}", args.output, e);
+            }
+        }
+
+        if last_report.elapsed() > STATS_INTERVAL {
+            let (complete, incomplete) = builder.counts();
+            println!("Event statistics: {} complete, {} incomplete, {} pending",
+                     complete, incomplete, builder.num_pending());
+            last_report = Instant::now();
+        }
+    }
+}
 
-    let zmq_socket = match cli.zmq_sock.to_ascii_lowercase().as_str() {
-        "pull" => zmq::SocketType::PULL,
-        "sub" => zmq::SocketType::SUB,
-        _ => panic!("Unknown ZeroMQ socket type string: {:?}", cli.zmq_sock),
+/// Interval of printing the pipeline statistics.
+const STATS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Interval of writing the status of the ZeroMQ source.
+const STATUS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Reports the connection events and the status of the ZeroMQ source.
+struct ZmqMonitor {
+    status: Option<StatusWriter>,
+    stream: String,
+    last_status: Instant,
+}
+
+impl ZmqMonitor {
+    fn check(&mut self, consumer: &ZmqConsumer) {
+        match consumer.events() {
+            Ok(events) => for event in events {
+                println!("ZeroMQ source event: {:?}", event);
+            },
+            Err(e) => println!("Error while monitoring the ZeroMQ socket: {:?}", e),
+        }
+        if let Some(writer) = &self.status {
+            if self.last_status.elapsed() > STATUS_INTERVAL {
+                if let Err(e) = writer.write(&self.stream, &consumer.status().fields()) {
+                    println!("Error while writing the source status to Redis: {:?}", e);
+                }
+                self.last_status = Instant::now();
+            }
+        }
+    }
+}
+
+/// Print the results of publishing to a Redis stream.
+fn print_published(entries: Vec<FcResult<String>>, verb: &str, stream: &str) {
+    for entry in entries {
+        match entry {
+            Ok(x) => println!("{} new data ({}) to Redis stream: {}", verb, x, stream),
+            Err(e) => println!("Error while publishing data to Redis stream: {:?}", e),
+        }
+    }
+}
+
+/// Consume from ZeroMQ and publish to Redis with a pool of worker threads.
+fn run_pipeline(cli: &RunArgs, consumer: &ZmqConsumer, monitor: &mut ZmqMonitor, producer: &mut RedisProducer,
+                schema: Option<&serde_json::Value>, transformer: Option<&Transformer>, stream: &str)
+        -> FcResult<()> {
+    let pipeline = Pipeline::new(cli.workers, cli.queue_size);
+    let mut last_report = Instant::now();
+    // deferred records are also published by the receiver when a receive times out
+    let producer = Mutex::new(producer);
+
+    pipeline.run(
+        || loop {
+            monitor.check(consumer);
+            match consumer.try_consume_raw()? {
+                Some(msg) => return Ok(Some(msg.to_vec())),
+                None => print_published(producer.lock().unwrap().flush(), "Published", stream),
+            }
+        },
+        || {
+            let decoder = create_decoder(&cli.decoder, schema);
+            let encoder = create_encoder(&cli.encoder, transformer.map(|t| t.schema()).or(schema));
+            move |bytes: &Vec<u8>| match transformer {
+                Some(t) => decoder.unpack(bytes)?.into_iter().map(|x| encoder.pack(&t.apply(x)?)).collect(),
+                None => transcode(decoder.as_ref(), encoder.as_ref(), bytes),
+            }
+        },
+        |_, result| {
+            let mut producer = producer.lock().unwrap();
+            match result {
+                Ok(encoded) => {
+                    let mut entries = producer.produce_encoded(&encoded, stream);
+                    entries.extend(producer.flush());
+                    print_published(entries, "Published", stream);
+                },
+                Err(e) => println!("Error while transcoding data: {:?}", e),
+            }
+            if last_report.elapsed() > STATS_INTERVAL {
+                println!("Pipeline statistics: {:?}", pipeline.stats());
+                if let Some(stats) = producer.rate_stats(stream) {
+                    println!("Rate limit statistics: {:?}", stats);
+                }
+                last_report = Instant::now();
+            }
+        },
+    )
+}
+
+/// Forward Avro messages from ZeroMQ to Redis without decoding them.
+fn run_passthrough(cli: &RunArgs, consumer: &ZmqConsumer, monitor: &mut ZmqMonitor, producer: &mut RedisProducer,
+                   schema: &serde_json::Value, stream: &str) -> FcResult<()> {
+    let mut passthrough = Passthrough::new(schema, cli.passthrough_sample);
+    let mut last_report = Instant::now();
+
+    loop {
+        monitor.check(consumer);
+        let msg = match consumer.try_consume_raw()? {
+            Some(msg) => msg,
+            None => {
+                // deferred records are published when a receive times out
+                print_published(producer.flush(), "Forwarded", stream);
+                continue;
+            },
+        };
+        match passthrough.check(&msg) {
+            Ok(_) => {
+                let mut entries = producer.produce_encoded(&[&msg[..]], stream);
+                entries.extend(producer.flush());
+                print_published(entries, "Forwarded", stream);
+            },
+            Err(e) => println!("Dropped invalid data: {:?}", e),
+        }
+        if last_report.elapsed() > STATS_INTERVAL {
+            println!("Passthrough statistics: {:?}", passthrough.stats());
+            if let Some(stats) = producer.rate_stats(stream) {
+                println!("Rate limit statistics: {:?}", stats);
+            }
+            last_report = Instant::now();
+        }
+    }
+}
+
+/// Interval of checking whether the script has changed.
+#[cfg(feature = "script")]
+const SCRIPT_RELOAD_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Run the script on a record, reloading it first if it has changed.
+#[cfg(feature = "script")]
+fn run_script(script: &mut ScriptStage, last_reload: &mut Instant, decoded: Decoded) -> Vec<Decoded> {
+    if last_reload.elapsed() > SCRIPT_RELOAD_INTERVAL {
+        match script.reload() {
+            Ok(true) => println!("Reloaded the script"),
+            Ok(false) => (),
+            Err(e) => println!("Error while reloading the script, keeping the previous one: {:?}", e),
+        }
+        *last_reload = Instant::now();
+    }
+    script.process(decoded).unwrap_or_else(|e| {
+        println!("Error while running the script: {:?}", e);
+        Vec::new()
+    })
+}
+
+/// Number of entries read from Redis in a single XRANGE.
+#[cfg(feature = "parquet")]
+const EXPORT_PAGE_SIZE: usize = 1000;
+
+/// Records of an export, which are read in pages.
+#[cfg(feature = "parquet")]
+enum ExportInput {
+    /// Entries of a stream in the range [start, end]
+    Stream { consumer: Box<RedisConsumer>, stream: String, start: String, end: String },
+    Files { decoder: Box<dyn Decoder + Send>, paths: Vec<String> },
+}
+
+#[cfg(feature = "parquet")]
+impl ExportInput {
+    /// Call `f` with each page of records and return the number of records.
+    ///
+    /// The end of the range of a stream is set to the last entry read, so that
+    /// reading again returns the same records.
+    fn for_each_page<F>(&mut self, mut f: F) -> FcResult<usize>
+            where F: FnMut(&[Decoded]) -> FcResult<()> {
+        let mut n = 0;
+        match self {
+            ExportInput::Stream { consumer, stream, start, end } => {
+                let mut first = start.clone();
+                let mut last = None;
+                loop {
+                    let page = consumer.range(stream, &first, end, EXPORT_PAGE_SIZE)?;
+                    if let Some((sid, _)) = page.last() {
+                        first = format!("({}", sid);
+                        last = Some(sid.clone());
+                    }
+                    let records: Vec<Decoded> = page.into_iter().map(|(_, x)| x).collect();
+                    f(&records)?;
+                    n += records.len();
+                    if records.len() < EXPORT_PAGE_SIZE {
+                        break;
+                    }
+                }
+                if let Some(last) = last {
+                    *end = last;
+                }
+            },
+            ExportInput::Files { decoder, paths } => for path in paths.iter() {
+                let records = decoder.unpack(&std::fs::read(path)?)?;
+                f(&records)?;
+                n += records.len();
+            },
+        }
+        Ok(n)
+    }
+}
+
+/// Export the records into a Parquet file, which are read twice: to collect
+/// the layout of the columns and to write them.
+#[cfg(feature = "parquet")]
+fn export_parquet(args: &ExportArgs, host: &str, port: i32) -> FcResult<bool> {
+    let (json_schema, stream) = match (&args.schema_file, &args.stream) {
+        (Some(path), stream) => {
+            let (json_schema, name) = read_schema(path)?;
+            (json_schema, stream.clone().unwrap_or(name))
+        },
+        (None, Some(stream)) => {
+            let mut registry = SchemaRegistry::new(host, port);
+            match registry.get_version(stream, "0")? {
+                Some(schema) => (Some(schema), stream.clone()),
+                None => {
+                    eprintln!("No schema registered for stream: {}", stream);
+                    return Ok(false);
+                },
+            }
+        },
+        (None, None) => {
+            eprintln!("Either --schema-file or --stream is required");
+            return Ok(false);
+        },
+    };
+    let json_schema = match json_schema {
+        Some(schema) => schema,
+        None => {
+            eprintln!("Cannot export stream without a schema: {}", stream);
+            return Ok(false);
+        },
+    };
+
+    let mut input = if args.input.is_empty() {
+        let mut consumer = RedisConsumer::new(host, port);
+        consumer.set_decoder(&args.decoder, Some(&json_schema));
+        ExportInput::Stream {
+            consumer: Box::new(consumer), stream: stream.clone(), start: args.start.clone(), end: args.end.clone(),
+        }
+    } else {
+        ExportInput::Files { decoder: create_decoder(&args.decoder, Some(&json_schema)), paths: args.input.clone() }
     };
 
+    let mut layout = ExportLayout::new(&parse_avro_schema(&json_schema)?)?;
+    let n = input.for_each_page(|records| layout.update(records))?;
+
+    let mut exporter = ParquetExporter::new(layout, std::path::Path::new(&args.output), args.row_group_size)?;
+    if n > 0 {
+        input.for_each_page(|records| exporter.write(records))?;
+    }
+    let n = exporter.close()?;
+    println!("Exported {} records of {} to {}", n, stream, args.output);
+    Ok(true)
+}
+
+/// Execute the export subcommand and return whether it succeeded.
+fn run_export(args: ExportArgs, _host: &str, _port: i32) -> FcResult<bool> {
+    match args.format.to_ascii_lowercase().as_str() {
+        #[cfg(feature = "parquet")]
+        "parquet" => export_parquet(&args, _host, _port),
+        _ => {
+            eprintln!("Unknown or disabled export format: {}", args.format);
+            Ok(false)
+        },
+    }
+}
+
+fn main() {
+    let Cli { command, run: cli, redis_host, redis_port } = Cli::parse();
+
+    if let Some(command) = command {
+        let ret = match command {
+            Command::Schema(command) => run_schema_command(command, &redis_host, redis_port),
+            Command::Export(args) => run_export(args, &redis_host, redis_port),
+            Command::BuildEvents(args) => run_event_builder(args, &redis_host, redis_port),
+        };
+        match ret {
+            Ok(true) => return,
+            Ok(false) => std::process::exit(1),
+            Err(e) => {
+                eprintln!("Error while executing command: {:?}", e);
+                std::process::exit(1);
+            },
+        }
+    }
+
     let (json_schema, stream) = load_schema(&cli.schema_file);
 
-    let mut consumer = ZmqConsumer::new(&cli.zmq_endpoint, zmq_socket);
+    let rate_limits: Vec<(String, RatePolicy)> = cli.rate_limit.iter().map(|spec| {
+        let (name, policy) = spec.split_once('=').unwrap_or((stream.as_str(), spec.as_str()));
+        (name.to_owned(), policy.parse().unwrap_or_else(|e| panic!("{}", e)))
+    }).collect();
+    // the records deferred by a latest policy or queued by the Kafka producer
+    // are published when a receive times out, and the events are checked as well
+    let mut receive_timeout = rate_limits.iter().filter_map(|(_, policy)| match policy {
+        RatePolicy::Latest(interval) => Some(i32::try_from(interval.as_millis()).unwrap_or(i32::MAX).max(1)),
+        _ => None,
+    }).min();
+    if cli.zmq_monitor || cli.zmq_watchdog.is_some() || cli.sink.eq_ignore_ascii_case("kafka") {
+        receive_timeout = Some(receive_timeout.map_or(1000, |x| x.min(1000)));
+    }
+
+    let mut consumer = match cli.source.to_ascii_lowercase().as_str() {
+        "zmq" => {
+            let zmq_socket = match cli.zmq_sock.to_ascii_lowercase().as_str() {
+                "pull" => zmq::SocketType::PULL,
+                "sub" => zmq::SocketType::SUB,
+                _ => panic!("Unknown ZeroMQ socket type string: {:?}", cli.zmq_sock),
+            };
+            let options = ZmqSocketOptions {
+                hwm: cli.zmq_hwm,
+                linger: cli.zmq_linger,
+                receive_timeout: cli.zmq_receive_timeout.or(receive_timeout),
+                buffer_size: cli.zmq_buffer_size,
+                reconnect_interval: cli.zmq_reconnect_interval,
+                tcp_keepalive: cli.zmq_tcp_keepalive.then_some(true),
+                conflate: cli.zmq_conflate,
+                monitor: cli.zmq_monitor,
+            };
+            let mut consumer = ZmqConsumer::with_options(&cli.zmq_endpoint, zmq_socket, &options);
+            if let Some(field) = &cli.zmq_sequence_field {
+                // the records are not decoded on the receiving thread otherwise
+                assert!(!cli.passthrough && cli.workers == 0,
+                        "Sequence tracking cannot be combined with passthrough or parallel workers");
+                consumer.set_sequence_field(field);
+            }
+            if let Some(timeout) = cli.zmq_watchdog {
+                consumer.set_watchdog(Duration::from_secs(timeout));
+            }
+            Source::Zmq(consumer)
+        },
+        "redis" => {
+            // the records would be published to the stream which is tailed
+            assert!(!cli.sink.eq_ignore_ascii_case("redis"),
+                    "The redis source cannot be combined with the redis sink");
+            let mut consumer = RedisConsumer::new(&redis_host, redis_port);
+            consumer.set_block(0);
+            // the decoder follows the schema versions registered by the producer
+            let mut registry = SchemaRegistry::new(&redis_host, redis_port);
+            if let Err(e) = registry.enable_invalidation() {
+                println!("Schema updates are only noticed after the cache expires: {:?}", e);
+            }
+            consumer.set_schema_registry(registry, &cli.decoder);
+            Source::Redis(consumer, None)
+        },
+        #[cfg(feature = "nats")]
+        "nats" => {
+            let mut consumer = NatsConsumer::new(&cli.nats_url);
+            if let Some(durable) = &cli.nats_durable {
+                consumer.set_durable(durable);
+            }
+            Source::Nats(consumer)
+        },
+        #[cfg(feature = "epics")]
+        "epics" => {
+            let pvs: Vec<(String, String)> = cli.epics_pv.iter().map(|x| match x.split_once('=') {
+                Some((field, pv)) => (field.to_owned(), pv.to_owned()),
+                None => panic!("EPICS PV must be given as FIELD=PV: {}", x),
+            }).collect();
+            Source::Epics(EpicsConsumer::new(
+                json_schema.as_ref().expect("EPICS source requires a schema"), &pvs, &cli.epics_protocol)
+                .expect("Failed to start the EPICS source"))
+        },
+        _ => panic!("Unknown or disabled source: {:?}", cli.source),
+    };
     consumer.set_decoder(&cli.decoder, json_schema.as_ref());
 
-    let mut producer = RedisProducer::new(&cli.redis_host, cli.redis_port);
-    producer.set_encoder(&cli.encoder, json_schema.as_ref());
+    let transformer = cli.transform_file.as_ref().map(|path| Transformer::from_file(
+        json_schema.as_ref().expect("Transform requires a schema"), path).expect("Invalid transform file"));
+    // schema of the published records
+    let output_schema = transformer.as_ref().map(|t| t.schema()).or(json_schema.as_ref());
+
+    let mut producer = match cli.sink.to_ascii_lowercase().as_str() {
+        "redis" => Sink::Redis(RedisProducer::new(&redis_host, redis_port)),
+        #[cfg(feature = "kafka")]
+        "kafka" => Sink::Kafka(KafkaProducer::new(&cli.kafka_brokers)),
+        #[cfg(feature = "nats")]
+        "nats" => {
+            let mut producer = NatsProducer::new(&cli.nats_url);
+            producer.set_jetstream(cli.nats_jetstream);
+            Sink::Nats(producer)
+        },
+        #[cfg(feature = "mqtt")]
+        "mqtt" => {
+            let mut producer = MqttProducer::new(
+                &cli.mqtt_host, cli.mqtt_port, &format!("foamcore-{}", std::process::id()));
+            producer.set_qos(rumqttc::qos(cli.mqtt_qos).expect("Invalid MQTT QoS level"));
+            producer.set_retain(!cli.mqtt_no_retain);
+            Sink::Mqtt(producer)
+        },
+        #[cfg(feature = "hdf5")]
+        "hdf5" => {
+            let mut writer = Hdf5Writer::new(
+                &cli.hdf5_dir, output_schema.expect("HDF5 sink requires a schema"));
+            if let Some(field) = &cli.hdf5_run_field {
+                writer.set_run_field(field);
+            }
+            Sink::Hdf5(writer)
+        },
+        _ => panic!("Unknown or disabled sink: {:?}", cli.sink),
+    };
+    producer.set_encoder(&cli.encoder, output_schema);
+    for (name, policy) in rate_limits {
+        producer.redis().expect("Rate limits require the redis sink").set_rate_policy(&name, policy);
+    }
+
+    // the schema of a tailed stream is registered by its producer
+    if !matches!(consumer, Source::Redis(..)) {
+        let mut schema_registry = SchemaRegistry::new(&redis_host, redis_port);
+        schema_registry.set(&stream, output_schema).unwrap();
+    }
 
-    let mut schema_registry = SchemaRegistry::new(&cli.redis_host, cli.redis_port);
-    schema_registry.set(&stream, json_schema.as_ref()).unwrap();
+    #[cfg(feature = "script")]
+    let mut script = cli.script.as_ref().map(|path| {
+        assert!(!cli.passthrough && cli.workers == 0,
+                "Scripting cannot be combined with passthrough or parallel workers");
+        ScriptStage::new(path, json_schema.as_ref().expect("Scripting requires a schema"))
+            .expect("Failed to load the script")
+    });
+    #[cfg(feature = "script")]
+    let mut last_reload = Instant::now();
 
+    let mut zmq_monitor = ZmqMonitor {
+        status: cli.zmq_monitor.then(|| StatusWriter::new(&redis_host, redis_port)),
+        stream: stream.clone(),
+        last_status: Instant::now(),
+    };
+
+    if cli.passthrough {
+        assert!(transformer.is_none(), "Passthrough cannot be combined with transforms");
+        assert!(cli.decoder.eq_ignore_ascii_case("avro") && cli.encoder.eq_ignore_ascii_case("avro"),
+                "Passthrough requires the avro decoder and encoder");
+        let schema = json_schema.as_ref().expect("Passthrough requires a schema");
+        let ret = match (&consumer, &mut producer) {
+            (Source::Zmq(c), Sink::Redis(p)) => run_passthrough(&cli, c, &mut zmq_monitor, p, schema, &stream),
+            _ => panic!("Passthrough requires the zmq source and the redis sink"),
+        };
+        if let Err(e) = ret {
+            panic!("Error while consuming data: {:?}", e);
+        }
+        return;
+    }
+
+    if cli.workers > 0 {
+        let ret = match (&consumer, &mut producer) {
+            (Source::Zmq(c), Sink::Redis(p)) => run_pipeline(
+                &cli, c, &mut zmq_monitor, p, json_schema.as_ref(), transformer.as_ref(), &stream),
+            _ => panic!("Parallel workers require the zmq source and the redis sink"),
+        };
+        if let Err(e) = ret {
+            panic!("Error while consuming data: {:?}", e);
+        }
+        return;
+    }
+
+    let mut last_report = Instant::now();
     loop {
-        let decoded = match consumer.consume() {
+        if let Source::Zmq(c) = &consumer {
+            zmq_monitor.check(c);
+        }
+        // None if no record arrived in time, which still flushes deferred records
+        let decoded = match consumer.consume(&stream) {
             Ok(x) => x,
             Err(e) => panic!("Error while consuming data: {:?}", e),
         };
+        let idle = decoded.is_none();
+        #[cfg(feature = "script")]
+        let records = match (decoded, script.as_mut()) {
+            (Some(decoded), Some(script)) => run_script(script, &mut last_reload, decoded),
+            (decoded, _) => decoded.into_iter().collect(),
+        };
+        #[cfg(not(feature = "script"))]
+        let records: Vec<Decoded> = decoded.into_iter().collect();
+
+        let records: Vec<Decoded> = match &transformer {
+            Some(t) => records.into_iter().filter_map(|x| t.apply(x).map_err(
+                |e| println!("Error while transforming data: {:?}", e)).ok()).collect(),
+            None => records,
+        };
 
-        let entries = producer.produce(&[decoded], &stream);
+        let mut entries = producer.produce(&records, &stream);
+        // deferred Redis records are cheap to check, while waiting for the
+        // Kafka delivery reports is left until no record arrives
+        if producer.redis().is_some() || idle {
+            entries.extend(producer.flush());
+        }
 
         for entry in entries {
             match entry {
-                Ok(x) => println!("Published new data ({}) to Redis stream: {}", x, stream),
-                Err(e) => println!("Error while publishing data to Redis: {:?}", e),
+                Ok(x) => println!("Published new data ({}) to {}: {}", x, producer.name(), stream),
+                Err(e) => println!("Error while publishing data to {}: {:?}", producer.name(), e),
             }
         };
 
+        if last_report.elapsed() > STATS_INTERVAL {
+            if let Some(stats) = producer.redis().and_then(|p| p.rate_stats(&stream).cloned()) {
+                println!("Rate limit statistics: {:?}", stats);
+            }
+            last_report = Instant::now();
+        }
     }
 }
@@ -0,0 +1,166 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::thread;
+use std::time::Duration;
+
+use rumqttc::{Client, ConnectionError, Event, MqttOptions, Packet, QoS};
+
+use crate::encoder::{create_encoder, Encoder};
+use crate::schema::Decoded;
+use crate::error::FcResult;
+
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// Return the MQTT topic of a stream ("namespace/name").
+pub fn mqtt_topic(stream: &str) -> String {
+    stream.replace(':', "/")
+}
+
+/// Publishes records to the topic "namespace/name" of a stream.
+///
+/// Records are published as retained messages by default so that a
+/// new subscriber immediately receives the last value of each stream.
+pub struct MqttProducer {
+    client: Client,
+    qos: QoS,
+    retain: bool,
+    sequence: u64,
+    encoder: Option<Box<dyn Encoder + Send>>,
+}
+
+impl MqttProducer {
+    pub fn new(host: &str, port: u16, client_id: &str) -> Self {
+        let mut options = MqttOptions::new(client_id, host, port);
+        options.set_keep_alive(Duration::from_secs(5));
+        let (client, mut connection) = Client::new(options, 100);
+
+        // The event loop must be driven for the requests to be sent. It
+        // reconnects with an increasing delay while the broker is unreachable.
+        thread::spawn(move || {
+            let mut connected = true;
+            let mut delay = MIN_RECONNECT_DELAY;
+            for notification in connection.iter() {
+                match notification {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        if !connected {
+                            println!("Reconnected to the MQTT broker");
+                        }
+                        connected = true;
+                        delay = MIN_RECONNECT_DELAY;
+                    },
+                    Ok(_) => (),
+                    Err(ConnectionError::RequestsDone) => break,
+                    Err(e) => {
+                        if connected {
+                            println!("Lost the connection to the MQTT broker: {:?}", e);
+                            connected = false;
+                        }
+                        thread::sleep(delay);
+                        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+                    },
+                }
+            }
+        });
+
+        MqttProducer {
+            client,
+            qos: QoS::AtMostOnce,
+            retain: true,
+            sequence: 0,
+            encoder: None,
+        }
+    }
+
+    pub fn set_encoder(&mut self, name: &str, schema: Option<&serde_json::Value>) {
+        self.encoder = Some(create_encoder(name, schema));
+    }
+
+    /// Sets the quality of service level.
+    pub fn set_qos(&mut self, qos: QoS) {
+        self.qos = qos;
+    }
+
+    /// Sets whether the published records are retained by the broker.
+    pub fn set_retain(&mut self, retain: bool) {
+        self.retain = retain;
+    }
+
+    /// Publish records of a given stream and return the local sequence number of each record.
+    pub fn produce(&mut self, records: &[Decoded], stream: &str) -> Vec<FcResult<String>> {
+        let topic = mqtt_topic(stream);
+        records.iter().map(|x| {
+            let encoded = self.encoder.as_ref().unwrap().pack(x)?;
+
+            self.client.publish(&topic, self.qos, self.retain, encoded)?;
+            self.sequence += 1;
+
+            Ok(self.sequence.to_string())
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apache_avro::types::Value;
+    use rumqttc::{Client, Event, MqttOptions, Packet, QoS};
+
+    use crate::decoder::create_decoder;
+    use crate::schema::Decoded;
+    use crate::mqtt_clients::{mqtt_topic, MqttProducer};
+
+    #[test]
+    fn test_mqtt_producer() {
+        let raw_schema = r#"
+            {
+                "namespace": "mqtt_clients_test",
+                "type": "record",
+                "name": "raw",
+                "fields": [
+                    {
+                      "name": "temperature",
+                      "type": "double"
+                    }
+                ]
+            }"#;
+        let json_schema: serde_json::Value = serde_json::from_str(raw_schema).unwrap();
+        let stream = format!("mqtt_clients_test:raw_{}", std::process::id());
+
+        let host = "127.0.0.1";
+        let port = 1883;
+
+        let mut producer = MqttProducer::new(host, port, "mqtt_clients_test_producer");
+        producer.set_encoder("avro", Some(&json_schema));
+        producer.set_qos(QoS::AtLeastOnce);
+
+        let item = Decoded::from([("temperature".to_string(), Value::Double(21.5))]);
+        producer.produce(std::slice::from_ref(&item), &stream).remove(0).unwrap();
+
+        // the retained message is delivered even if it was published before subscribing
+        let (client, mut connection) = Client::new(
+            MqttOptions::new("mqtt_clients_test_consumer", host, port), 10);
+        client.subscribe(mqtt_topic(&stream), QoS::AtLeastOnce).unwrap();
+
+        let decoder = create_decoder("avro", Some(&json_schema));
+        for notification in connection.iter().take(100) {
+            match notification {
+                Ok(Event::Incoming(Packet::Publish(p))) => {
+                    assert_eq!(p.topic, mqtt_topic(&stream));
+                    assert_eq!(decoder.unpack(&p.payload.to_vec()).unwrap(), vec![item]);
+                    return;
+                },
+                Ok(_) => (),
+                Err(e) => {
+                    println!("Test skipped: no MQTT broker: {:?}", e);
+                    return;
+                },
+            }
+        }
+        panic!("No message received");
+    }
+}
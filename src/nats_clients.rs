@@ -0,0 +1,255 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::collections::{HashMap, HashSet};
+use std::time::Duration;
+
+use nats::jetstream::{JetStream, PushSubscription, StreamConfig, SubscribeOptions};
+
+use crate::decoder::{create_decoder, Decoder};
+use crate::encoder::{create_encoder, Encoder};
+use crate::schema::{Decoded, topic_name};
+use crate::error::{FcError, FcResult};
+
+/// Return the JetStream stream name of a stream ("namespace_name").
+fn jetstream_name(stream: &str) -> String {
+    stream.replace([':', '.'], "_")
+}
+
+/// Lazily established connection to a NATS server.
+struct NatsConnection {
+    url: String,
+    connection: Option<nats::Connection>,
+}
+
+impl NatsConnection {
+    fn new(url: &str) -> Self {
+        NatsConnection {
+            url: url.to_owned(),
+            connection: None,
+        }
+    }
+
+    fn get(&mut self) -> FcResult<&nats::Connection> {
+        if self.connection.is_none() {
+            self.connection = Some(nats::connect(&self.url)?);
+        }
+        Ok(self.connection.as_ref().unwrap())
+    }
+
+    fn jetstream(&mut self) -> FcResult<JetStream> {
+        Ok(nats::jetstream::new(self.get()?.clone()))
+    }
+}
+
+/// Publishes records to the subject "namespace.name" of a stream.
+pub struct NatsProducer {
+    connection: NatsConnection,
+    jetstream: bool,
+    // JetStream streams which are known to exist
+    streams: HashSet<String>,
+    sequence: u64,
+    encoder: Option<Box<dyn Encoder + Send>>,
+}
+
+impl NatsProducer {
+    pub fn new(url: &str) -> Self {
+        NatsProducer {
+            connection: NatsConnection::new(url),
+            jetstream: false,
+            streams: HashSet::new(),
+            sequence: 0,
+            encoder: None,
+        }
+    }
+
+    pub fn set_encoder(&mut self, name: &str, schema: Option<&serde_json::Value>) {
+        self.encoder = Some(create_encoder(name, schema));
+    }
+
+    /// Persist the published records in JetStream streams so that they can be replayed.
+    ///
+    /// A JetStream stream named "namespace_name" is created for each stream if it
+    /// does not exist.
+    pub fn set_jetstream(&mut self, enabled: bool) {
+        self.jetstream = enabled;
+    }
+
+    fn ensure_jetstream(&mut self, stream: &str) -> FcResult<JetStream> {
+        let js = self.connection.jetstream()?;
+        let name = jetstream_name(stream);
+        if !self.streams.contains(&name) {
+            if js.stream_info(&name).is_err() {
+                js.add_stream(StreamConfig {
+                    name: name.clone(),
+                    subjects: vec![topic_name(stream)],
+                    ..Default::default()
+                })?;
+            }
+            self.streams.insert(name);
+        }
+        Ok(js)
+    }
+
+    /// Publish records of a given stream and return the sequence number of each record.
+    ///
+    /// The sequence number is assigned by JetStream if enabled or counted locally otherwise.
+    pub fn produce(&mut self, records: &[Decoded], stream: &str) -> Vec<FcResult<String>> {
+        let subject = topic_name(stream);
+        records.iter().map(|x| {
+            let encoded = self.encoder.as_ref().unwrap().pack(x)?;
+
+            if self.jetstream {
+                let ack = self.ensure_jetstream(stream)?.publish(&subject, encoded)?;
+                Ok(ack.sequence.to_string())
+            } else {
+                self.connection.get()?.publish(&subject, encoded)?;
+                self.sequence += 1;
+                Ok(self.sequence.to_string())
+            }
+        }).collect()
+    }
+}
+
+enum NatsSubscription {
+    Core(nats::Subscription),
+    JetStream(PushSubscription),
+}
+
+/// Consumes records from the subject "namespace.name" of a stream.
+pub struct NatsConsumer {
+    connection: NatsConnection,
+    durable: Option<String>,
+    subscriptions: HashMap<String, NatsSubscription>,
+    timeout: Duration,
+    decoder: Option<Box<dyn Decoder + Send>>,
+}
+
+impl NatsConsumer {
+    pub fn new(url: &str) -> Self {
+        NatsConsumer {
+            connection: NatsConnection::new(url),
+            durable: None,
+            subscriptions: HashMap::new(),
+            timeout: Duration::from_millis(100),
+            decoder: None,
+        }
+    }
+
+    pub fn set_decoder(&mut self, name: &str, schema: Option<&serde_json::Value>) {
+        self.decoder = Some(create_decoder(name, schema));
+    }
+
+    /// Consume through a JetStream durable consumer with the given name.
+    ///
+    /// A new durable consumer replays all the records kept in the JetStream
+    /// stream. An existing one resumes from its last acknowledged record.
+    pub fn set_durable(&mut self, name: &str) {
+        self.durable = Some(name.to_owned());
+        self.subscriptions.clear();
+    }
+
+    /// Sets the maximum time to wait for a record in `consume`.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    fn subscribe(&mut self, stream: &str) -> FcResult<&NatsSubscription> {
+        if !self.subscriptions.contains_key(stream) {
+            let subject = topic_name(stream);
+            let subscription = match &self.durable {
+                Some(durable) => {
+                    let options = SubscribeOptions::new()
+                        .durable_name(durable.clone())
+                        .deliver_all();
+                    NatsSubscription::JetStream(
+                        self.connection.jetstream()?.subscribe_with_options(&subject, &options)?)
+                },
+                None => NatsSubscription::Core(self.connection.get()?.subscribe(&subject)?),
+            };
+            self.subscriptions.insert(stream.to_owned(), subscription);
+        }
+        Ok(&self.subscriptions[stream])
+    }
+
+    /// Consumes a single record of a given stream.
+    pub fn consume(&mut self, stream: &str) -> FcResult<Decoded> {
+        let timeout = self.timeout;
+        let bytes = match self.subscribe(stream)? {
+            NatsSubscription::Core(sub) => sub.next_timeout(timeout)?.data,
+            NatsSubscription::JetStream(sub) => {
+                let msg = sub.next_timeout(timeout)?;
+                msg.ack()?;
+                msg.data
+            },
+        };
+
+        let decoded = self.decoder.as_ref().unwrap().unpack(&bytes)?;
+        decoded.into_iter().next().ok_or_else(
+            || FcError::SchemaError(format!("Empty message received from stream: {}", stream)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use apache_avro::types::Value;
+
+    use crate::error::FcError;
+    use crate::schema::Decoded;
+    use crate::nats_clients::{NatsConsumer, NatsProducer};
+
+    #[test]
+    fn test_nats_consumer_and_producer() {
+        let raw_schema = r#"
+            {
+                "namespace": "nats_clients_test",
+                "type": "record",
+                "name": "raw",
+                "fields": [
+                    {
+                      "name": "index",
+                      "type": "int"
+                    }
+                ]
+            }"#;
+        let json_schema: serde_json::Value = serde_json::from_str(raw_schema).unwrap();
+        let stream = format!("nats_clients_test:raw_{}", std::process::id());
+
+        let url = "nats://127.0.0.1:4222";
+
+        let mut producer = NatsProducer::new(url);
+        producer.set_encoder("avro", Some(&json_schema));
+        producer.set_jetstream(true);
+
+        let mut consumer = NatsConsumer::new(url);
+        consumer.set_decoder("avro", Some(&json_schema));
+        consumer.set_durable("nats_clients_test");
+        consumer.set_timeout(Duration::from_secs(5));
+
+        const NUM_RECORDS: i32 = 3;
+        let items: Vec<Decoded> = (0..NUM_RECORDS).map(|i| {
+            Decoded::from([("index".to_string(), Value::Int(i))])
+        }).collect();
+
+        match producer.produce(&items, &stream).remove(0) {
+            Ok(_) => (),
+            Err(FcError::IoError(e)) => {
+                println!("Test skipped: no NATS server with JetStream: {:?}", e);
+                return;
+            },
+            Err(e) => panic!("{:?}", e),
+        }
+
+        // durable consumers replay the records published before subscribing
+        for i in 0..NUM_RECORDS {
+            let ret = consumer.consume(&stream).unwrap();
+            assert_eq!(ret, Decoded::from([("index".to_string(), Value::Int(i))]));
+        }
+    }
+}
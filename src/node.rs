@@ -0,0 +1,439 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use apache_avro::Schema;
+use apache_avro::schema::RecordSchema;
+use apache_avro::types::Value;
+
+use crate::json::{is_ndarray, Names};
+use crate::schema::Decoded;
+use crate::error::{FcError, FcResult};
+
+/// Self-describing value shared by the MessagePack and CBOR codecs.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Node {
+    Null,
+    Bool(bool),
+    Int(i128),
+    Float(f64),
+    Bytes(Vec<u8>),
+    String(String),
+    Array(Vec<Node>),
+    Map(Vec<(Node, Node)>),
+    /// MessagePack extension type and its payload
+    Ext(i8, Vec<u8>),
+}
+
+impl Node {
+    fn as_key(&self) -> Option<&str> {
+        match self {
+            Node::String(s) => Some(s),
+            Node::Bytes(b) => std::str::from_utf8(b).ok(),
+            _ => None,
+        }
+    }
+
+    fn get(&self, key: &str) -> Option<&Node> {
+        match self {
+            Node::Map(entries) => entries.iter().find(|(k, _)| k.as_key() == Some(key)).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    fn as_i64(&self) -> Option<i64> {
+        match self {
+            Node::Int(i) => i64::try_from(*i).ok(),
+            _ => None,
+        }
+    }
+
+    fn as_i32(&self) -> Option<i32> {
+        match self {
+            Node::Int(i) => i32::try_from(*i).ok(),
+            _ => None,
+        }
+    }
+
+    fn as_f64(&self) -> Option<f64> {
+        match self {
+            Node::Float(x) => Some(*x),
+            Node::Int(i) => Some(*i as f64),
+            _ => None,
+        }
+    }
+}
+
+fn type_error(expected: &str, node: &Node) -> FcError {
+    FcError::SchemaError(format!("Expected {}, got: {:?}", expected, node))
+}
+
+/// Convert an array encoded by msgpack-numpy, i.e. a map with the keys
+/// "nd", "type", "shape" and "data", into an ndarray record.
+fn ndarray_from_numpy(node: &Node) -> FcResult<Value> {
+    let dtype = node.get("type").and_then(|t| t.as_key()).ok_or_else(
+        || type_error("msgpack-numpy 'type'", node))?;
+    let shape = match node.get("shape") {
+        Some(Node::Array(items)) => items.iter().map(|x| {
+            x.as_i32().map(Value::Int).ok_or_else(|| type_error("int", x))
+        }).collect::<FcResult<Vec<_>>>()?,
+        _ => return Err(type_error("msgpack-numpy 'shape'", node)),
+    };
+    let data = match node.get("data") {
+        Some(Node::Bytes(b)) => b.clone(),
+        _ => return Err(type_error("msgpack-numpy 'data'", node)),
+    };
+
+    Ok(Value::Record(vec![
+        ("shape".to_string(), Value::Array(shape)),
+        ("dtype".to_string(), Value::String(dtype.to_owned())),
+        ("data".to_string(), Value::Bytes(data)),
+    ]))
+}
+
+fn node_to_avro(node: &Node, schema: &Schema, names: &Names) -> FcResult<Value> {
+    let schema = names.resolve(schema)?;
+    Ok(match (schema, node) {
+        (Schema::Null, Node::Null) => Value::Null,
+        (Schema::Boolean, Node::Bool(b)) => Value::Boolean(*b),
+        (Schema::Int, _) => Value::Int(node.as_i32().ok_or_else(|| type_error("int", node))?),
+        (Schema::Long, _) => Value::Long(node.as_i64().ok_or_else(|| type_error("long", node))?),
+        (Schema::Float, _) => Value::Float(node.as_f64().ok_or_else(|| type_error("float", node))? as f32),
+        (Schema::Double, _) => Value::Double(node.as_f64().ok_or_else(|| type_error("double", node))?),
+        (Schema::Bytes, Node::Bytes(b)) | (Schema::Bytes, Node::Ext(_, b)) => Value::Bytes(b.clone()),
+        (Schema::String, Node::String(_)) | (Schema::String, Node::Bytes(_)) => Value::String(
+            node.as_key().ok_or_else(|| type_error("utf-8 string", node))?.to_owned()),
+        (Schema::Date, _) => Value::Date(node.as_i32().ok_or_else(|| type_error("date", node))?),
+        (Schema::TimeMillis, _) => Value::TimeMillis(
+            node.as_i32().ok_or_else(|| type_error("time-millis", node))?),
+        (Schema::TimeMicros, _) => Value::TimeMicros(
+            node.as_i64().ok_or_else(|| type_error("time-micros", node))?),
+        (Schema::TimestampMillis, _) => Value::TimestampMillis(
+            node.as_i64().ok_or_else(|| type_error("timestamp-millis", node))?),
+        (Schema::TimestampMicros, _) => Value::TimestampMicros(
+            node.as_i64().ok_or_else(|| type_error("timestamp-micros", node))?),
+        (Schema::Array(items), Node::Array(a)) => Value::Array(
+            a.iter().map(|x| node_to_avro(x, items, names)).collect::<FcResult<_>>()?),
+        (Schema::Map(values), Node::Map(entries)) => Value::Map(entries.iter().map(|(k, v)| {
+            let key = k.as_key().ok_or_else(|| type_error("string key", k))?;
+            Ok((key.to_owned(), node_to_avro(v, values, names)?))
+        }).collect::<FcResult<_>>()?),
+        (Schema::Union(us), _) => {
+            let mut ret = None;
+            for (i, variant) in us.variants().iter().enumerate() {
+                if let Ok(v) = node_to_avro(node, variant, names) {
+                    ret = Some(Value::Union(i as u32, Box::new(v)));
+                    break;
+                }
+            }
+            ret.ok_or_else(|| type_error("one of the union types", node))?
+        },
+        (Schema::Enum(es), _) => {
+            let symbol = node.as_key().ok_or_else(|| type_error("enum symbol", node))?;
+            let index = es.symbols.iter().position(|x| x == symbol).ok_or_else(
+                || type_error("enum symbol", node))?;
+            Value::Enum(index as u32, symbol.to_owned())
+        },
+        (Schema::Fixed(fs), Node::Bytes(b)) | (Schema::Fixed(fs), Node::Ext(_, b)) if b.len() == fs.size =>
+            Value::Fixed(fs.size, b.clone()),
+        (Schema::Record(rs), Node::Map(_)) => {
+            if is_ndarray(rs) && node.get("nd") == Some(&Node::Bool(true)) {
+                return ndarray_from_numpy(node);
+            }
+            Value::Record(node_to_avro_fields(node, rs, names)?)
+        },
+        // an ndarray wrapped in an extension type holds an encoded msgpack-numpy map
+        (Schema::Record(rs), Node::Ext(_, payload)) if is_ndarray(rs) =>
+            ndarray_from_numpy(&msgpack::from_slice(payload)?)?,
+        _ => return Err(type_error(&format!("{:?}", schema), node)),
+    })
+}
+
+fn node_to_avro_fields(node: &Node, rs: &RecordSchema, names: &Names) -> FcResult<Vec<(String, Value)>> {
+    rs.fields.iter().map(|field| {
+        let value = match node.get(&field.name) {
+            Some(v) => node_to_avro(v, &field.schema, names)?,
+            None => node_to_avro(&Node::Null, &field.schema, names)
+                .map_err(|_| FcError::SchemaError(format!("Missing field: {}", field.name)))?,
+        };
+        Ok((field.name.clone(), value))
+    }).collect()
+}
+
+/// Convert a node, which is either a record or an array of records, into decoded records.
+pub(crate) fn node_to_decoded(node: &Node, schema: &Schema) -> FcResult<Vec<Decoded>> {
+    let names = Names::new(schema);
+    let rs = match names.resolve(schema)? {
+        Schema::Record(rs) => rs,
+        _ => return Err(FcError::SchemaError("Expected a record schema".to_string())),
+    };
+
+    let records = match node {
+        Node::Array(items) => items.iter().collect(),
+        _ => vec![node],
+    };
+    records.into_iter().map(|x| match x {
+        Node::Map(_) => Ok(node_to_avro_fields(x, rs, &names)?.into_iter().collect()),
+        _ => Err(type_error("map", x)),
+    }).collect()
+}
+
+/// Whether a record has the fields of an ndarray record.
+fn is_ndarray_value(fields: &[(String, Value)]) -> bool {
+    fields.len() == 3 && ["shape", "dtype", "data"].iter().all(|n| fields.iter().any(|(k, _)| k == n))
+}
+
+/// Convert an Avro value into a node.
+///
+/// If `numpy` is true, ndarray records are converted into the layout of
+/// msgpack-numpy so that Python consumers get numpy arrays back.
+pub(crate) fn avro_to_node(value: &Value, numpy: bool) -> FcResult<Node> {
+    Ok(match value {
+        Value::Null => Node::Null,
+        Value::Boolean(b) => Node::Bool(*b),
+        Value::Int(x) | Value::Date(x) | Value::TimeMillis(x) => Node::Int(*x as i128),
+        Value::Long(x) | Value::TimeMicros(x)
+            | Value::TimestampMillis(x) | Value::TimestampMicros(x) => Node::Int(*x as i128),
+        Value::Float(x) => Node::Float(*x as f64),
+        Value::Double(x) => Node::Float(*x),
+        Value::Bytes(b) | Value::Fixed(_, b) => Node::Bytes(b.clone()),
+        Value::String(s) | Value::Enum(_, s) => Node::String(s.clone()),
+        Value::Union(_, v) => avro_to_node(v, numpy)?,
+        Value::Array(items) => Node::Array(
+            items.iter().map(|x| avro_to_node(x, numpy)).collect::<FcResult<_>>()?),
+        Value::Map(m) => Node::Map(m.iter().map(|(k, v)| {
+            Ok((Node::String(k.clone()), avro_to_node(v, numpy)?))
+        }).collect::<FcResult<_>>()?),
+        Value::Record(fields) if numpy && is_ndarray_value(fields) => {
+            let field = |name: &str| fields.iter().find(|(k, _)| k == name).map(|(_, v)| v).unwrap();
+            Node::Map(vec![
+                (Node::Bytes(b"nd".to_vec()), Node::Bool(true)),
+                (Node::Bytes(b"type".to_vec()), avro_to_node(field("dtype"), numpy)?),
+                (Node::Bytes(b"kind".to_vec()), Node::Bytes(Vec::new())),
+                (Node::Bytes(b"shape".to_vec()), avro_to_node(field("shape"), numpy)?),
+                (Node::Bytes(b"data".to_vec()), avro_to_node(field("data"), numpy)?),
+            ])
+        },
+        Value::Record(fields) => Node::Map(fields.iter().map(|(k, v)| {
+            Ok((Node::String(k.clone()), avro_to_node(v, numpy)?))
+        }).collect::<FcResult<_>>()?),
+        _ => return Err(FcError::SchemaError(format!("Unsupported Avro value: {:?}", value))),
+    })
+}
+
+/// Convert a decoded record into a node.
+pub(crate) fn decoded_to_node(datum: &Decoded, numpy: bool) -> FcResult<Node> {
+    let mut keys: Vec<&String> = datum.keys().collect();
+    keys.sort();
+    Ok(Node::Map(keys.into_iter().map(|k| {
+        Ok((Node::String(k.clone()), avro_to_node(&datum[k], numpy)?))
+    }).collect::<FcResult<_>>()?))
+}
+
+/// Conversion between msgpack values and nodes.
+pub(crate) mod msgpack {
+    use crate::node::Node;
+    use crate::error::{FcError, FcResult};
+
+    pub(crate) fn from_value(value: rmpv::Value) -> FcResult<Node> {
+        Ok(match value {
+            rmpv::Value::Nil => Node::Null,
+            rmpv::Value::Boolean(b) => Node::Bool(b),
+            rmpv::Value::Integer(i) => Node::Int(match i.as_i64() {
+                Some(x) => x as i128,
+                None => i.as_u64().unwrap() as i128,
+            }),
+            rmpv::Value::F32(x) => Node::Float(x as f64),
+            rmpv::Value::F64(x) => Node::Float(x),
+            rmpv::Value::String(s) => match s.into_str() {
+                Some(s) => Node::String(s),
+                None => return Err(FcError::CodecError("Invalid UTF-8 string in MessagePack data".to_string())),
+            },
+            rmpv::Value::Binary(b) => Node::Bytes(b),
+            rmpv::Value::Ext(code, b) => Node::Ext(code, b),
+            rmpv::Value::Array(items) => Node::Array(
+                items.into_iter().map(from_value).collect::<FcResult<_>>()?),
+            rmpv::Value::Map(entries) => Node::Map(entries.into_iter().map(|(k, v)| {
+                Ok((from_value(k)?, from_value(v)?))
+            }).collect::<FcResult<_>>()?),
+        })
+    }
+
+    /// Decode a single MessagePack value, e.g. the payload of an extension type.
+    pub(crate) fn from_slice(mut bytes: &[u8]) -> FcResult<Node> {
+        let value = rmpv::decode::read_value(&mut bytes).map_err(
+            |e| FcError::CodecError(format!("Invalid MessagePack data: {}", e)))?;
+        from_value(value)
+    }
+
+    pub(crate) fn to_value(node: Node) -> FcResult<rmpv::Value> {
+        Ok(match node {
+            Node::Null => rmpv::Value::Nil,
+            Node::Bool(b) => rmpv::Value::Boolean(b),
+            Node::Int(i) => match i64::try_from(i) {
+                Ok(x) => rmpv::Value::from(x),
+                Err(_) => rmpv::Value::from(u64::try_from(i).map_err(
+                    |_| FcError::CodecError(format!("Integer out of range: {}", i)))?),
+            },
+            Node::Float(x) => rmpv::Value::F64(x),
+            Node::Bytes(b) => rmpv::Value::Binary(b),
+            Node::Ext(code, b) => rmpv::Value::Ext(code, b),
+            Node::String(s) => rmpv::Value::String(s.into()),
+            Node::Array(items) => rmpv::Value::Array(
+                items.into_iter().map(to_value).collect::<FcResult<_>>()?),
+            Node::Map(entries) => rmpv::Value::Map(entries.into_iter().map(|(k, v)| {
+                Ok((to_value(k)?, to_value(v)?))
+            }).collect::<FcResult<_>>()?),
+        })
+    }
+}
+
+/// Conversion between CBOR values and nodes.
+pub(crate) mod cbor {
+    use ciborium::value::Value;
+
+    use crate::node::Node;
+    use crate::error::{FcError, FcResult};
+
+    pub(crate) fn from_value(value: Value) -> FcResult<Node> {
+        Ok(match value {
+            Value::Null => Node::Null,
+            Value::Bool(b) => Node::Bool(b),
+            Value::Integer(i) => Node::Int(i128::from(i)),
+            Value::Float(x) => Node::Float(x),
+            Value::Text(s) => Node::String(s),
+            Value::Bytes(b) => Node::Bytes(b),
+            // semantic tags are ignored
+            Value::Tag(_, v) => from_value(*v)?,
+            Value::Array(items) => Node::Array(items.into_iter().map(from_value).collect::<FcResult<_>>()?),
+            Value::Map(entries) => Node::Map(entries.into_iter().map(|(k, v)| {
+                Ok((from_value(k)?, from_value(v)?))
+            }).collect::<FcResult<_>>()?),
+            _ => return Err(FcError::CodecError(format!("Unsupported CBOR item: {:?}", value))),
+        })
+    }
+
+    pub(crate) fn to_value(node: Node) -> FcResult<Value> {
+        Ok(match node {
+            Node::Null => Value::Null,
+            Node::Bool(b) => Value::Bool(b),
+            Node::Int(i) => Value::Integer(i.try_into().map_err(
+                |_| FcError::CodecError(format!("Integer out of range: {}", i)))?),
+            Node::Float(x) => Value::Float(x),
+            Node::Bytes(b) => Value::Bytes(b),
+            Node::Ext(code, _) => return Err(FcError::CodecError(
+                format!("MessagePack extension type {} cannot be written as CBOR", code))),
+            Node::String(s) => Value::Text(s),
+            Node::Array(items) => Value::Array(items.into_iter().map(to_value).collect::<FcResult<_>>()?),
+            Node::Map(entries) => Value::Map(entries.into_iter().map(|(k, v)| {
+                Ok((to_value(k)?, to_value(v)?))
+            }).collect::<FcResult<_>>()?),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apache_avro::types::Value;
+
+    use crate::node::{msgpack, node_to_decoded, decoded_to_node, Node};
+    use crate::schema::{Decoded, json_to_avro_schema};
+
+    #[test]
+    fn test_msgpack_numpy_ndarray() {
+        let schema = json_to_avro_schema(&serde_json::json!({
+            "namespace": "testcase",
+            "type": "record",
+            "name": "raw",
+            "fields": [
+                {"name": "index", "type": "long"},
+                {"name": "image", "type": {
+                    "type": "record",
+                    "name": "NDArray",
+                    "fields": [
+                        {"name": "shape", "type": {"items": "int", "type": "array"}},
+                        {"name": "dtype", "type": "string"},
+                        {"name": "data", "type": "bytes"}
+                    ]
+                }}
+            ]
+        }));
+
+        let numpy = Node::Map(vec![
+            (Node::Bytes(b"nd".to_vec()), Node::Bool(true)),
+            (Node::Bytes(b"type".to_vec()), Node::String("<u2".to_string())),
+            (Node::Bytes(b"kind".to_vec()), Node::Bytes(Vec::new())),
+            (Node::Bytes(b"shape".to_vec()), Node::Array(vec![Node::Int(2)])),
+            (Node::Bytes(b"data".to_vec()), Node::Bytes(vec![1, 0, 2, 0])),
+        ]);
+        let node = Node::Map(vec![
+            (Node::String("index".to_string()), Node::Int(7)),
+            (Node::String("image".to_string()), numpy.clone()),
+        ]);
+
+        let decoded = node_to_decoded(&node, &schema).unwrap();
+        assert_eq!(decoded, vec![Decoded::from([
+            ("index".to_string(), Value::Long(7)),
+            ("image".to_string(), Value::Record(vec![
+                ("shape".to_string(), Value::Array(vec![Value::Int(2)])),
+                ("dtype".to_string(), Value::String("<u2".to_string())),
+                ("data".to_string(), Value::Bytes(vec![1, 0, 2, 0])),
+            ])),
+        ])]);
+
+        assert_eq!(decoded_to_node(&decoded[0], true).unwrap(), Node::Map(vec![
+            (Node::String("image".to_string()), numpy.clone()),
+            (Node::String("index".to_string()), Node::Int(7)),
+        ]));
+
+        // msgpack-numpy arrays can also be wrapped in an extension type
+        let mut payload = Vec::new();
+        rmpv::encode::write_value(&mut payload, &msgpack::to_value(numpy).unwrap()).unwrap();
+        let node = msgpack::from_value(rmpv::Value::Map(vec![
+            (rmpv::Value::from("index"), rmpv::Value::from(7)),
+            (rmpv::Value::from("image"), rmpv::Value::Ext(1, payload)),
+        ])).unwrap();
+        assert_eq!(node_to_decoded(&node, &schema).unwrap(), decoded);
+    }
+
+    #[test]
+    fn test_nd_key_is_not_numpy() {
+        let schema = json_to_avro_schema(&serde_json::json!({
+            "type": "record",
+            "name": "raw",
+            "fields": [{"name": "image", "type": {
+                "type": "record",
+                "name": "NDArray",
+                "fields": [
+                    {"name": "shape", "type": {"items": "int", "type": "array"}},
+                    {"name": "dtype", "type": "string"},
+                    {"name": "data", "type": "bytes"}
+                ]
+            }}]
+        }));
+        // a plain ndarray record which happens to have an "nd" key
+        let node = Node::Map(vec![(Node::String("image".to_string()), Node::Map(vec![
+            (Node::String("nd".to_string()), Node::Bool(false)),
+            (Node::String("shape".to_string()), Node::Array(vec![Node::Int(1)])),
+            (Node::String("dtype".to_string()), Node::String("|u1".to_string())),
+            (Node::String("data".to_string()), Node::Bytes(vec![7])),
+        ]))]);
+        assert_eq!(node_to_decoded(&node, &schema).unwrap(), vec![Decoded::from([
+            ("image".to_string(), Value::Record(vec![
+                ("shape".to_string(), Value::Array(vec![Value::Int(1)])),
+                ("dtype".to_string(), Value::String("|u1".to_string())),
+                ("data".to_string(), Value::Bytes(vec![7])),
+            ])),
+        ])]);
+    }
+
+    #[test]
+    fn test_invalid_items() {
+        // a str8 of two bytes which are not valid UTF-8
+        assert!(msgpack::from_slice(&[0xd9, 0x02, 0xff, 0xfe]).is_err());
+        assert!(msgpack::from_slice(&[0xd9]).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use crate::decoder::{AvroDecoder, Decoder};
+use crate::error::{FcError, FcResult};
+
+/// Counters of a passthrough validator.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PassthroughStats {
+    pub accepted: u64,
+    pub rejected: u64,
+    /// Messages which were fully decoded
+    pub sampled: u64,
+}
+
+/// Validates Avro messages which are forwarded without being decoded.
+///
+/// The writer schema in the header of every message is compared with the
+/// expected schema, which is cheap once a writer schema has been seen, and
+/// every message must contain exactly one record since consumers expect one
+/// record per entry. A fraction of the messages is fully decoded to catch
+/// corrupted data.
+pub struct Passthrough {
+    decoder: AvroDecoder,
+    sample: f64,
+    stats: PassthroughStats,
+}
+
+impl Passthrough {
+    /// Sample is the fraction of messages to fully decode, between 0 and 1.
+    pub fn new(schema: &serde_json::Value, sample: f64) -> Self {
+        assert!((0.0..=1.0).contains(&sample), "Sample fraction must be between 0 and 1: {}", sample);
+
+        Passthrough {
+            decoder: AvroDecoder::new(schema),
+            sample,
+            stats: PassthroughStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &PassthroughStats {
+        &self.stats
+    }
+
+    /// Whether the next message is fully decoded. Sampling is evenly spaced.
+    fn is_sampled(&self) -> bool {
+        let n = (self.stats.accepted + self.stats.rejected) as f64;
+        ((n + 1.0) * self.sample).floor() > (n * self.sample).floor()
+    }
+
+    fn validate(&self, bytes: &[u8], sampled: bool) -> FcResult<()> {
+        let counts = self.decoder.block_counts(bytes)?;
+        if counts != [1] {
+            return Err(FcError::CodecError(format!(
+                "Avro container must have a single record, found blocks of {:?}", counts)));
+        }
+        if sampled {
+            self.decoder.unpack(&bytes.to_vec())?;
+        }
+        Ok(())
+    }
+
+    /// Check a message before it is forwarded.
+    pub fn check(&mut self, bytes: &[u8]) -> FcResult<()> {
+        let sampled = self.is_sampled();
+        if sampled {
+            self.stats.sampled += 1;
+        }
+        let ret = self.validate(bytes, sampled);
+        match ret {
+            Ok(_) => self.stats.accepted += 1,
+            Err(_) => self.stats.rejected += 1,
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apache_avro::types::Value;
+
+    use crate::encoder::{AvroEncoder, Encoder};
+    use crate::passthrough::Passthrough;
+    use crate::schema::Decoded;
+
+    #[test]
+    fn test_passthrough() {
+        let schema = serde_json::json!({
+            "type": "record",
+            "name": "passthrough_test",
+            "fields": [{"name": "index", "type": "long"}]
+        });
+        let other = serde_json::json!({
+            "type": "record",
+            "name": "passthrough_test",
+            "fields": [{"name": "index", "type": "int"}]
+        });
+        let datum = Decoded::from([("index".to_string(), Value::Long(1))]);
+        let bytes = AvroEncoder::new(&schema).pack(&datum).unwrap();
+
+        let mut passthrough = Passthrough::new(&schema, 0.5);
+        for _ in 0..4 {
+            passthrough.check(&bytes).unwrap();
+        }
+        assert_eq!(passthrough.stats().sampled, 2);
+
+        // corrupted data passes the header check but not the full decoding
+        let mut corrupted = bytes.clone();
+        let n = corrupted.len();
+        corrupted[n - 17] = 0x80;
+        assert!(passthrough.check(&corrupted).is_ok());
+        assert!(passthrough.check(&corrupted).is_err());
+        assert!(passthrough.check(&bytes[..n - 8]).is_err());
+
+        // consumers expect a single record per message
+        let encoder = AvroEncoder::new(&schema);
+        assert!(passthrough.check(&encoder.pack_batch(&[]).unwrap()).is_err());
+        assert!(passthrough.check(&encoder.pack_batch(&[datum.clone(), datum.clone()]).unwrap()).is_err());
+
+        let other_bytes = AvroEncoder::new(&other).pack(
+            &Decoded::from([("index".to_string(), Value::Int(1))])).unwrap();
+        assert!(passthrough.check(&other_bytes).is_err());
+        assert!(passthrough.check(b"not avro").is_err());
+        assert_eq!(passthrough.stats().accepted, 5);
+        assert_eq!(passthrough.stats().rejected, 6);
+    }
+}
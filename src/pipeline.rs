@@ -0,0 +1,214 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+use crate::decoder::Decoder;
+use crate::encoder::Encoder;
+use crate::schema::Encoded;
+use crate::error::FcResult;
+
+/// Decode a message and encode each of its records.
+pub fn transcode(decoder: &dyn Decoder, encoder: &dyn Encoder, bytes: &Encoded) -> FcResult<Vec<Encoded>> {
+    decoder.unpack(bytes)?.iter().map(|x| encoder.pack(x)).collect()
+}
+
+/// Counters and queue depths of a running pipeline.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PipelineStats {
+    pub received: u64,
+    pub published: u64,
+    /// Messages which failed to be transcoded
+    pub failed: u64,
+    /// Messages waiting for a worker
+    pub queued: usize,
+    /// Transcoded messages waiting for an earlier one
+    pub reordering: usize,
+}
+
+#[derive(Default)]
+struct Metrics {
+    received: AtomicU64,
+    published: AtomicU64,
+    failed: AtomicU64,
+    queued: AtomicUsize,
+    reordering: AtomicUsize,
+}
+
+/// Receives messages on the calling thread, transcodes them on a pool of
+/// worker threads and publishes them on a writer thread in the order they
+/// were received.
+///
+/// At most `queue_size` + `workers` messages are in flight. The receiver
+/// blocks when the limit is reached, so a slow sink slows down consumption
+/// instead of growing the queues.
+pub struct Pipeline {
+    workers: usize,
+    queue_size: usize,
+    metrics: Metrics,
+}
+
+impl Pipeline {
+    pub fn new(workers: usize, queue_size: usize) -> Self {
+        assert!(workers > 0, "Pipeline requires at least one worker");
+        assert!(queue_size > 0, "Pipeline requires a non-empty queue");
+
+        Pipeline {
+            workers,
+            queue_size,
+            metrics: Metrics::default(),
+        }
+    }
+
+    pub fn stats(&self) -> PipelineStats {
+        PipelineStats {
+            received: self.metrics.received.load(Ordering::Relaxed),
+            published: self.metrics.published.load(Ordering::Relaxed),
+            failed: self.metrics.failed.load(Ordering::Relaxed),
+            queued: self.metrics.queued.load(Ordering::Relaxed),
+            reordering: self.metrics.reordering.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Run until `receive` returns None or an error.
+    ///
+    /// `new_worker` is called once per worker thread to create its transcoding
+    /// function. `publish` is called with the sequence number of each message
+    /// and its transcoded records, or the error of transcoding.
+    pub fn run<R, F, T, P>(&self, mut receive: R, new_worker: F, mut publish: P) -> FcResult<()>
+        where R: FnMut() -> FcResult<Option<Encoded>>,
+              F: Fn() -> T,
+              T: FnMut(&Encoded) -> FcResult<Vec<Encoded>> + Send,
+              P: FnMut(u64, FcResult<Vec<Encoded>>) + Send {
+        let metrics = &self.metrics;
+        let in_flight = self.queue_size + self.workers;
+
+        let (input_tx, input_rx) = mpsc::sync_channel::<(u64, Encoded)>(self.queue_size);
+        let input_rx = Mutex::new(input_rx);
+        let (output_tx, output_rx) = mpsc::sync_channel(self.queue_size);
+        // a token is taken for each received message and returned once it is published
+        let (token_tx, token_rx) = mpsc::sync_channel(in_flight);
+        for _ in 0..in_flight {
+            token_tx.send(()).unwrap();
+        }
+
+        thread::scope(|s| {
+            for _ in 0..self.workers {
+                let mut work = new_worker();
+                let input_rx = &input_rx;
+                let output_tx = output_tx.clone();
+                s.spawn(move || loop {
+                    let received = input_rx.lock().unwrap().recv();
+                    let (seq, bytes) = match received {
+                        Ok(x) => x,
+                        Err(_) => break,
+                    };
+                    metrics.queued.fetch_sub(1, Ordering::Relaxed);
+                    if output_tx.send((seq, work(&bytes))).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(output_tx);
+
+            s.spawn(move || {
+                let mut next = 0;
+                let mut pending = BTreeMap::new();
+                for (seq, result) in output_rx {
+                    pending.insert(seq, result);
+                    while let Some(result) = pending.remove(&next) {
+                        let counter = if result.is_ok() { &metrics.published } else { &metrics.failed };
+                        counter.fetch_add(1, Ordering::Relaxed);
+                        publish(next, result);
+                        next += 1;
+                        let _ = token_tx.send(());
+                    }
+                    metrics.reordering.store(pending.len(), Ordering::Relaxed);
+                }
+            });
+
+            let mut seq = 0;
+            let ret = loop {
+                // the writer stopped if no token is left
+                if token_rx.recv().is_err() {
+                    break Ok(());
+                }
+                let bytes = match receive() {
+                    Ok(Some(x)) => x,
+                    Ok(None) => break Ok(()),
+                    Err(e) => break Err(e),
+                };
+                metrics.received.fetch_add(1, Ordering::Relaxed);
+                metrics.queued.fetch_add(1, Ordering::Relaxed);
+                if input_tx.send((seq, bytes)).is_err() {
+                    break Ok(());
+                }
+                seq += 1;
+            };
+            // stop the workers, which in turn stops the writer
+            drop(input_tx);
+            ret
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::error::FcError;
+    use crate::pipeline::{Pipeline, PipelineStats};
+
+    #[test]
+    fn test_pipeline_preserves_order() {
+        let pipeline = Pipeline::new(4, 2);
+        let mut inputs = (0..50u8).map(|i| vec![i]);
+        let mut outputs = Vec::new();
+
+        pipeline.run(
+            || Ok(inputs.next()),
+            || |bytes: &Vec<u8>| {
+                // later messages tend to finish first
+                std::thread::sleep(Duration::from_micros(100 * (bytes[0] % 5) as u64));
+                if bytes[0] == 7 {
+                    return Err(FcError::CodecError("invalid".to_string()));
+                }
+                Ok(vec![bytes.clone(), bytes.clone()])
+            },
+            |seq, result| outputs.push((seq, result.map(|x| x.len()).ok())),
+        ).unwrap();
+
+        assert_eq!(outputs.len(), 50);
+        for (i, (seq, n)) in outputs.iter().enumerate() {
+            assert_eq!(*seq, i as u64);
+            assert_eq!(*n, if i == 7 { None } else { Some(2) });
+        }
+        assert_eq!(pipeline.stats(), PipelineStats {
+            received: 50, published: 49, failed: 1, queued: 0, reordering: 0,
+        });
+    }
+
+    #[test]
+    fn test_pipeline_stops_on_receive_error() {
+        let pipeline = Pipeline::new(2, 4);
+        let mut count = 0;
+        let ret = pipeline.run(
+            || {
+                count += 1;
+                if count > 3 { Err(FcError::CodecError("closed".to_string())) } else { Ok(Some(vec![0])) }
+            },
+            || |bytes: &Vec<u8>| Ok(vec![bytes.clone()]),
+            |_, _| (),
+        );
+        assert!(ret.is_err());
+        assert_eq!(pipeline.stats().published, 3);
+    }
+}
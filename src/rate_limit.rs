@@ -0,0 +1,198 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use crate::schema::Encoded;
+
+/// Policy which limits the records published to a stream.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RatePolicy {
+    /// Token bucket: at most `rate` records per second on average and bursts
+    /// of at most `burst` records
+    MaxRate { rate: f64, burst: f64 },
+    /// Keep the first of every n records
+    EveryNth(u64),
+    /// At most one record per interval. Records arriving in between replace
+    /// each other and the latest one is published once the interval is over
+    Latest(Duration),
+}
+
+impl FromStr for RatePolicy {
+    type Err = String;
+
+    /// Parse "rate:<records per second>[:<burst>]", "every:<n>" or "latest:<milliseconds>".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid rate policy: {}", s);
+        let parts: Vec<&str> = s.split(':').collect();
+        let number = |x: &str| x.parse::<f64>().ok().filter(|x| x.is_finite() && *x > 0.).ok_or_else(invalid);
+        Ok(match parts[..] {
+            ["rate", rate] => RatePolicy::MaxRate { rate: number(rate)?, burst: 1. },
+            ["rate", rate, burst] => RatePolicy::MaxRate { rate: number(rate)?, burst: number(burst)?.max(1.) },
+            ["every", n] => RatePolicy::EveryNth(n.parse().ok().filter(|n| *n > 0).ok_or_else(invalid)?),
+            ["latest", ms] => RatePolicy::Latest(Duration::from_secs_f64(number(ms)? / 1000.)),
+            _ => return Err(invalid()),
+        })
+    }
+}
+
+/// What to do with a record offered to a `RateLimiter`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Admission {
+    Publish,
+    Drop,
+    /// Keep the record with `defer` until it is due or replaced
+    Defer,
+}
+
+/// Counters of a `RateLimiter`.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RateStats {
+    pub published: u64,
+    pub dropped: u64,
+}
+
+/// Applies a `RatePolicy` to the records of a stream.
+pub struct RateLimiter {
+    policy: RatePolicy,
+    tokens: f64,
+    // time of the last refill (MaxRate) or publication (Latest)
+    last: Option<Instant>,
+    count: u64,
+    pending: Option<Encoded>,
+    stats: RateStats,
+}
+
+impl RateLimiter {
+    pub fn new(policy: RatePolicy) -> Self {
+        let tokens = match policy {
+            RatePolicy::MaxRate { burst, .. } => burst,
+            _ => 0.,
+        };
+        RateLimiter {
+            policy,
+            tokens,
+            last: None,
+            count: 0,
+            pending: None,
+            stats: RateStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> &RateStats {
+        &self.stats
+    }
+
+    fn is_due(&self, now: Instant, interval: Duration) -> bool {
+        self.last.is_none_or(|t| now.duration_since(t) >= interval)
+    }
+
+    /// Decide what to do with a new record.
+    pub fn admit(&mut self, now: Instant) -> Admission {
+        let admission = match self.policy {
+            RatePolicy::MaxRate { rate, burst } => {
+                if let Some(last) = self.last {
+                    self.tokens = (self.tokens + now.duration_since(last).as_secs_f64() * rate).min(burst);
+                }
+                self.last = Some(now);
+                if self.tokens >= 1. {
+                    self.tokens -= 1.;
+                    Admission::Publish
+                } else {
+                    Admission::Drop
+                }
+            },
+            RatePolicy::EveryNth(n) => {
+                self.count += 1;
+                if (self.count - 1).is_multiple_of(n) { Admission::Publish } else { Admission::Drop }
+            },
+            RatePolicy::Latest(interval) => {
+                if self.is_due(now, interval) {
+                    // the pending record is superseded by the new one
+                    if self.pending.take().is_some() {
+                        self.stats.dropped += 1;
+                    }
+                    self.last = Some(now);
+                    Admission::Publish
+                } else {
+                    Admission::Defer
+                }
+            },
+        };
+        match admission {
+            Admission::Publish => self.stats.published += 1,
+            Admission::Drop => self.stats.dropped += 1,
+            Admission::Defer => (),
+        }
+        admission
+    }
+
+    /// Keep a deferred record, replacing the previous one.
+    pub fn defer(&mut self, encoded: Encoded) {
+        if self.pending.replace(encoded).is_some() {
+            self.stats.dropped += 1;
+        }
+    }
+
+    /// Return the deferred record if its interval is over.
+    pub fn take_due(&mut self, now: Instant) -> Option<Encoded> {
+        match self.policy {
+            RatePolicy::Latest(interval) if self.pending.is_some() && self.is_due(now, interval) => {
+                self.last = Some(now);
+                self.stats.published += 1;
+                self.pending.take()
+            },
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crate::rate_limit::{Admission, RateLimiter, RatePolicy, RateStats};
+
+    #[test]
+    fn test_parse_rate_policy() {
+        assert_eq!("rate:10".parse::<RatePolicy>().unwrap(), RatePolicy::MaxRate { rate: 10., burst: 1. });
+        assert_eq!("rate:10:5".parse::<RatePolicy>().unwrap(), RatePolicy::MaxRate { rate: 10., burst: 5. });
+        assert_eq!("every:3".parse::<RatePolicy>().unwrap(), RatePolicy::EveryNth(3));
+        assert_eq!("latest:100".parse::<RatePolicy>().unwrap(), RatePolicy::Latest(Duration::from_millis(100)));
+        assert!("every:0".parse::<RatePolicy>().is_err());
+        assert!("rate:-1".parse::<RatePolicy>().is_err());
+        assert!("fast".parse::<RatePolicy>().is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let t0 = Instant::now();
+        let ms = |x: u64| t0 + Duration::from_millis(x);
+
+        // 10 records per second with bursts of 2
+        let mut limiter = RateLimiter::new(RatePolicy::MaxRate { rate: 10., burst: 2. });
+        let admitted: Vec<_> = [0, 0, 0, 50, 100, 300, 300, 300].iter().map(|&t| limiter.admit(ms(t))).collect();
+        assert_eq!(admitted.iter().filter(|x| **x == Admission::Publish).count(), 5);
+        assert_eq!(limiter.stats(), &RateStats { published: 5, dropped: 3 });
+
+        let mut limiter = RateLimiter::new(RatePolicy::EveryNth(3));
+        let admitted: Vec<_> = (0..7).map(|_| limiter.admit(t0) == Admission::Publish).collect();
+        assert_eq!(admitted, [true, false, false, true, false, false, true]);
+
+        let mut limiter = RateLimiter::new(RatePolicy::Latest(Duration::from_millis(100)));
+        assert_eq!(limiter.admit(ms(0)), Admission::Publish);
+        assert_eq!(limiter.admit(ms(10)), Admission::Defer);
+        limiter.defer(vec![1]);
+        assert_eq!(limiter.admit(ms(20)), Admission::Defer);
+        limiter.defer(vec![2]);
+        assert_eq!(limiter.take_due(ms(50)), None);
+        assert_eq!(limiter.take_due(ms(100)), Some(vec![2]));
+        assert_eq!(limiter.take_due(ms(300)), None);
+        assert_eq!(limiter.stats(), &RateStats { published: 2, dropped: 1 });
+    }
+}
@@ -5,18 +5,25 @@
  *
  * Author: Jun Zhu
  */
+use std::collections::HashMap;
+use std::marker::PhantomData;
+use std::time::Instant;
+
 use redis::{Commands};
-use redis::streams::{StreamId, StreamReadReply, StreamReadOptions, StreamMaxlen};
+use redis::streams::{StreamId, StreamRangeReply, StreamReadReply, StreamReadOptions, StreamMaxlen};
+use serde::Serialize;
 
 use crate::decoder::{create_decoder, Decoder};
 use crate::encoder::{create_encoder, Encoder};
-use crate::schema::Decoded;
-use crate::error::FcResult;
+use crate::rate_limit::{Admission, RateLimiter, RatePolicy, RateStats};
+use crate::schema::{Decoded, Encoded, SchemaRegistry, to_decoded};
+use crate::error::{FcError, FcResult};
 
 pub struct RedisProducer {
     client: redis::Client,
     maxlen: StreamMaxlen,
     encoder: Option<Box<dyn Encoder + Send>>,
+    limiters: HashMap<String, RateLimiter>,
 }
 
 impl RedisProducer {
@@ -30,6 +37,7 @@ impl RedisProducer {
             client,
             maxlen: StreamMaxlen::Equals(10),
             encoder: None,
+            limiters: HashMap::new(),
         }
     }
 
@@ -42,24 +50,168 @@ impl RedisProducer {
         self.maxlen = StreamMaxlen::Equals(maxlen);
     }
 
+    /// Limit the records published to a given stream.
+    pub fn set_rate_policy(&mut self, stream: &str, policy: RatePolicy) {
+        self.limiters.insert(stream.to_owned(), RateLimiter::new(policy));
+    }
+
+    /// Return the numbers of published and dropped records of a rate limited stream.
+    pub fn rate_stats(&self, stream: &str) -> Option<&RateStats> {
+        self.limiters.get(stream).map(|x| x.stats())
+    }
+
+    fn admit(&mut self, stream: &str) -> Admission {
+        match self.limiters.get_mut(stream) {
+            Some(limiter) => limiter.admit(Instant::now()),
+            None => Admission::Publish,
+        }
+    }
+
     /// Publish records to a given stream.
+    ///
+    /// Records dropped or deferred by the rate policy of the stream are not
+    /// in the returned entries.
     pub fn produce(&mut self, records: &[Decoded], stream: &str)
             -> Vec<FcResult<String>> {
-        records.into_iter().map(|x| {
-            let encoded = self.encoder.as_ref().unwrap().pack(x)?;
+        let mut ret = Vec::with_capacity(records.len());
+        for x in records {
+            let admission = self.admit(stream);
+            if admission == Admission::Drop {
+                continue;
+            }
+            let encoded = match self.encoder.as_ref().unwrap().pack(x) {
+                Ok(encoded) => encoded,
+                Err(e) => {
+                    ret.push(Err(e));
+                    continue;
+                },
+            };
+            match admission {
+                Admission::Defer => self.limiters.get_mut(stream).unwrap().defer(encoded),
+                _ => ret.push(self.xadd(stream, &encoded)),
+            }
+        }
+        ret
+    }
+
+    /// Publish records which are already encoded to a given stream.
+    pub fn produce_encoded<B: AsRef<[u8]>>(&mut self, encoded: &[B], stream: &str) -> Vec<FcResult<String>> {
+        let mut ret = Vec::with_capacity(encoded.len());
+        for x in encoded {
+            match self.admit(stream) {
+                Admission::Publish => ret.push(self.xadd(stream, x.as_ref())),
+                Admission::Drop => (),
+                Admission::Defer => self.limiters.get_mut(stream).unwrap().defer(x.as_ref().to_vec()),
+            }
+        }
+        ret
+    }
+
+    /// Publish the deferred records whose interval is over.
+    ///
+    /// Should be called regularly when the latest-only policy is used, since
+    /// otherwise the last record is only published with the next one.
+    pub fn flush(&mut self) -> Vec<FcResult<String>> {
+        let now = Instant::now();
+        let due: Vec<_> = self.limiters.iter_mut()
+            .filter_map(|(stream, limiter)| limiter.take_due(now).map(|x| (stream.clone(), x)))
+            .collect();
+        due.into_iter().map(|(stream, encoded)| self.xadd(&stream, &encoded)).collect()
+    }
+
+    fn xadd(&self, stream: &str, encoded: &[u8]) -> FcResult<String> {
+        let entry = self.client.get_connection()?
+            .xadd_maxlen(stream, self.maxlen, "*", &[("data", encoded)])?;
+
+        Ok(entry)
+    }
+}
+
+/// RedisProducer which publishes plain Rust structs.
+pub struct TypedRedisProducer<T> {
+    producer: RedisProducer,
+    _marker: PhantomData<T>,
+}
 
-            let entry = self.client.get_connection()?
-                .xadd_maxlen(stream, self.maxlen, "*", &[("data", encoded)])?;
+impl<T: Serialize> TypedRedisProducer<T> {
 
-            Ok(entry)
+    pub fn new(host: &str, port: i32) -> Self {
+        TypedRedisProducer {
+            producer: RedisProducer::new(host, port),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn set_encoder(&mut self, name: &str, schema: Option<&serde_json::Value>) {
+        self.producer.set_encoder(name, schema);
+    }
+
+    /// Sets the MAXLEN parameter in XADD
+    pub fn set_maxlen(&mut self, maxlen: usize) {
+        self.producer.set_maxlen(maxlen);
+    }
+
+    /// Limit the records published to a given stream.
+    pub fn set_rate_policy(&mut self, stream: &str, policy: RatePolicy) {
+        self.producer.set_rate_policy(stream, policy);
+    }
+
+    /// Publish records to a given stream.
+    pub fn produce(&mut self, records: &[T], stream: &str) -> Vec<FcResult<String>> {
+        records.iter().flat_map(|x| match to_decoded(x) {
+            Ok(decoded) => self.producer.produce(&[decoded], stream),
+            Err(e) => vec![Err(e)],
         }).collect()
     }
 }
 
+/// Writes the status of a data source into the Redis hash "<stream>:_status".
+pub struct StatusWriter {
+    client: redis::Client,
+    ttl: usize,
+}
+
+impl StatusWriter {
+    pub fn new(host: &str, port: i32) -> Self {
+        let client = redis::Client::open(
+            format!("redis://{}:{}", host, port)).expect(
+            "Failed to open a Redis connection");
+
+        StatusWriter {
+            client,
+            ttl: 30,
+        }
+    }
+
+    /// Sets the number of seconds after which a status which is not written again expires.
+    pub fn set_ttl(&mut self, ttl: usize) {
+        self.ttl = ttl;
+    }
+
+    /// Write the status of the source of a given stream.
+    pub fn write(&self, stream: &str, status: &[(&str, String)]) -> FcResult<()> {
+        let key = stream.to_owned() + ":_status";
+        let mut con = self.client.get_connection()?;
+        redis::pipe().atomic()
+            .hset_multiple(&key, status).ignore()
+            .expire(&key, self.ttl).ignore()
+            .query::<()>(&mut con)?;
+        Ok(())
+    }
+}
+
+/// Rebuilds the decoder whenever the registered schema of the stream changes.
+struct SchemaTracker {
+    registry: SchemaRegistry,
+    decoder: String,
+    schema: Option<serde_json::Value>,
+}
+
 pub struct RedisConsumer {
     client: redis::Client,
     block: usize,
     decoder: Option<Box<dyn Decoder + Send>>,
+    tracker: Option<SchemaTracker>,
 }
 
 impl RedisConsumer {
@@ -72,6 +224,7 @@ impl RedisConsumer {
             client,
             block: 100,
             decoder: None,
+            tracker: None,
         }
     }
 
@@ -79,6 +232,30 @@ impl RedisConsumer {
         self.decoder = Some(create_decoder(name, schema));
     }
 
+    /// Use the schema registered for the consumed stream to create the decoder.
+    ///
+    /// The decoder is recreated whenever the registry returns a new schema.
+    pub fn set_schema_registry(&mut self, registry: SchemaRegistry, decoder: &str) {
+        self.tracker = Some(SchemaTracker {
+            registry,
+            decoder: decoder.to_owned(),
+            schema: None,
+        });
+    }
+
+    fn update_decoder(&mut self, stream: &str) -> FcResult<()> {
+        if let Some(tracker) = self.tracker.as_mut() {
+            let schema = tracker.registry.get(stream)?;
+            if tracker.schema.as_ref() != Some(schema) {
+                let schema = schema.clone();
+                self.decoder = Some(create_decoder(
+                    &tracker.decoder, if schema.is_null() { None } else { Some(&schema) }));
+                tracker.schema = Some(schema);
+            }
+        }
+        Ok(())
+    }
+
     /// Sets the BLOCK parameter in XREAD
     pub fn set_block(&mut self, block: usize) {
         self.block = block;
@@ -90,6 +267,12 @@ impl RedisConsumer {
     /// One can set ID to None to get the latest record and use the returned stream ID
     /// as the argument of the next call.
     pub fn consume(&mut self, stream: &str, id: Option<&str>) -> FcResult<(String, Decoded)> {
+        Ok(self.try_consume(stream, id)?.expect("No record arrived within BLOCK"))
+    }
+
+    /// Same as `consume` but returns None if no record arrived within BLOCK.
+    pub fn try_consume(&mut self, stream: &str, id: Option<&str>)
+            -> FcResult<Option<(String, Decoded)>> {
         const COUNT: usize = 1;
         let opts = StreamReadOptions::default().count(COUNT).block(self.block);
 
@@ -101,6 +284,9 @@ impl RedisConsumer {
             .xread_options(&[stream], &ids, &opts)?;
 
         let keys = reply.keys;
+        if keys.is_empty() {
+            return Ok(None);
+        }
         assert_eq!(keys.len(), 1); // one stream
         assert_eq!(keys[0].key, stream);
 
@@ -113,10 +299,60 @@ impl RedisConsumer {
             _ => panic!(),
         };
 
+        self.update_decoder(stream)?;
         let decoded = self.decoder.as_ref().unwrap().unpack(bytes)?;
         assert_eq!(decoded.len(), 1);
 
-        Ok((sid, decoded.into_iter().next().unwrap()))
+        Ok(Some((sid, decoded.into_iter().next().unwrap())))
+    }
+
+    /// Reads at most COUNT entries after the given ID of each stream with a
+    /// single XREAD and returns a list of (stream, stream ID, encoded data).
+    ///
+    /// The entries of a stream are in order. Returns an empty list if no
+    /// entry arrived within BLOCK.
+    pub fn read_raw(&mut self, streams: &[&str], ids: &[&str], count: usize)
+            -> FcResult<Vec<(String, String, Encoded)>> {
+        let opts = StreamReadOptions::default().count(count).block(self.block);
+        let reply: StreamReadReply = self.client.get_connection()?
+            .xread_options(streams, ids, &opts)?;
+
+        let mut ret = Vec::new();
+        for key in reply.keys {
+            for StreamId {id: sid, map: record} in key.ids {
+                let bytes = match record.get("data") {
+                    Some(redis::Value::Data(s)) => s.clone(),
+                    _ => return Err(FcError::CodecError(format!("Entry {} has no data", sid))),
+                };
+                ret.push((key.key.clone(), sid, bytes));
+            }
+        }
+        Ok(ret)
+    }
+
+    /// Reads at most COUNT records with IDs in the range [start, end] and returns
+    /// a list of (stream ID, decoded record).
+    ///
+    /// "-" and "+" stand for the first and last IDs of the stream. Use "(" + ID
+    /// as start to continue after a previously read record.
+    pub fn range(&mut self, stream: &str, start: &str, end: &str, count: usize)
+            -> FcResult<Vec<(String, Decoded)>> {
+        let reply: StreamRangeReply = self.client.get_connection()?
+            .xrange_count(stream, start, end, count)?;
+
+        self.update_decoder(stream)?;
+        let decoder = self.decoder.as_ref().unwrap();
+        let mut ret = Vec::with_capacity(reply.ids.len());
+        for StreamId {id: sid, map: record} in reply.ids {
+            let bytes = match record.get("data") {
+                Some(redis::Value::Data(s)) => s,
+                _ => return Err(FcError::CodecError(format!("Entry {} has no data", sid))),
+            };
+            for decoded in decoder.unpack(bytes)? {
+                ret.push((sid.clone(), decoded));
+            }
+        }
+        Ok(ret)
     }
 }
 
@@ -127,8 +363,10 @@ mod tests {
     use apache_avro::types::Value;
     use crate::error::FcError;
 
-    use crate::schema::Decoded;
-    use crate::redis_clients::{RedisConsumer, RedisProducer};
+    use crate::encoder::create_encoder;
+    use crate::schema::{Decoded, SchemaRegistry};
+    use crate::schema_store::MemorySchemaStore;
+    use crate::redis_clients::{RedisConsumer, RedisProducer, TypedRedisProducer};
 
     #[test]
     fn test_redis_consumer_and_producer() {
@@ -183,4 +421,82 @@ mod tests {
 
         t.join().unwrap();
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_typed_redis_producer() {
+        #[derive(serde::Serialize)]
+        struct Raw {
+            index: i32,
+        }
+
+        let json_schema = serde_json::json!({
+            "namespace": "redis_clients_test",
+            "type": "record",
+            "name": "typed",
+            "fields": [{"name": "index", "type": "int"}]
+        });
+        let stream = "redis_clients_test_typed";
+
+        let host = "127.0.0.1";
+        let port = 6379;
+
+        let mut producer = TypedRedisProducer::<Raw>::new(host, port);
+        producer.set_encoder("avro", Some(&json_schema));
+
+        let mut consumer = RedisConsumer::new(host, port);
+        consumer.set_decoder("avro", Some(&json_schema));
+
+        let items = vec![Raw { index: 1 }, Raw { index: 2 }];
+        let ids = match producer.produce(&items, stream).into_iter().collect::<Result<Vec<_>, _>>() {
+            Ok(ids) => ids,
+            Err(FcError::RedisError(e)) => {
+                println!("Test skipped: no Redis connection: {:?}", e);
+                return;
+            },
+            Err(e) => panic!("{:?}", e),
+        };
+
+        let records = consumer.range(stream, &ids[0], &ids[1], 10).unwrap();
+        assert_eq!(records, vec![
+            (ids[0].clone(), Decoded::from([("index".to_string(), Value::Int(1))])),
+            (ids[1].clone(), Decoded::from([("index".to_string(), Value::Int(2))])),
+        ]);
+    }
+
+    #[test]
+    fn test_redis_consumer_schema_update() {
+        let mut schema = serde_json::json!({
+            "namespace": "redis_clients_test",
+            "type": "record",
+            "name": "tracked",
+            "fields": [{"name": "index", "type": "int"}]
+        });
+        let stream = "redis_clients_test:tracked";
+
+        let store = MemorySchemaStore::new();
+        let mut writer = SchemaRegistry::with_store(Box::new(store.clone()));
+        writer.set(stream, Some(&schema)).unwrap();
+
+        let mut registry = SchemaRegistry::with_store(Box::new(store));
+        registry.set_ttl(None);
+        registry.enable_invalidation().unwrap();
+        // no connection is made until a stream is read
+        let mut consumer = RedisConsumer::new("127.0.0.1", 6379);
+        consumer.set_schema_registry(registry, "avro");
+
+        consumer.update_decoder(stream).unwrap();
+        let bytes = create_encoder("avro", Some(&schema)).pack(
+            &Decoded::from([("index".to_string(), Value::Int(1))])).unwrap();
+        assert_eq!(consumer.decoder.as_ref().unwrap().unpack(&bytes).unwrap(),
+                   vec![Decoded::from([("index".to_string(), Value::Int(1))])]);
+
+        // the decoder follows the new version without restarting the consumer
+        schema["fields"][0]["type"] = serde_json::json!("long");
+        writer.set(stream, Some(&schema)).unwrap();
+        consumer.update_decoder(stream).unwrap();
+        let bytes = create_encoder("avro", Some(&schema)).pack(
+            &Decoded::from([("index".to_string(), Value::Long(2))])).unwrap();
+        assert_eq!(consumer.decoder.as_ref().unwrap().unpack(&bytes).unwrap(),
+                   vec![Decoded::from([("index".to_string(), Value::Long(2))])]);
+    }
+}
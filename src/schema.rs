@@ -6,25 +6,98 @@
  * Author: Jun Zhu
  */
 use std::fs;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use apache_avro::types::Value;
-use redis::Commands;
+use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 
-use crate::error::FcResult;
+use crate::error::{FcError, FcResult};
+use crate::schema_store::{RedisSchemaStore, SchemaStore, SchemaUpdate, Subscription};
+
+pub use foamcore_derive::FoamSchema;
 
 pub type Encoded = Vec<u8>;
 pub type Decoded = HashMap<String, Value>;
 
-/// Parse Avro schema from Json schema.
-pub fn json_to_avro_schema(schema: &serde_json::Value) -> apache_avro::Schema {
-    let avro_schema = apache_avro::Schema::parse(schema).unwrap();
+/// Typed counterpart of the "ndarray" record used in the schemas.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NDArray {
+    pub shape: Vec<i32>,
+    pub dtype: String,
+    #[serde(with = "serde_bytes")]
+    pub data: Vec<u8>,
+}
+
+/// Convert a serializable Rust value into a decoded record.
+///
+/// The value must serialize to an Avro record, i.e. it must be a struct or a map.
+pub fn to_decoded<T: Serialize>(datum: &T) -> FcResult<Decoded> {
+    match apache_avro::to_value(datum)? {
+        Value::Record(fields) => Ok(fields.into_iter().collect()),
+        Value::Map(fields) => Ok(fields),
+        _ => Err(FcError::AvroError(apache_avro::Error::Validation)),
+    }
+}
+
+/// Convert a decoded record into a deserializable Rust value.
+pub fn from_decoded<T: DeserializeOwned>(datum: Decoded) -> FcResult<T> {
+    let record = Value::Record(datum.into_iter().collect());
+    Ok(apache_avro::from_value::<T>(&record)?)
+}
+
+/// Find the value of a (nested) field, returning None for missing and null values.
+///
+/// Union values are unwrapped.
+pub fn field_value<'a>(datum: &'a Decoded, path: &[String]) -> Option<&'a Value> {
+    fn strip_union(value: &Value) -> Option<&Value> {
+        match value {
+            Value::Union(_, inner) => strip_union(inner),
+            Value::Null => None,
+            _ => Some(value),
+        }
+    }
+
+    let mut value = strip_union(datum.get(path.first()?)?)?;
+    for name in &path[1..] {
+        value = match value {
+            Value::Record(fields) => strip_union(&fields.iter().find(|(k, _)| k == name)?.1)?,
+            _ => return None,
+        };
+    }
+    Some(value)
+}
+
+/// Parse Avro schema from Json schema, which must be a record.
+pub fn parse_avro_schema(schema: &serde_json::Value) -> FcResult<apache_avro::Schema> {
+    let avro_schema = apache_avro::Schema::parse(schema)?;
     match avro_schema {
-        apache_avro::Schema::Record(_) => avro_schema,
-        _ => panic!("Expected Schema::Record. Actual: {:?}", avro_schema),
+        apache_avro::Schema::Record(_) => Ok(avro_schema),
+        _ => Err(FcError::SchemaError(format!("Expected Schema::Record. Actual: {:?}", avro_schema))),
     }
 }
 
+/// Parse Avro schema from Json schema.
+pub fn json_to_avro_schema(schema: &serde_json::Value) -> apache_avro::Schema {
+    parse_avro_schema(schema).unwrap_or_else(|e| panic!("{:?}", e))
+}
+
+/// Return the Redis stream name ("namespace:name") of a Json schema.
+pub fn stream_name(schema: &serde_json::Value) -> String {
+    let namespace = schema.get("namespace").expect("Schema must contain 'namespace'");
+    let name = schema.get("name").expect("Schema must contain 'name'");
+    namespace.as_str().unwrap().to_owned() + ":" + name.as_str().unwrap()
+}
+
+/// Return the topic/subject name ("namespace.name") of a stream.
+///
+/// Used by transports which do not allow ':' in names (e.g. Kafka).
+pub fn topic_name(stream: &str) -> String {
+    stream.replace(':', ".")
+}
+
 /// Load Json schema from file.
 ///
 /// A schema is needed not only for serialization and deserialization,
@@ -39,67 +112,525 @@ pub fn load_schema(path: &str) -> (Option<serde_json::Value>, String) {
         println!("{}", serde_json::to_string_pretty(&raw_schema).unwrap());
     }
 
-    let namespace = raw_schema.get("namespace").expect("Schema must contain 'namespace'");
-    let name = raw_schema.get("name").expect("Schema must contain 'name'");
-    let stream = namespace.as_str().unwrap().to_owned() + ":" + name.as_str().unwrap();
+    let stream = stream_name(&raw_schema);
 
     let schema = raw_schema.clone().get("fields").map_or(None, |_| Some(raw_schema));
 
     (schema, stream)
 }
 
+/// Load Json schema from file like `load_schema`, but return errors instead
+/// of panicking. A schema with fields must be a valid Avro record.
+pub fn read_schema(path: &str) -> FcResult<(Option<serde_json::Value>, String)> {
+    let raw_schema: serde_json::Value = serde_json::from_str(&fs::read_to_string(path)?)?;
+
+    let namespace = raw_schema.get("namespace").and_then(|x| x.as_str()).ok_or_else(
+        || FcError::SchemaError("Schema must contain 'namespace'".to_string()))?;
+    let name = raw_schema.get("name").and_then(|x| x.as_str()).ok_or_else(
+        || FcError::SchemaError("Schema must contain 'name'".to_string()))?;
+    let stream = namespace.to_owned() + ":" + name;
+
+    if raw_schema.get("fields").is_none() {
+        return Ok((None, stream));
+    }
+    parse_avro_schema(&raw_schema)?;
+    Ok((Some(raw_schema), stream))
+}
+
+/// Rust types which can be used as fields of a `FoamSchema` record.
+pub trait AvroType {
+    fn avro_type() -> serde_json::Value;
+}
+
+/// Rust types which carry their own Avro record schema.
+///
+/// Usually implemented with `#[derive(FoamSchema)]`.
+pub trait FoamSchema: AvroType {
+    fn json_schema() -> serde_json::Value {
+        Self::avro_type()
+    }
+
+    fn stream() -> String {
+        stream_name(&Self::json_schema())
+    }
+}
+
+macro_rules! impl_avro_type {
+    ($($t:ty => $name:expr),*) => {
+        $(impl AvroType for $t {
+            fn avro_type() -> serde_json::Value {
+                serde_json::Value::from($name)
+            }
+        })*
+    };
+}
+
+impl_avro_type!(
+    bool => "boolean",
+    i32 => "int",
+    i64 => "long",
+    f32 => "float",
+    f64 => "double",
+    String => "string",
+    serde_bytes::ByteBuf => "bytes"
+);
+
+impl<T: AvroType> AvroType for Vec<T> {
+    fn avro_type() -> serde_json::Value {
+        serde_json::json!({"type": "array", "items": T::avro_type()})
+    }
+}
+
+impl<T: AvroType> AvroType for HashMap<String, T> {
+    fn avro_type() -> serde_json::Value {
+        serde_json::json!({"type": "map", "values": T::avro_type()})
+    }
+}
+
+impl<T: AvroType> AvroType for Option<T> {
+    fn avro_type() -> serde_json::Value {
+        serde_json::json!(["null", T::avro_type()])
+    }
+}
+
+impl AvroType for NDArray {
+    fn avro_type() -> serde_json::Value {
+        serde_json::json!({
+            "type": "record",
+            "logicalType": "ndarray",
+            "name": "NDArray",
+            "fields": [
+                {"name": "shape", "type": {"items": "int", "type": "array"}},
+                {"name": "dtype", "type": "string"},
+                {"name": "data", "type": "bytes"}
+            ]
+        })
+    }
+}
+
+/// Build a Json record schema from (name, type) pairs of fields.
+///
+/// Named types (e.g. NDArray) can only be defined once in an Avro schema.
+/// Repeated definitions are replaced by references to the first one.
+pub fn record_schema(namespace: &str, name: &str,
+                     fields: Vec<(&str, serde_json::Value)>) -> serde_json::Value {
+    let mut schema = serde_json::json!({
+        "namespace": namespace,
+        "type": "record",
+        "name": name,
+        "fields": fields.into_iter().map(|(n, t)| {
+            serde_json::json!({"name": n, "type": t})
+        }).collect::<Vec<_>>(),
+    });
+    dedup_named_types(&mut schema, None, &mut HashSet::new());
+    schema
+}
+
+fn dedup_named_types(schema: &mut serde_json::Value, namespace: Option<&str>,
+                     defined: &mut HashSet<String>) {
+    match schema {
+        serde_json::Value::Array(union) => {
+            for s in union {
+                dedup_named_types(s, namespace, defined);
+            }
+        },
+        serde_json::Value::Object(obj) => {
+            match obj.get("type").and_then(|t| t.as_str()) {
+                Some("record") | Some("enum") | Some("fixed") => {
+                    // a named type without namespace inherits the enclosing one
+                    let name = obj["name"].as_str().unwrap().to_owned();
+                    let fullname = match obj.get("namespace").and_then(|ns| ns.as_str()).or(namespace) {
+                        Some(ns) if !name.contains('.') && !ns.is_empty() => format!("{}.{}", ns, name),
+                        _ => name,
+                    };
+                    if !defined.insert(fullname.clone()) {
+                        // references are resolved in the enclosing namespace
+                        *schema = serde_json::Value::String(match (fullname.rsplit_once('.'), namespace) {
+                            (Some((ns, short)), Some(enclosing)) if ns == enclosing => short.to_owned(),
+                            _ => fullname,
+                        });
+                        return;
+                    }
+                    let namespace = fullname.rsplit_once('.').map(|(ns, _)| ns.to_owned());
+                    if let Some(serde_json::Value::Array(fields)) = obj.get_mut("fields") {
+                        for field in fields {
+                            if let Some(t) = field.get_mut("type") {
+                                dedup_named_types(t, namespace.as_deref(), defined);
+                            }
+                        }
+                    }
+                },
+                Some("array") => {
+                    if let Some(items) = obj.get_mut("items") {
+                        dedup_named_types(items, namespace, defined);
+                    }
+                },
+                Some("map") => {
+                    if let Some(values) = obj.get_mut("values") {
+                        dedup_named_types(values, namespace, defined);
+                    }
+                },
+                _ => (),
+            }
+        },
+        _ => (),
+    }
+}
+
+const PRIMITIVE_TYPES: [&str; 8] = ["null", "boolean", "int", "long", "float", "double", "bytes", "string"];
+
+/// Replace references to named types by their definitions.
+///
+/// This is the inverse of the deduplication in `record_schema`, so that the
+/// type of a field can be used on its own.
+pub(crate) fn expand_named_types(schema: &mut serde_json::Value, namespace: Option<&str>,
+                                 defined: &mut HashMap<String, serde_json::Value>) {
+    match schema {
+        serde_json::Value::String(name) if !PRIMITIVE_TYPES.contains(&name.as_str()) => {
+            let qualified = match namespace {
+                Some(ns) if !name.contains('.') && !ns.is_empty() => Some(format!("{}.{}", ns, name)),
+                _ => None,
+            };
+            if let Some(definition) = qualified.and_then(|n| defined.get(&n)).or_else(|| defined.get(name.as_str())) {
+                *schema = definition.clone();
+            }
+        },
+        serde_json::Value::Array(union) => {
+            for s in union {
+                expand_named_types(s, namespace, defined);
+            }
+        },
+        serde_json::Value::Object(obj) => {
+            match obj.get("type").and_then(|t| t.as_str()) {
+                Some("record") | Some("enum") | Some("fixed") => {
+                    let name = obj["name"].as_str().unwrap().to_owned();
+                    let fullname = match obj.get("namespace").and_then(|ns| ns.as_str()).or(namespace) {
+                        Some(ns) if !name.contains('.') && !ns.is_empty() => format!("{}.{}", ns, name),
+                        _ => name,
+                    };
+                    let namespace = fullname.rsplit_once('.').map(|(ns, _)| ns.to_owned());
+                    if let Some(serde_json::Value::Array(fields)) = obj.get_mut("fields") {
+                        for field in fields {
+                            if let Some(t) = field.get_mut("type") {
+                                expand_named_types(t, namespace.as_deref(), defined);
+                            }
+                        }
+                    }
+                    defined.insert(fullname, schema.clone());
+                },
+                Some("array") => {
+                    if let Some(items) = obj.get_mut("items") {
+                        expand_named_types(items, namespace, defined);
+                    }
+                },
+                Some("map") => {
+                    if let Some(values) = obj.get_mut("values") {
+                        expand_named_types(values, namespace, defined);
+                    }
+                },
+                _ => (),
+            }
+        },
+        _ => (),
+    }
+}
+
+struct CachedSchema {
+    // Json null for a schema without fields
+    schema: serde_json::Value,
+    fetched: Instant,
+}
+
+#[derive(Default)]
+struct Invalidation {
+    streams: HashSet<String>,
+    // set while the subscription is lost and updates cannot be observed
+    lost: bool,
+    // set when the subscription has been restored and updates may have been missed
+    restored: bool,
+}
+
+/// Schema registry on top of a `SchemaStore`, Redis by default.
+///
+/// Schemas are cached locally. A cached schema is fetched again after the
+/// TTL has expired or after an update notification has been received
+/// (see `enable_invalidation`).
 pub struct SchemaRegistry {
-    client: redis::Client,
-    schemas: HashMap<String, Option<serde_json::Value>>,
+    store: Box<dyn SchemaStore + Send>,
+    schemas: HashMap<String, CachedSchema>,
+    ttl: Option<Duration>,
+    invalidation: Arc<Mutex<Invalidation>>,
+    // stops the subscription to schema updates when dropped
+    subscription: Option<Subscription>,
 }
 
 impl SchemaRegistry {
     pub fn new(host: &str, port: i32) -> Self {
-        let client = redis::Client::open(
-            format!("redis://{}:{}", host, port.to_string())).expect(
-            "Failed to open a Redis connection");
-        let schemas : HashMap<String, Option<serde_json::Value>> = HashMap::new();
+        Self::with_store(Box::new(RedisSchemaStore::new(host, port)))
+    }
 
+    pub fn with_store(store: Box<dyn SchemaStore + Send>) -> Self {
         SchemaRegistry {
-            client,
-            schemas,
+            store,
+            schemas: HashMap::new(),
+            ttl: Some(Duration::from_secs(10)),
+            invalidation: Arc::new(Mutex::new(Invalidation::default())),
+            subscription: None,
         }
     }
 
-    pub fn get(&mut self, stream: &str) -> FcResult<&serde_json::Value> {
-        let mut con = self.client.get_connection()?;
+    /// Sets the lifetime of cached schemas. None means cached schemas never expire.
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+    }
 
-        // "0" is the version. Schema evolution has not implemented yet.
-        let s: String = con.hget(stream.to_owned() + ":_schema", "0").unwrap();
-        let schema = serde_json::from_str(s.as_str()).unwrap();
+    /// Subscribe to schema updates made through other registries.
+    ///
+    /// The cached schemas of the updated streams are invalidated. The cache
+    /// is bypassed while the subscription is lost and cleared once it has
+    /// been restored. The subscription is stopped when the registry is dropped.
+    pub fn enable_invalidation(&mut self) -> FcResult<()> {
+        let invalidation = Arc::clone(&self.invalidation);
+        let subscription = self.store.subscribe(Box::new(move |update| {
+            let mut invalidation = invalidation.lock().unwrap();
+            match update {
+                SchemaUpdate::Stream(s) => { invalidation.streams.insert(s.to_owned()); },
+                SchemaUpdate::Lost => invalidation.lost = true,
+                SchemaUpdate::Restored => {
+                    invalidation.lost = false;
+                    invalidation.restored = true;
+                },
+            }
+        }))?;
 
-        self.schemas.insert(stream.to_owned(), schema).unwrap();
+        match subscription {
+            Some(s) => {
+                self.subscription = Some(s);
+                Ok(())
+            },
+            None => Err(FcError::SchemaError(
+                "Schema store does not support update notifications".to_string())),
+        }
+    }
 
-        Ok(self.schemas.get(stream).unwrap().as_ref().unwrap())
+    /// Drop the cached schema of a stream.
+    pub fn invalidate(&mut self, stream: &str) {
+        self.schemas.remove(stream);
     }
 
-    pub fn set(&mut self, stream: &str, schema: Option<&serde_json::Value>) -> FcResult<()> {
-        let mut con = self.client.get_connection()?;
+    fn apply_invalidation(&mut self) {
+        let mut invalidation = self.invalidation.lock().unwrap();
+        if invalidation.lost || invalidation.restored {
+            self.schemas.clear();
+            invalidation.restored = false;
+        }
+        for stream in invalidation.streams.drain() {
+            self.schemas.remove(&stream);
+        }
+    }
+
+    /// Return the schema of a stream, fetching it from the store if the cached one is stale.
+    ///
+    /// A schema without fields is returned as Json null.
+    pub fn get(&mut self, stream: &str) -> FcResult<&serde_json::Value> {
+        self.apply_invalidation();
 
+        let stale = match self.schemas.get(stream) {
+            Some(cached) => self.ttl.is_some_and(|ttl| cached.fetched.elapsed() >= ttl),
+            None => true,
+        };
+        if stale {
+            // "0" is the version. Schema evolution has not implemented yet.
+            let schema = self.get_version(stream, "0")?.ok_or_else(
+                || FcError::SchemaError(format!("No schema registered for stream: {}", stream)))?;
+            self.schemas.insert(stream.to_owned(), CachedSchema {
+                schema,
+                fetched: Instant::now(),
+            });
+        }
+
+        Ok(&self.schemas[stream].schema)
+    }
+
+    /// Register the schema of a stream.
+    pub fn set(&mut self, stream: &str, schema: Option<&serde_json::Value>) -> FcResult<()> {
         // "0" is the version. Schema evolution has not implemented yet.
-        let _ : () = con.hset(stream.to_owned() + ":_schema", "0", match schema {
+        self.store.set(stream, "0", &match schema {
             Some(s) => s.to_string(),
             None => String::new(),
         })?;
-        self.schemas.insert(stream.to_owned(), schema.cloned());
+
+        self.schemas.insert(stream.to_owned(), CachedSchema {
+            schema: schema.cloned().unwrap_or(serde_json::Value::Null),
+            fetched: Instant::now(),
+        });
+
+        Ok(())
+    }
+
+    /// Return the names of all the streams which have a registered schema.
+    pub fn list(&mut self) -> FcResult<Vec<String>> {
+        self.store.list()
+    }
+
+    /// Return the registered versions of the schema of a stream.
+    pub fn versions(&mut self, stream: &str) -> FcResult<Vec<String>> {
+        self.store.versions(stream)
+    }
+
+    /// Return a given version of the schema of a stream without touching the cache.
+    ///
+    /// An empty schema (i.e. a schema without fields) is returned as Json null.
+    pub fn get_version(&mut self, stream: &str, version: &str)
+            -> FcResult<Option<serde_json::Value>> {
+        match self.store.get(stream, version)? {
+            Some(s) if s.is_empty() => Ok(Some(serde_json::Value::Null)),
+            Some(s) => Ok(Some(serde_json::from_str(&s)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Delete all the registered versions of the schema of a stream.
+    pub fn delete(&mut self, stream: &str) -> FcResult<()> {
+        self.store.delete(stream)?;
+        self.schemas.remove(stream);
 
         Ok(())
     }
 }
 
+fn describe_fields(schema: &serde_json::Value) -> Vec<(String, serde_json::Value)> {
+    schema.get("fields").and_then(|f| f.as_array()).map_or(Vec::new(), |fields| {
+        fields.iter().map(|f| {
+            (f["name"].as_str().unwrap_or_default().to_owned(), f["type"].clone())
+        }).collect()
+    })
+}
+
+/// Compare two Json schemas field by field.
+///
+/// Returns a human-readable line for every difference, prefixed by
+/// "-" (only in `old`), "+" (only in `new`) or "~" (changed).
+pub fn diff_schemas(old: &serde_json::Value, new: &serde_json::Value) -> Vec<String> {
+    let mut diffs = Vec::new();
+
+    for key in ["namespace", "name"] {
+        if old.get(key) != new.get(key) {
+            diffs.push(format!("~ {}: {} -> {}", key,
+                               old.get(key).unwrap_or(&serde_json::Value::Null),
+                               new.get(key).unwrap_or(&serde_json::Value::Null)));
+        }
+    }
+
+    let old_fields = describe_fields(old);
+    let new_fields = describe_fields(new);
+    for (name, t) in &old_fields {
+        match new_fields.iter().find(|(n, _)| n == name) {
+            Some((_, new_t)) if new_t != t => diffs.push(format!("~ {}: {} -> {}", name, t, new_t)),
+            Some(_) => (),
+            None => diffs.push(format!("- {}: {}", name, t)),
+        }
+    }
+    for (name, t) in &new_fields {
+        if !old_fields.iter().any(|(n, _)| n == name) {
+            diffs.push(format!("+ {}: {}", name, t));
+        }
+    }
+
+    diffs
+}
+
 
 #[cfg(test)]
 mod tests {
-    use crate::schema::load_schema;
+    use crate::schema::{load_schema, diff_schemas, read_schema, record_schema, AvroType, NDArray, SchemaRegistry};
+    use crate::error::FcError;
+    use crate::schema_store::MemorySchemaStore;
 
     #[test]
     #[should_panic(expected = "Unable to read file: abc")]
     fn test_load_avro_schema() {
         load_schema("abc");
     }
+
+    #[test]
+    fn test_read_schema() {
+        assert!(matches!(read_schema("abc"), Err(FcError::IoError(_))));
+
+        let (schema, stream) = read_schema("tests/data/schema1.json").unwrap();
+        assert_eq!(stream, "schema1:raw");
+        assert!(schema.is_some());
+
+        let path = std::env::temp_dir().join(format!("foamcore_read_schema_{}.json", std::process::id()));
+        let path = path.to_str().unwrap();
+        std::fs::write(path, r#"{"namespace": "testcase", "type": "record", "name": "raw",
+                                 "fields": [{"name": "index", "type": "integer"}]}"#).unwrap();
+        assert!(matches!(read_schema(path), Err(FcError::AvroError(_))));
+        std::fs::write(path, r#"{"type": "record", "name": "raw", "fields": []}"#).unwrap();
+        assert!(matches!(read_schema(path), Err(FcError::SchemaError(_))));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_record_schema_dedup() {
+        let schema = record_schema("testcase", "raw", vec![
+            ("image", NDArray::avro_type()),
+            ("mask", NDArray::avro_type()),
+        ]);
+        assert_eq!(schema["fields"][0]["type"], NDArray::avro_type());
+        assert_eq!(schema["fields"][1]["type"], "NDArray");
+    }
+
+    #[test]
+    fn test_diff_schemas() {
+        let old = serde_json::json!({
+            "namespace": "testcase",
+            "type": "record",
+            "name": "raw",
+            "fields": [
+                {"name": "index", "type": "int"},
+                {"name": "name", "type": "string"}
+            ]
+        });
+        let mut new = old.clone();
+        assert!(diff_schemas(&old, &new).is_empty());
+
+        new["fields"] = serde_json::json!([
+            {"name": "index", "type": "long"},
+            {"name": "value", "type": "double"}
+        ]);
+        assert_eq!(diff_schemas(&old, &new), vec![
+            "~ index: \"int\" -> \"long\"",
+            "- name: \"string\"",
+            "+ value: \"double\"",
+        ]);
+    }
+
+    #[test]
+    fn test_schema_registry_with_memory_store() {
+        let store = MemorySchemaStore::new();
+        let mut reader = SchemaRegistry::with_store(Box::new(store.clone()));
+        reader.set_ttl(None);
+        reader.enable_invalidation().unwrap();
+        let mut writer = SchemaRegistry::with_store(Box::new(store));
+
+        let mut schema = serde_json::json!({
+            "namespace": "testcase",
+            "type": "record",
+            "name": "raw",
+            "fields": [{"name": "index", "type": "int"}]
+        });
+        assert!(reader.get("testcase:raw").is_err());
+
+        writer.set("testcase:raw", Some(&schema)).unwrap();
+        assert_eq!(reader.get("testcase:raw").unwrap(), &schema);
+
+        schema["fields"][0]["type"] = serde_json::json!("long");
+        writer.set("testcase:raw", Some(&schema)).unwrap();
+        assert_eq!(reader.get("testcase:raw").unwrap(), &schema);
+
+        assert_eq!(reader.list().unwrap(), vec!["testcase:raw"]);
+        writer.delete("testcase:raw").unwrap();
+        assert!(reader.get("testcase:raw").is_err());
+    }
 }
@@ -0,0 +1,596 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use redis::Commands;
+
+use crate::error::FcResult;
+
+/// Notification of a schema store subscription.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SchemaUpdate<'a> {
+    /// The schema of a stream has been updated
+    Stream(&'a str),
+    /// Updates can no longer be observed until the subscription is restored
+    Lost,
+    /// The subscription has been restored and updates may have been missed
+    Restored,
+}
+
+/// Callback invoked for every notification of a subscription.
+pub type SchemaNotifier = Box<dyn Fn(SchemaUpdate) + Send>;
+
+/// Handle of a subscription to schema updates.
+///
+/// The subscription is stopped and its thread, if any, is joined on drop.
+pub struct Subscription {
+    // dropping the sender stops the subscription
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Subscription {
+    pub fn new(stop: mpsc::Sender<()>, thread: Option<JoinHandle<()>>) -> Self {
+        Subscription {
+            stop: Some(stop),
+            thread,
+        }
+    }
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Whether the `Subscription` owning the sender of a stop channel has been dropped.
+fn is_stopped(stop: &mpsc::Receiver<()>) -> bool {
+    matches!(stop.try_recv(), Err(mpsc::TryRecvError::Disconnected))
+}
+
+/// Storage backend of a `SchemaRegistry`.
+///
+/// Schemas are stored as raw Json strings. An empty string stands for a
+/// schema without fields.
+pub trait SchemaStore {
+    /// Return the names of all the streams which have a registered schema.
+    fn list(&self) -> FcResult<Vec<String>>;
+
+    /// Return the registered versions of the schema of a stream.
+    fn versions(&self, stream: &str) -> FcResult<Vec<String>>;
+
+    /// Return a given version of the schema of a stream.
+    fn get(&self, stream: &str, version: &str) -> FcResult<Option<String>>;
+
+    /// Register a given version of the schema of a stream.
+    fn set(&self, stream: &str, version: &str, schema: &str) -> FcResult<()>;
+
+    /// Delete all the registered versions of the schema of a stream.
+    fn delete(&self, stream: &str) -> FcResult<()>;
+
+    /// Call `notify` whenever a schema is updated through another client,
+    /// until the returned subscription is dropped.
+    ///
+    /// Returns None if the backend does not support update notifications.
+    fn subscribe(&self, _notify: SchemaNotifier) -> FcResult<Option<Subscription>> {
+        Ok(None)
+    }
+}
+
+/// Redis Pub/Sub channel on which the names of updated streams are published.
+pub const SCHEMA_UPDATE_CHANNEL: &str = "_schema_updates";
+
+// interval at which a subscriber checks whether it has been stopped
+const SUBSCRIPTION_POLL_INTERVAL: Duration = Duration::from_millis(100);
+const MIN_RESUBSCRIBE_DELAY: Duration = Duration::from_millis(100);
+const MAX_RESUBSCRIBE_DELAY: Duration = Duration::from_secs(10);
+
+/// Schemas stored in the "<stream>:_schema" hashes in Redis.
+pub struct RedisSchemaStore {
+    client: redis::Client,
+}
+
+impl RedisSchemaStore {
+    pub fn new(host: &str, port: i32) -> Self {
+        let client = redis::Client::open(
+            format!("redis://{}:{}", host, port)).expect(
+            "Failed to open a Redis connection");
+
+        RedisSchemaStore {
+            client,
+        }
+    }
+}
+
+impl SchemaStore for RedisSchemaStore {
+    fn list(&self) -> FcResult<Vec<String>> {
+        let mut con = self.client.get_connection()?;
+
+        let mut streams: Vec<String> = con.scan_match::<_, String>("*:_schema")?
+            .filter_map(|key| key.strip_suffix(":_schema").map(|s| s.to_owned()))
+            .collect();
+        streams.sort();
+        streams.dedup();
+
+        Ok(streams)
+    }
+
+    fn versions(&self, stream: &str) -> FcResult<Vec<String>> {
+        let mut con = self.client.get_connection()?;
+
+        let mut versions: Vec<String> = con.hkeys(stream.to_owned() + ":_schema")?;
+        versions.sort();
+
+        Ok(versions)
+    }
+
+    fn get(&self, stream: &str, version: &str) -> FcResult<Option<String>> {
+        let mut con = self.client.get_connection()?;
+
+        Ok(con.hget(stream.to_owned() + ":_schema", version)?)
+    }
+
+    fn set(&self, stream: &str, version: &str, schema: &str) -> FcResult<()> {
+        let mut con = self.client.get_connection()?;
+
+        let _ : () = con.hset(stream.to_owned() + ":_schema", version, schema)?;
+        let _ : () = con.publish(SCHEMA_UPDATE_CHANNEL, stream)?;
+
+        Ok(())
+    }
+
+    fn delete(&self, stream: &str) -> FcResult<()> {
+        let mut con = self.client.get_connection()?;
+
+        let _ : () = con.del(stream.to_owned() + ":_schema")?;
+        let _ : () = con.publish(SCHEMA_UPDATE_CHANNEL, stream)?;
+
+        Ok(())
+    }
+
+    /// Listen on SCHEMA_UPDATE_CHANNEL in a background thread, which
+    /// subscribes again with an increasing delay if the connection is lost.
+    fn subscribe(&self, notify: SchemaNotifier) -> FcResult<Option<Subscription>> {
+        let client = self.client.clone();
+        let (tx, rx) = mpsc::channel();
+        let (stop_tx, stop_rx) = mpsc::channel();
+
+        let thread = thread::spawn(move || {
+            // the result of the first subscription is returned to the caller
+            let mut first = Some(tx);
+            let mut delay = MIN_RESUBSCRIBE_DELAY;
+            while !is_stopped(&stop_rx) {
+                let ret = (|| -> redis::RedisResult<()> {
+                    let mut con = client.get_connection()?;
+                    con.set_read_timeout(Some(SUBSCRIPTION_POLL_INTERVAL))?;
+                    let mut pubsub = con.as_pubsub();
+                    pubsub.subscribe(SCHEMA_UPDATE_CHANNEL)?;
+                    match first.take() {
+                        Some(tx) => tx.send(Ok(())).unwrap(),
+                        None => notify(SchemaUpdate::Restored),
+                    }
+                    delay = MIN_RESUBSCRIBE_DELAY;
+
+                    while !is_stopped(&stop_rx) {
+                        match pubsub.get_message() {
+                            Ok(msg) => if let Ok(stream) = msg.get_payload::<String>() {
+                                notify(SchemaUpdate::Stream(&stream));
+                            },
+                            Err(e) if e.is_timeout() => (),
+                            Err(e) => return Err(e),
+                        }
+                    }
+                    Ok(())
+                })();
+
+                if let Err(e) = ret {
+                    if let Some(tx) = first.take() {
+                        tx.send(Err(e)).unwrap();
+                        return;
+                    }
+                    notify(SchemaUpdate::Lost);
+                    // returns early once the subscription is dropped
+                    let _ = stop_rx.recv_timeout(delay);
+                    delay = (delay * 2).min(MAX_RESUBSCRIBE_DELAY);
+                }
+            }
+        });
+
+        if let Err(e) = rx.recv().unwrap() {
+            thread.join().unwrap();
+            return Err(e.into());
+        }
+        Ok(Some(Subscription::new(stop_tx, Some(thread))))
+    }
+}
+
+/// Schemas stored as "<root>/<stream>/<version>.json" files.
+pub struct DirSchemaStore {
+    root: PathBuf,
+}
+
+impl DirSchemaStore {
+    pub fn new(root: &str) -> Self {
+        DirSchemaStore {
+            root: PathBuf::from(root),
+        }
+    }
+
+    fn file_path(&self, stream: &str, version: &str) -> PathBuf {
+        self.root.join(stream).join(version.to_owned() + ".json")
+    }
+}
+
+impl SchemaStore for DirSchemaStore {
+    fn list(&self) -> FcResult<Vec<String>> {
+        if !self.root.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut streams = Vec::new();
+        for entry in fs::read_dir(&self.root)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                streams.push(entry.file_name().to_string_lossy().into_owned());
+            }
+        }
+        streams.sort();
+
+        Ok(streams)
+    }
+
+    fn versions(&self, stream: &str) -> FcResult<Vec<String>> {
+        let dir = self.root.join(stream);
+        if !dir.is_dir() {
+            return Ok(Vec::new());
+        }
+
+        let mut versions = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let name = entry?.file_name().to_string_lossy().into_owned();
+            if let Some(version) = name.strip_suffix(".json") {
+                versions.push(version.to_owned());
+            }
+        }
+        versions.sort();
+
+        Ok(versions)
+    }
+
+    fn get(&self, stream: &str, version: &str) -> FcResult<Option<String>> {
+        let path = self.file_path(stream, version);
+        if !path.is_file() {
+            return Ok(None);
+        }
+        Ok(Some(fs::read_to_string(path)?))
+    }
+
+    fn set(&self, stream: &str, version: &str, schema: &str) -> FcResult<()> {
+        fs::create_dir_all(self.root.join(stream))?;
+        fs::write(self.file_path(stream, version), schema)?;
+        Ok(())
+    }
+
+    fn delete(&self, stream: &str) -> FcResult<()> {
+        let dir = self.root.join(stream);
+        if dir.is_dir() {
+            fs::remove_dir_all(dir)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Default)]
+struct MemoryStoreInner {
+    schemas: BTreeMap<String, BTreeMap<String, String>>,
+    notifiers: Vec<(mpsc::Receiver<()>, SchemaNotifier)>,
+}
+
+impl MemoryStoreInner {
+    fn notify(&mut self, stream: &str) {
+        self.notifiers.retain(|(stop, _)| !is_stopped(stop));
+        for (_, notify) in &self.notifiers {
+            notify(SchemaUpdate::Stream(stream));
+        }
+    }
+}
+
+/// Schemas kept in memory, mainly for testing.
+///
+/// Clones share the same schemas, which allows several registries to
+/// observe each other's updates.
+#[derive(Clone, Default)]
+pub struct MemorySchemaStore {
+    inner: Arc<Mutex<MemoryStoreInner>>,
+}
+
+impl MemorySchemaStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl SchemaStore for MemorySchemaStore {
+    fn list(&self) -> FcResult<Vec<String>> {
+        Ok(self.inner.lock().unwrap().schemas.keys().cloned().collect())
+    }
+
+    fn versions(&self, stream: &str) -> FcResult<Vec<String>> {
+        Ok(self.inner.lock().unwrap().schemas.get(stream)
+            .map_or(Vec::new(), |v| v.keys().cloned().collect()))
+    }
+
+    fn get(&self, stream: &str, version: &str) -> FcResult<Option<String>> {
+        Ok(self.inner.lock().unwrap().schemas.get(stream)
+            .and_then(|v| v.get(version).cloned()))
+    }
+
+    fn set(&self, stream: &str, version: &str, schema: &str) -> FcResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.schemas.entry(stream.to_owned()).or_default()
+            .insert(version.to_owned(), schema.to_owned());
+        inner.notify(stream);
+        Ok(())
+    }
+
+    fn delete(&self, stream: &str) -> FcResult<()> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.schemas.remove(stream);
+        inner.notify(stream);
+        Ok(())
+    }
+
+    fn subscribe(&self, notify: SchemaNotifier) -> FcResult<Option<Subscription>> {
+        let (stop_tx, stop_rx) = mpsc::channel();
+        self.inner.lock().unwrap().notifiers.push((stop_rx, notify));
+        Ok(Some(Subscription::new(stop_tx, None)))
+    }
+}
+
+/// Schemas stored in a Confluent-compatible schema registry.
+///
+/// The stream name is used as the subject. Confluent registries assign
+/// versions themselves, therefore the version passed to `set` is ignored
+/// and version "0" refers to the latest version in `get`.
+#[cfg(feature = "confluent")]
+pub struct ConfluentSchemaStore {
+    url: String,
+    agent: ureq::Agent,
+}
+
+#[cfg(feature = "confluent")]
+impl ConfluentSchemaStore {
+    pub fn new(url: &str) -> Self {
+        ConfluentSchemaStore {
+            url: url.trim_end_matches('/').to_owned(),
+            agent: ureq::Agent::new(),
+        }
+    }
+
+    fn request(&self, method: &str, path: &str, body: Option<serde_json::Value>)
+            -> FcResult<Option<serde_json::Value>> {
+        let request = self.agent.request(method, &format!("{}{}", self.url, path))
+            .set("Accept", "application/vnd.schemaregistry.v1+json");
+        let result = match body {
+            Some(b) => request
+                .set("Content-Type", "application/vnd.schemaregistry.v1+json")
+                .send_string(&b.to_string()),
+            None => request.call(),
+        };
+
+        match result {
+            Ok(response) => Ok(Some(serde_json::from_str(&response.into_string()?)?)),
+            Err(ureq::Error::Status(404, _)) => Ok(None),
+            Err(e) => Err(crate::error::FcError::HttpError(e.to_string())),
+        }
+    }
+}
+
+#[cfg(feature = "confluent")]
+impl SchemaStore for ConfluentSchemaStore {
+    fn list(&self) -> FcResult<Vec<String>> {
+        let subjects = self.request("GET", "/subjects", None)?;
+        Ok(subjects.map_or(Ok(Vec::new()), serde_json::from_value)?)
+    }
+
+    fn versions(&self, stream: &str) -> FcResult<Vec<String>> {
+        let versions = self.request("GET", &format!("/subjects/{}/versions", stream), None)?;
+        let versions: Vec<i64> = versions.map_or(Ok(Vec::new()), serde_json::from_value)?;
+        Ok(versions.into_iter().map(|v| v.to_string()).collect())
+    }
+
+    fn get(&self, stream: &str, version: &str) -> FcResult<Option<String>> {
+        let version = if version == "0" { "latest" } else { version };
+        let reply = self.request(
+            "GET", &format!("/subjects/{}/versions/{}", stream, version), None)?;
+        Ok(reply.and_then(|r| r.get("schema").and_then(|s| s.as_str()).map(|s| s.to_owned())))
+    }
+
+    fn set(&self, stream: &str, _version: &str, schema: &str) -> FcResult<()> {
+        self.request("POST", &format!("/subjects/{}/versions", stream),
+                     Some(serde_json::json!({"schema": schema})))?;
+        Ok(())
+    }
+
+    fn delete(&self, stream: &str) -> FcResult<()> {
+        self.request("DELETE", &format!("/subjects/{}", stream), None)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+
+    use std::sync::{Arc, Mutex};
+
+    use crate::schema_store::{DirSchemaStore, MemorySchemaStore, SchemaStore, SchemaUpdate};
+
+    fn check_store(store: &dyn SchemaStore) {
+        store.set("testcase:raw", "0", "{}").unwrap();
+        store.set("testcase:raw", "1", "").unwrap();
+
+        assert_eq!(store.list().unwrap(), vec!["testcase:raw"]);
+        assert_eq!(store.versions("testcase:raw").unwrap(), vec!["0", "1"]);
+        assert_eq!(store.get("testcase:raw", "0").unwrap(), Some("{}".to_string()));
+        assert_eq!(store.get("testcase:raw", "2").unwrap(), None);
+
+        store.delete("testcase:raw").unwrap();
+        assert!(store.list().unwrap().is_empty());
+        assert!(store.versions("testcase:raw").unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_memory_schema_store() {
+        check_store(&MemorySchemaStore::new());
+    }
+
+    #[test]
+    fn test_memory_schema_store_subscription() {
+        let store = MemorySchemaStore::new();
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&updates);
+        let subscription = store.subscribe(Box::new(move |update| {
+            if let SchemaUpdate::Stream(s) = update {
+                sink.lock().unwrap().push(s.to_owned());
+            }
+        })).unwrap();
+
+        store.set("testcase:raw", "0", "").unwrap();
+        drop(subscription);
+        store.delete("testcase:raw").unwrap();
+        assert_eq!(*updates.lock().unwrap(), vec!["testcase:raw"]);
+    }
+
+    #[test]
+    fn test_dir_schema_store() {
+        let root = env::temp_dir().join(format!("foamcore_schema_store_{}", std::process::id()));
+        check_store(&DirSchemaStore::new(root.to_str().unwrap()));
+        let _ = std::fs::remove_dir_all(root);
+    }
+
+    #[cfg(feature = "confluent")]
+    mod confluent {
+        use std::collections::BTreeMap;
+        use std::io::{BufRead, BufReader, Read, Write};
+        use std::net::{TcpListener, TcpStream};
+        use std::thread;
+
+        use serde_json::json;
+
+        use crate::schema_store::{ConfluentSchemaStore, SchemaStore};
+
+        fn read_request(stream: &mut TcpStream) -> (String, String, String) {
+            let mut reader = BufReader::new(stream);
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let mut parts = line.split_whitespace();
+            let method = parts.next().unwrap().to_owned();
+            let path = parts.next().unwrap().to_owned();
+
+            let mut content_length = 0;
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                let header = header.trim();
+                if header.is_empty() {
+                    break;
+                }
+                if let Some((key, value)) = header.split_once(':') {
+                    if key.eq_ignore_ascii_case("content-length") {
+                        content_length = value.trim().parse().unwrap();
+                    }
+                }
+            }
+
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).unwrap();
+            (method, path, String::from_utf8(body).unwrap())
+        }
+
+        /// Serve a minimal subset of the Confluent schema registry API.
+        fn serve(listener: TcpListener) {
+            let mut subjects: BTreeMap<String, Vec<String>> = BTreeMap::new();
+            let not_found = json!({"error_code": 40401, "message": "Subject not found."});
+
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let (method, path, body) = read_request(&mut stream);
+                let segments: Vec<&str> = path.trim_start_matches('/').split('/').collect();
+
+                let (status, reply) = match (method.as_str(), segments.as_slice()) {
+                    ("GET", ["subjects"]) => (200, json!(subjects.keys().collect::<Vec<_>>())),
+                    ("GET", ["subjects", s, "versions"]) => match subjects.get(*s) {
+                        Some(v) => (200, json!((1..=v.len()).collect::<Vec<_>>())),
+                        None => (404, not_found.clone()),
+                    },
+                    ("GET", ["subjects", s, "versions", v]) => {
+                        let schema = subjects.get(*s).and_then(|versions| match *v {
+                            "latest" => versions.last(),
+                            v => v.parse::<usize>().ok()
+                                .and_then(|i| versions.get(i.wrapping_sub(1))),
+                        });
+                        match schema {
+                            Some(schema) => (200, json!({"subject": s, "schema": schema})),
+                            None => (404, not_found.clone()),
+                        }
+                    },
+                    ("POST", ["subjects", s, "versions"]) => {
+                        let body: serde_json::Value = serde_json::from_str(&body).unwrap();
+                        let versions = subjects.entry(s.to_string()).or_default();
+                        versions.push(body["schema"].as_str().unwrap().to_owned());
+                        (200, json!({"id": versions.len()}))
+                    },
+                    ("DELETE", ["subjects", s]) => match subjects.remove(*s) {
+                        Some(v) => (200, json!((1..=v.len()).collect::<Vec<_>>())),
+                        None => (404, not_found.clone()),
+                    },
+                    _ => (404, not_found.clone()),
+                };
+
+                let reply = reply.to_string();
+                write!(stream, "HTTP/1.1 {} OK\r\nContent-Type: application/json\r\n\
+                                Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                       status, reply.len(), reply).unwrap();
+            }
+        }
+
+        #[test]
+        fn test_confluent_schema_store() {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            thread::spawn(move || serve(listener));
+
+            let store = ConfluentSchemaStore::new(&url);
+            assert!(store.list().unwrap().is_empty());
+            assert_eq!(store.get("testcase:raw", "0").unwrap(), None);
+
+            store.set("testcase:raw", "0", "{\"type\": \"int\"}").unwrap();
+            store.set("testcase:raw", "0", "{\"type\": \"long\"}").unwrap();
+
+            assert_eq!(store.list().unwrap(), vec!["testcase:raw"]);
+            assert_eq!(store.versions("testcase:raw").unwrap(), vec!["1", "2"]);
+            assert_eq!(store.get("testcase:raw", "0").unwrap(),
+                       Some("{\"type\": \"long\"}".to_string()));
+            assert_eq!(store.get("testcase:raw", "1").unwrap(),
+                       Some("{\"type\": \"int\"}".to_string()));
+
+            store.delete("testcase:raw").unwrap();
+            assert!(store.list().unwrap().is_empty());
+        }
+    }
+}
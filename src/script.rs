@@ -0,0 +1,259 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use apache_avro::Schema;
+use apache_avro::types::Value;
+use rhai::{Array, Blob, Dynamic, Engine, EvalAltResult, FLOAT, INT, Map, Scope, AST};
+
+use crate::dtype::TypeDescr;
+use crate::json::Names;
+use crate::schema::{Decoded, json_to_avro_schema};
+use crate::error::{FcError, FcResult};
+
+/// Limit of the operations of a single call, which stops endless loops.
+const MAX_OPERATIONS: u64 = 10_000_000;
+
+/// Convert an Avro value into a script value.
+///
+/// Unions are unwrapped, null becomes () and bytes become blobs.
+pub fn value_to_dynamic(value: &Value) -> Dynamic {
+    match value {
+        Value::Null => Dynamic::UNIT,
+        Value::Boolean(b) => (*b).into(),
+        Value::Int(x) | Value::Date(x) | Value::TimeMillis(x) => (*x as INT).into(),
+        Value::Long(x) | Value::TimeMicros(x) | Value::TimestampMillis(x) | Value::TimestampMicros(x) =>
+            (*x as INT).into(),
+        Value::Float(x) => (*x as FLOAT).into(),
+        Value::Double(x) => (*x as FLOAT).into(),
+        Value::Bytes(b) | Value::Fixed(_, b) => Dynamic::from_blob(b.clone()),
+        Value::String(s) | Value::Enum(_, s) => s.clone().into(),
+        Value::Union(_, v) => value_to_dynamic(v),
+        Value::Array(items) => Dynamic::from_array(items.iter().map(value_to_dynamic).collect()),
+        Value::Map(items) => Dynamic::from_map(
+            items.iter().map(|(k, v)| (k.as_str().into(), value_to_dynamic(v))).collect()),
+        Value::Record(fields) => Dynamic::from_map(
+            fields.iter().map(|(k, v)| (k.as_str().into(), value_to_dynamic(v))).collect()),
+        _ => Dynamic::UNIT,
+    }
+}
+
+fn to_decoded_fields(mut map: Map, schema: &Schema, names: &Names, path: &str)
+        -> FcResult<Vec<(String, Value)>> {
+    let rs = match names.resolve(schema)? {
+        Schema::Record(rs) => rs,
+        _ => return Err(FcError::SchemaError(format!("{} is not a record", path))),
+    };
+    rs.fields.iter().map(|field| {
+        let path = format!("{}.{}", path, field.name);
+        let value = map.remove(field.name.as_str()).unwrap_or(Dynamic::UNIT);
+        Ok((field.name.clone(), dynamic_to_value(value, &field.schema, names, &path)?))
+    }).collect()
+}
+
+/// Convert a script value into an Avro value of the given schema.
+pub(crate) fn dynamic_to_value(value: Dynamic, schema: &Schema, names: &Names, path: &str) -> FcResult<Value> {
+    let error = |value: &Dynamic| FcError::SchemaError(format!(
+        "Script returned {} for {} of type {:?}", value.type_name(), path, schema));
+    let as_int = |value: &Dynamic| value.as_int().map_err(|_| error(value));
+    let as_i32 = |value: &Dynamic| as_int(value).and_then(|x| i32::try_from(x).map_err(|_| error(value)));
+    let as_float = |value: &Dynamic| value.as_float()
+        .or_else(|_| value.as_int().map(|x| x as FLOAT)).map_err(|_| error(value));
+
+    Ok(match names.resolve(schema)? {
+        Schema::Null if value.is_unit() => Value::Null,
+        Schema::Boolean => Value::Boolean(value.as_bool().map_err(|_| error(&value))?),
+        Schema::Int => Value::Int(as_i32(&value)?),
+        Schema::Long => Value::Long(as_int(&value)?),
+        Schema::Float => Value::Float(as_float(&value)? as f32),
+        Schema::Double => Value::Double(as_float(&value)?),
+        Schema::Date => Value::Date(as_i32(&value)?),
+        Schema::TimeMillis => Value::TimeMillis(as_i32(&value)?),
+        Schema::TimeMicros => Value::TimeMicros(as_int(&value)?),
+        Schema::TimestampMillis => Value::TimestampMillis(as_int(&value)?),
+        Schema::TimestampMicros => Value::TimestampMicros(as_int(&value)?),
+        Schema::Bytes if value.is_blob() => Value::Bytes(value.cast::<Blob>()),
+        Schema::Fixed(fs) if value.is_blob() => {
+            let bytes = value.clone().cast::<Blob>();
+            if bytes.len() != fs.size {
+                return Err(error(&value));
+            }
+            Value::Fixed(fs.size, bytes)
+        },
+        Schema::String if value.is_string() => Value::String(value.cast::<String>()),
+        Schema::Enum(es) if value.is_string() => {
+            let symbol = value.clone().cast::<String>();
+            let index = es.symbols.iter().position(|s| *s == symbol).ok_or_else(|| error(&value))?;
+            Value::Enum(index as u32, symbol)
+        },
+        Schema::Union(us) => {
+            let mut ret = None;
+            for (i, variant) in us.variants().iter().enumerate() {
+                if let Ok(v) = dynamic_to_value(value.clone(), variant, names, path) {
+                    ret = Some(Value::Union(i as u32, Box::new(v)));
+                    break;
+                }
+            }
+            ret.ok_or_else(|| error(&value))?
+        },
+        Schema::Array(items) if value.is_array() => Value::Array(value.cast::<Array>().into_iter()
+            .map(|x| dynamic_to_value(x, items, names, path)).collect::<FcResult<_>>()?),
+        Schema::Map(values) if value.is_map() => Value::Map(value.cast::<Map>().into_iter()
+            .map(|(k, v)| Ok((k.to_string(), dynamic_to_value(v, values, names, path)?)))
+            .collect::<FcResult<_>>()?),
+        Schema::Record(_) if value.is_map() =>
+            Value::Record(to_decoded_fields(value.cast::<Map>(), schema, names, path)?),
+        _ => return Err(error(&value)),
+    })
+}
+
+/// Return the elements of an ndarray map as floats.
+fn ndarray_values(ndarray: Map) -> Result<Array, Box<EvalAltResult>> {
+    let dtype = ndarray.get("dtype").and_then(|d| d.clone().into_string().ok())
+        .ok_or("ndarray requires 'dtype'")?;
+    let data = ndarray.get("data").filter(|d| d.is_blob()).map(|d| d.clone().cast::<Blob>())
+        .ok_or("ndarray requires 'data'")?;
+    let values = TypeDescr::parse(&dtype).and_then(|d| d.to_f64(&data)).map_err(|e| e.to_string())?;
+    Ok(values.into_iter().map(|x| (x as FLOAT).into()).collect())
+}
+
+/// Runs a Rhai script on each record.
+///
+/// The script must define `fn process(record)`, which receives the record
+/// as a map and returns either the (modified) record, an array of records
+/// or () to drop the record. The returned records must conform to the schema
+/// of the input records. ndarray fields are maps of "shape", "dtype" and
+/// "data" (a blob), and `ndarray_values(x)` returns the elements as floats.
+pub struct ScriptStage {
+    engine: Engine,
+    path: PathBuf,
+    ast: AST,
+    // modification time and size of the compiled script
+    version: (SystemTime, u64),
+    schema: Schema,
+    names: Names,
+}
+
+impl ScriptStage {
+    pub fn new(path: &str, schema: &serde_json::Value) -> FcResult<Self> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        engine.register_fn("ndarray_values", ndarray_values);
+
+        let path = PathBuf::from(path);
+        let version = ScriptStage::version(&path)?;
+        let ast = engine.compile_file(path.clone())?;
+        let schema = json_to_avro_schema(schema);
+
+        Ok(ScriptStage {
+            engine,
+            path,
+            ast,
+            version,
+            names: Names::new(&schema),
+            schema,
+        })
+    }
+
+    fn version(path: &PathBuf) -> FcResult<(SystemTime, u64)> {
+        let metadata = std::fs::metadata(path)?;
+        Ok((metadata.modified()?, metadata.len()))
+    }
+
+    /// Compile the script again if the file has changed and return whether it did.
+    ///
+    /// The previous script is kept if the new one fails to compile.
+    pub fn reload(&mut self) -> FcResult<bool> {
+        let version = ScriptStage::version(&self.path)?;
+        if version == self.version {
+            return Ok(false);
+        }
+        // a broken script is not compiled again until it changes
+        self.version = version;
+        self.ast = self.engine.compile_file(self.path.clone())?;
+        Ok(true)
+    }
+
+    /// Run the script on a record and return the resulting records.
+    pub fn process(&self, datum: Decoded) -> FcResult<Vec<Decoded>> {
+        let record = Dynamic::from_map(
+            datum.iter().map(|(k, v)| (k.as_str().into(), value_to_dynamic(v))).collect());
+        let ret: Dynamic = self.engine.call_fn(&mut Scope::new(), &self.ast, "process", (record,))?;
+
+        let records = if ret.is_unit() {
+            Vec::new()
+        } else if ret.is_array() {
+            ret.cast::<Array>()
+        } else {
+            vec![ret]
+        };
+        records.into_iter().map(|x| {
+            if !x.is_map() {
+                return Err(FcError::SchemaError(format!("Script returned {} instead of a record", x.type_name())));
+            }
+            Ok(to_decoded_fields(x.cast::<Map>(), &self.schema, &self.names, "record")?.into_iter().collect())
+        }).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apache_avro::types::Value;
+
+    use crate::schema::Decoded;
+    use crate::script::ScriptStage;
+
+    #[test]
+    fn test_script_stage() {
+        let schema = serde_json::json!({
+            "type": "record",
+            "name": "script_test",
+            "fields": [
+                {"name": "index", "type": "long"},
+                {"name": "energy", "type": "double"},
+                {"name": "label", "type": ["null", "string"]}
+            ]
+        });
+        let path = std::env::temp_dir().join(format!("script_test_{}.rhai", std::process::id()));
+        std::fs::write(&path, r#"
+            fn process(record) {
+                if record.index < 0 { return (); }
+                record.energy = record.energy * 1000.0;
+                if record.index == 2 { record.label = "double"; return [record, record]; }
+                record
+            }
+        "#).unwrap();
+        let mut stage = ScriptStage::new(path.to_str().unwrap(), &schema).unwrap();
+
+        let record = |index: i64| Decoded::from([
+            ("index".to_string(), Value::Long(index)),
+            ("energy".to_string(), Value::Double(1.5)),
+            ("label".to_string(), Value::Union(0, Box::new(Value::Null))),
+        ]);
+        assert!(stage.process(record(-1)).unwrap().is_empty());
+        let processed = stage.process(record(1)).unwrap();
+        assert_eq!(processed[0]["energy"], Value::Double(1500.));
+        assert_eq!(processed[0]["label"], Value::Union(0, Box::new(Value::Null)));
+        let processed = stage.process(record(2)).unwrap();
+        assert_eq!(processed.len(), 2);
+        assert_eq!(processed[1]["label"], Value::Union(1, Box::new(Value::String("double".to_string()))));
+
+        assert!(!stage.reload().unwrap());
+        std::fs::write(&path, "fn process(record) { record.energy = \"high\"; record }").unwrap();
+        assert!(stage.reload().unwrap());
+        assert!(stage.process(record(1)).is_err());
+
+        // the previous script is kept if the new one is broken
+        std::fs::write(&path, "fn process(record) {").unwrap();
+        assert!(stage.reload().is_err());
+        assert!(stage.process(record(1)).is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
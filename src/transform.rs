@@ -0,0 +1,382 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::collections::{HashMap, HashSet};
+
+use apache_avro::types::Value;
+use serde::Deserialize;
+
+use crate::dtype::{DType, TypeDescr};
+use crate::schema::{Decoded, NDArray, AvroType, expand_named_types, record_schema};
+use crate::error::{FcError, FcResult};
+
+/// A step of the transform stage, e.g. {"op": "sum", "field": "image", "output": "image_sum"}.
+///
+/// Derived fields are added to the record and the ndarray fields they are
+/// computed from are kept unless they are dropped explicitly.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum Transform {
+    /// Keep only the given fields, in the given order
+    Select { fields: Vec<String> },
+    Drop { fields: Vec<String> },
+    Rename { field: String, output: String },
+    /// Sum of an ndarray field
+    Sum { field: String, output: String },
+    /// Mean of an ndarray field
+    Mean { field: String, output: String },
+    /// Crop [x, y, width, height] of the last two dimensions of an ndarray field
+    Roi { field: String, output: String, roi: [usize; 4] },
+    /// Average [rows, columns] blocks of the last two dimensions of an ndarray field
+    Bin { field: String, output: String, factor: [usize; 2] },
+}
+
+fn field_error(field: &str) -> FcError {
+    FcError::SchemaError(format!("Unknown field in transform: {}", field))
+}
+
+/// Return whether a Json type is a nullable ndarray, or None if it is not an ndarray.
+fn ndarray_nullable(t: &serde_json::Value) -> Option<bool> {
+    let is_ndarray = |t: &serde_json::Value| t["type"] == "record" && t["fields"].as_array().is_some_and(
+        |fields| fields.len() == 3 && ["shape", "dtype", "data"].iter().all(
+            |n| fields.iter().any(|f| f["name"] == *n)));
+    match t {
+        serde_json::Value::Array(union) => match &union[..] {
+            [n, x] | [x, n] if *n == "null" && is_ndarray(x) => Some(true),
+            _ => None,
+        },
+        _ if is_ndarray(t) => Some(false),
+        _ => None,
+    }
+}
+
+fn nullable_type(t: serde_json::Value, nullable: bool) -> serde_json::Value {
+    if nullable { serde_json::json!(["null", t]) } else { t }
+}
+
+/// Wrap a derived value in the union of a nullable output field.
+fn wrap(value: Option<Value>, nullable: bool) -> Value {
+    match (value, nullable) {
+        (Some(v), true) => Value::Union(1, Box::new(v)),
+        (Some(v), false) => v,
+        (None, _) => Value::Union(0, Box::new(Value::Null)),
+    }
+}
+
+/// An ndarray with the last two dimensions split off.
+struct Image<'a> {
+    shape: Vec<usize>,
+    descr: TypeDescr,
+    dtype: &'a str,
+    data: &'a [u8],
+}
+
+impl<'a> Image<'a> {
+    /// Parse an ndarray record. Returns None for null.
+    fn parse(value: &'a Value, field: &str) -> FcResult<Option<Self>> {
+        let invalid = || FcError::SchemaError(format!("Field {} is not a valid ndarray", field));
+        let fields = match value {
+            Value::Union(_, v) => return Image::parse(v, field),
+            Value::Null => return Ok(None),
+            Value::Record(fields) => fields,
+            _ => return Err(invalid()),
+        };
+        let get = |name: &str| fields.iter().find(|(k, _)| k == name).map(|(_, v)| v);
+        let shape = match get("shape") {
+            Some(Value::Array(dims)) => dims.iter().map(|d| match d {
+                Value::Int(x) if *x >= 0 => Ok(*x as usize),
+                _ => Err(invalid()),
+            }).collect::<FcResult<Vec<_>>>()?,
+            _ => return Err(invalid()),
+        };
+        let (dtype, data) = match (get("dtype"), get("data")) {
+            (Some(Value::String(d)), Some(Value::Bytes(b))) => (d.as_str(), b.as_slice()),
+            _ => return Err(invalid()),
+        };
+        let descr = TypeDescr::parse(dtype)?;
+        let nbytes = shape.iter().try_fold(descr.itemsize(), |n, &x| n.checked_mul(x));
+        if nbytes != Some(data.len()) {
+            return Err(invalid());
+        }
+        Ok(Some(Image { shape, descr, dtype, data }))
+    }
+
+    fn values(&self) -> FcResult<Vec<f64>> {
+        self.descr.to_f64(self.data)
+    }
+
+    /// Return (number of frames, rows, columns).
+    fn frames(&self, field: &str) -> FcResult<(usize, usize, usize)> {
+        match self.shape[..] {
+            [ref leading @ .., rows, cols] => Ok((leading.iter().product(), rows, cols)),
+            _ => Err(FcError::SchemaError(format!("Field {} must have at least two dimensions", field))),
+        }
+    }
+
+    fn with_shape(&self, rows: usize, cols: usize) -> Vec<Value> {
+        let n = self.shape.len();
+        self.shape[..n - 2].iter().chain([rows, cols].iter())
+            .map(|x| Value::Int(*x as i32)).collect()
+    }
+
+    fn roi(&self, field: &str, [x, y, width, height]: [usize; 4]) -> FcResult<Value> {
+        let (frames, rows, cols) = self.frames(field)?;
+        // an overflowing end is out of bounds
+        if x.checked_add(width).is_none_or(|end| end > cols) || y.checked_add(height).is_none_or(|end| end > rows) {
+            return Err(FcError::SchemaError(format!(
+                "ROI {:?} is out of the bounds of field {} ({} x {})", [x, y, width, height], field, rows, cols)));
+        }
+        let itemsize = self.descr.itemsize();
+        let mut data = Vec::with_capacity(frames * width * height * itemsize);
+        for frame in 0..frames {
+            for row in y..y + height {
+                let start = ((frame * rows + row) * cols + x) * itemsize;
+                data.extend_from_slice(&self.data[start..start + width * itemsize]);
+            }
+        }
+        Ok(Value::Record(vec![
+            ("shape".to_string(), Value::Array(self.with_shape(height, width))),
+            ("dtype".to_string(), Value::String(self.dtype.to_owned())),
+            ("data".to_string(), Value::Bytes(data)),
+        ]))
+    }
+
+    fn bin(&self, field: &str, [fy, fx]: [usize; 2]) -> FcResult<Value> {
+        let (frames, rows, cols) = self.frames(field)?;
+        if fy == 0 || fx == 0 {
+            return Err(FcError::SchemaError(format!("Invalid binning factor for field {}", field)));
+        }
+        // the remaining rows and columns are discarded
+        let (out_rows, out_cols) = (rows / fy, cols / fx);
+        let values = self.values()?;
+        let mut binned = vec![0.; frames * out_rows * out_cols];
+        for frame in 0..frames {
+            for row in 0..out_rows * fy {
+                for col in 0..out_cols * fx {
+                    binned[(frame * out_rows + row / fy) * out_cols + col / fx] +=
+                        values[(frame * rows + row) * cols + col];
+                }
+            }
+        }
+        let n = (fy * fx) as f64;
+        binned.iter_mut().for_each(|x| *x /= n);
+
+        let descr = TypeDescr { dtype: DType::Float64, big_endian: false };
+        Ok(Value::Record(vec![
+            ("shape".to_string(), Value::Array(self.with_shape(out_rows, out_cols))),
+            ("dtype".to_string(), Value::String("<f8".to_string())),
+            ("data".to_string(), Value::Bytes(descr.from_f64(&binned)?)),
+        ]))
+    }
+}
+
+/// Selects, renames and drops fields and derives new fields from ndarray
+/// fields. The output schema is generated from the input schema.
+pub struct Transformer {
+    transforms: Vec<Transform>,
+    // whether the output of each transform is nullable
+    nullable: Vec<bool>,
+    schema: serde_json::Value,
+}
+
+impl Transformer {
+    pub fn new(schema: &serde_json::Value, transforms: Vec<Transform>) -> FcResult<Self> {
+        let namespace = schema["namespace"].as_str().unwrap_or("");
+        let name = schema["name"].as_str().ok_or_else(
+            || FcError::SchemaError("Transform requires a named record schema".to_string()))?;
+
+        // field types must be complete since the fields defining named types may be dropped
+        let mut expanded = schema.clone();
+        expand_named_types(&mut expanded, None, &mut HashMap::new());
+        let mut fields: Vec<(String, serde_json::Value)> = expanded["fields"].as_array()
+            .ok_or_else(|| FcError::SchemaError("Transform requires a record schema".to_string()))?
+            .iter().map(|f| (f["name"].as_str().unwrap_or_default().to_owned(), f["type"].clone())).collect();
+
+        let position = |fields: &[(String, serde_json::Value)], field: &str| {
+            fields.iter().position(|(n, _)| n == field).ok_or_else(|| field_error(field))
+        };
+        let mut nullable = Vec::with_capacity(transforms.len());
+        for transform in &transforms {
+            let mut is_nullable = false;
+            match transform {
+                Transform::Select { fields: selected } => {
+                    let mut seen = HashSet::new();
+                    if let Some(f) = selected.iter().find(|f| !seen.insert(*f)) {
+                        return Err(FcError::SchemaError(format!("Field selected more than once: {}", f)));
+                    }
+                    fields = selected.iter().map(|f| Ok(fields[position(&fields, f)?].clone()))
+                        .collect::<FcResult<_>>()?;
+                },
+                Transform::Drop { fields: dropped } => {
+                    for f in dropped {
+                        fields.remove(position(&fields, f)?);
+                    }
+                },
+                Transform::Rename { field, output } => {
+                    let i = position(&fields, field)?;
+                    if output != field && position(&fields, output).is_ok() {
+                        return Err(FcError::SchemaError(format!(
+                            "Cannot rename field {} to the existing field {}", field, output)));
+                    }
+                    fields[i].0 = output.clone();
+                },
+                Transform::Sum { field, output } | Transform::Mean { field, output } |
+                Transform::Roi { field, output, .. } | Transform::Bin { field, output, .. } => {
+                    let t = &fields[position(&fields, field)?].1;
+                    is_nullable = ndarray_nullable(t).ok_or_else(
+                        || FcError::SchemaError(format!("Field {} is not an ndarray", field)))?;
+                    let t = match transform {
+                        Transform::Sum { .. } | Transform::Mean { .. } => serde_json::json!("double"),
+                        _ => NDArray::avro_type(),
+                    };
+                    if let Ok(i) = position(&fields, output) {
+                        fields.remove(i);
+                    }
+                    fields.push((output.clone(), nullable_type(t, is_nullable)));
+                },
+            }
+            nullable.push(is_nullable);
+        }
+
+        let schema = record_schema(
+            namespace, name, fields.iter().map(|(n, t)| (n.as_str(), t.clone())).collect());
+        Ok(Transformer { transforms, nullable, schema })
+    }
+
+    /// Load the transforms from a Json file with a list of transforms.
+    pub fn from_file(schema: &serde_json::Value, path: &str) -> FcResult<Self> {
+        let transforms = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+        Transformer::new(schema, transforms)
+    }
+
+    /// Schema of the transformed records.
+    pub fn schema(&self) -> &serde_json::Value {
+        &self.schema
+    }
+
+    pub fn apply(&self, mut datum: Decoded) -> FcResult<Decoded> {
+        for (transform, &nullable) in self.transforms.iter().zip(&self.nullable) {
+            match transform {
+                Transform::Select { fields } => {
+                    datum = fields.iter().map(|f| datum.remove_entry(f).ok_or_else(|| field_error(f)))
+                        .collect::<FcResult<_>>()?;
+                },
+                Transform::Drop { fields } => {
+                    for f in fields {
+                        datum.remove(f);
+                    }
+                },
+                Transform::Rename { field, output } => {
+                    let value = datum.remove(field).ok_or_else(|| field_error(field))?;
+                    datum.insert(output.clone(), value);
+                },
+                Transform::Sum { field, output } | Transform::Mean { field, output } |
+                Transform::Roi { field, output, .. } | Transform::Bin { field, output, .. } => {
+                    let image = Image::parse(datum.get(field).ok_or_else(|| field_error(field))?, field)?;
+                    let value = match (image, transform) {
+                        (None, _) => None,
+                        (Some(image), Transform::Sum { .. }) =>
+                            Some(Value::Double(image.values()?.iter().sum())),
+                        (Some(image), Transform::Mean { .. }) => {
+                            let values = image.values()?;
+                            Some(Value::Double(values.iter().sum::<f64>() / values.len() as f64))
+                        },
+                        (Some(image), Transform::Roi { roi, .. }) => Some(image.roi(field, *roi)?),
+                        (Some(image), Transform::Bin { factor, .. }) => Some(image.bin(field, *factor)?),
+                        _ => unreachable!(),
+                    };
+                    datum.insert(output.clone(), wrap(value, nullable));
+                },
+            }
+        }
+        Ok(datum)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apache_avro::types::Value;
+
+    use crate::dtype::TypeDescr;
+    use crate::schema::{Decoded, NDArray, AvroType, json_to_avro_schema, record_schema};
+    use crate::transform::{Transform, Transformer};
+
+    fn image(shape: Vec<i32>, values: &[f64]) -> Value {
+        Value::Record(vec![
+            ("shape".to_string(), Value::Array(shape.into_iter().map(Value::Int).collect())),
+            ("dtype".to_string(), Value::String("<i2".to_string())),
+            ("data".to_string(), Value::Bytes(TypeDescr::parse("<i2").unwrap().from_f64(values).unwrap())),
+        ])
+    }
+
+    #[test]
+    fn test_transformer() {
+        let schema = record_schema("transform_test", "raw", vec![
+            ("pulse_id", serde_json::json!("long")),
+            ("image", NDArray::avro_type()),
+            ("dark", serde_json::json!(["null", NDArray::avro_type()])),
+        ]);
+        let transforms: Vec<Transform> = serde_json::from_value(serde_json::json!([
+            {"op": "sum", "field": "image", "output": "image_sum"},
+            {"op": "mean", "field": "dark", "output": "dark_mean"},
+            {"op": "roi", "field": "image", "output": "roi", "roi": [1, 0, 2, 2]},
+            {"op": "bin", "field": "image", "output": "binned", "factor": [2, 2]},
+            {"op": "drop", "fields": ["image", "dark"]},
+            {"op": "rename", "field": "pulse_id", "output": "pid"}
+        ])).unwrap();
+        let transformer = Transformer::new(&schema, transforms).unwrap();
+
+        // the NDArray type is defined by the dropped field
+        let output = transformer.schema();
+        json_to_avro_schema(output);
+        let names: Vec<_> = output["fields"].as_array().unwrap().iter().map(|f| &f["name"]).collect();
+        assert_eq!(names, ["pid", "image_sum", "dark_mean", "roi", "binned"]);
+
+        let datum = Decoded::from([
+            ("pulse_id".to_string(), Value::Long(1)),
+            ("image".to_string(), image(vec![2, 3], &[1., 2., 3., 4., 5., 6.])),
+            ("dark".to_string(), Value::Union(0, Box::new(Value::Null))),
+        ]);
+        let transformed = transformer.apply(datum).unwrap();
+        assert_eq!(transformed.len(), 5);
+        assert_eq!(transformed["pid"], Value::Long(1));
+        assert_eq!(transformed["image_sum"], Value::Double(21.));
+        assert_eq!(transformed["dark_mean"], Value::Union(0, Box::new(Value::Null)));
+        assert_eq!(transformed["roi"], image(vec![2, 2], &[2., 3., 5., 6.]));
+        assert_eq!(transformed["binned"], Value::Record(vec![
+            ("shape".to_string(), Value::Array(vec![Value::Int(1), Value::Int(1)])),
+            ("dtype".to_string(), Value::String("<f8".to_string())),
+            ("data".to_string(), Value::Bytes(3f64.to_le_bytes().to_vec())),
+        ]));
+
+        let invalid = vec![Transform::Sum { field: "pulse_id".to_string(), output: "x".to_string() }];
+        assert!(Transformer::new(&schema, invalid).is_err());
+        let invalid = vec![Transform::Rename { field: "pulse_id".to_string(), output: "image".to_string() }];
+        assert!(Transformer::new(&schema, invalid).is_err());
+        let invalid = vec![Transform::Select { fields: vec!["pulse_id".to_string(), "pulse_id".to_string()] }];
+        assert!(Transformer::new(&schema, invalid).is_err());
+
+        // the end of the ROI overflows
+        let roi = vec![Transform::Roi {
+            field: "image".to_string(), output: "roi".to_string(), roi: [usize::MAX, 0, 2, 2],
+        }];
+        let datum = Decoded::from([("image".to_string(), image(vec![2, 3], &[1., 2., 3., 4., 5., 6.]))]);
+        assert!(Transformer::new(&schema, roi).unwrap().apply(datum).is_err());
+
+        // the size of the shape overflows
+        let datum = Decoded::from([
+            ("pulse_id".to_string(), Value::Long(1)),
+            ("image".to_string(), Value::Record(vec![
+                ("shape".to_string(), Value::Array(vec![Value::Int(i32::MAX); 4])),
+                ("dtype".to_string(), Value::String("<i2".to_string())),
+                ("data".to_string(), Value::Bytes(Vec::new())),
+            ])),
+            ("dark".to_string(), Value::Union(0, Box::new(Value::Null))),
+        ]);
+        assert!(transformer.apply(datum).is_err());
+    }
+}
@@ -0,0 +1,243 @@
+/**
+ * Distributed under the terms of the BSD 3-Clause License.
+ *
+ * The full license is in the file LICENSE, distributed with this software.
+ *
+ * Author: Jun Zhu
+ */
+use std::sync::Mutex;
+
+use wasmtime::{Config, Engine, Instance, Linker, Memory, Module, Store, StoreLimits, StoreLimitsBuilder, TypedFunc};
+
+use crate::decoder::Decoder;
+use crate::json::json_to_decoded;
+use crate::schema::{Decoded, Encoded, json_to_avro_schema};
+use crate::error::{FcError, FcResult};
+
+fn wasm_error<E: std::fmt::Display>(e: E) -> FcError {
+    FcError::WasmError(e.to_string())
+}
+
+/// Resource limits of a WebAssembly decoder.
+#[derive(Clone, Debug)]
+pub struct WasmLimits {
+    /// Maximum size of the linear memory in bytes
+    pub memory: usize,
+    /// Fuel available for decoding a single message, roughly the number of
+    /// executed instructions
+    pub fuel: u64,
+}
+
+impl Default for WasmLimits {
+    fn default() -> Self {
+        WasmLimits {
+            memory: 256 << 20,
+            fuel: 10_000_000_000,
+        }
+    }
+}
+
+struct WasmInstance {
+    store: Store<StoreLimits>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    decode: TypedFunc<(i32, i32), i64>,
+    dealloc: Option<TypedFunc<(i32, i32), ()>>,
+}
+
+impl WasmInstance {
+    fn new(engine: &Engine, module: &Module, limits: &WasmLimits) -> FcResult<Self> {
+        let mut store = Store::new(engine, StoreLimitsBuilder::new()
+            .memory_size(limits.memory)
+            .instances(1)
+            .build());
+        store.limiter(|limits| limits);
+
+        // no host functions are provided, so the module cannot access anything
+        // but its own memory
+        let instance: Instance = Linker::new(engine).instantiate(&mut store, module).map_err(wasm_error)?;
+        let memory = instance.get_memory(&mut store, "memory").ok_or_else(
+            || FcError::WasmError("Module does not export 'memory'".to_string()))?;
+        let alloc = instance.get_typed_func(&mut store, "alloc").map_err(wasm_error)?;
+        let decode = instance.get_typed_func(&mut store, "decode").map_err(wasm_error)?;
+        let dealloc = instance.get_typed_func(&mut store, "dealloc").ok();
+
+        Ok(WasmInstance { store, memory, alloc, decode, dealloc })
+    }
+
+    fn call(&mut self, bytes: &[u8], fuel: u64) -> FcResult<Vec<u8>> {
+        self.store.set_fuel(fuel).map_err(wasm_error)?;
+
+        let len = i32::try_from(bytes.len()).map_err(wasm_error)?;
+        let ptr = self.alloc.call(&mut self.store, len).map_err(wasm_error)?;
+        self.memory.write(&mut self.store, ptr as u32 as usize, bytes).map_err(wasm_error)?;
+
+        let ret = self.decode.call(&mut self.store, (ptr, len)).map_err(wasm_error)?;
+        if ret < 0 {
+            return Err(FcError::CodecError(format!("WebAssembly decoder failed with code {}", ret)));
+        }
+        let (out_ptr, out_len) = ((ret >> 32) as u32, ret as u32);
+        // the output is bounds-checked before it is copied
+        let start = out_ptr as usize;
+        let end = start + out_len as usize;
+        let output = self.memory.data(&self.store).get(start..end).ok_or_else(|| FcError::WasmError(format!(
+            "Output buffer {}..{} is out of the linear memory of {} bytes",
+            start, end, self.memory.data_size(&self.store))))?.to_vec();
+
+        if let Some(dealloc) = &self.dealloc {
+            dealloc.call(&mut self.store, (ptr, len)).map_err(wasm_error)?;
+            dealloc.call(&mut self.store, (out_ptr as i32, out_len as i32)).map_err(wasm_error)?;
+        }
+        Ok(output)
+    }
+}
+
+/// Decoder implemented by a WebAssembly module, e.g. for vendor formats.
+///
+/// The module must export:
+///
+/// - `memory`;
+/// - `alloc(len: i32) -> i32`, which returns a buffer for the input;
+/// - `decode(ptr: i32, len: i32) -> i64`, which returns the output buffer as
+///   `ptr << 32 | len`, or a negative error code. The output is a Json object
+///   or array of objects conforming to the schema;
+/// - optionally `dealloc(ptr: i32, len: i32)`, which is called for the input
+///   and output buffers after each message.
+///
+/// The module runs without any imports, with a limited memory and a limited
+/// amount of fuel per message. It is instantiated again after a trap.
+pub struct WasmDecoder {
+    schema: apache_avro::Schema,
+    engine: Engine,
+    module: Module,
+    limits: WasmLimits,
+    instance: Mutex<Option<WasmInstance>>,
+}
+
+impl WasmDecoder {
+    pub fn new(path: &str, schema: &serde_json::Value, limits: WasmLimits) -> FcResult<Self> {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config).map_err(wasm_error)?;
+        let module = Module::from_file(&engine, path).map_err(wasm_error)?;
+        let instance = WasmInstance::new(&engine, &module, &limits)?;
+
+        Ok(WasmDecoder {
+            schema: json_to_avro_schema(schema),
+            engine,
+            module,
+            limits,
+            instance: Mutex::new(Some(instance)),
+        })
+    }
+}
+
+impl Decoder for WasmDecoder {
+    fn unpack(&self, bytes: &Encoded) -> FcResult<Vec<Decoded>> {
+        let mut guard = self.instance.lock().unwrap();
+        if guard.is_none() {
+            *guard = Some(WasmInstance::new(&self.engine, &self.module, &self.limits)?);
+        }
+        let output = match guard.as_mut().unwrap().call(bytes, self.limits.fuel) {
+            Ok(x) => x,
+            Err(e) => {
+                // the state of the module is unknown after a trap
+                if let FcError::WasmError(_) = e {
+                    *guard = None;
+                }
+                return Err(e);
+            },
+        };
+        drop(guard);
+
+        let value: serde_json::Value = serde_json::from_slice(&output)?;
+        match value {
+            serde_json::Value::Array(items) => {
+                items.iter().map(|x| json_to_decoded(x, &self.schema)).collect()
+            },
+            _ => Ok(vec![json_to_decoded(&value, &self.schema)?]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use apache_avro::types::Value;
+
+    use crate::decoder::Decoder;
+    use crate::schema::Decoded;
+    use crate::wasm_decoder::{WasmDecoder, WasmLimits};
+
+    // Returns the input as the output, with a bump allocator.
+    const ECHO_WAT: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (global $next (mut i32) (i32.const 1024))
+          (func (export "alloc") (param $len i32) (result i32)
+            (local $ptr i32)
+            (local.set $ptr (global.get $next))
+            (global.set $next (i32.add (global.get $next) (local.get $len)))
+            (block $done
+              (loop $grow
+                (br_if $done (i32.le_u (global.get $next) (i32.mul (memory.size) (i32.const 65536))))
+                (br_if $done (i32.eq (memory.grow (i32.const 1)) (i32.const -1)))
+                (br $grow)))
+            (local.get $ptr))
+          (func (export "decode") (param $ptr i32) (param $len i32) (result i64)
+            (i64.or (i64.shl (i64.extend_i32_u (local.get $ptr)) (i64.const 32))
+                    (i64.extend_i32_u (local.get $len))))
+          (func (export "dealloc") (param $ptr i32) (param $len i32)
+            (global.set $next (i32.const 1024))))
+    "#;
+
+    #[test]
+    fn test_wasm_decoder() {
+        let schema = serde_json::json!({
+            "type": "record",
+            "name": "wasm_test",
+            "fields": [{"name": "index", "type": "long"}]
+        });
+        let path = std::env::temp_dir().join(format!("wasm_test_{}.wat", std::process::id()));
+        std::fs::write(&path, ECHO_WAT).unwrap();
+
+        let limits = WasmLimits { memory: 2 << 16, fuel: 1_000_000 };
+        let decoder = WasmDecoder::new(path.to_str().unwrap(), &schema, limits).unwrap();
+        let decoded = decoder.unpack(&br#"[{"index": 1}, {"index": 2}]"#.to_vec()).unwrap();
+        assert_eq!(decoded[1], Decoded::from([("index".to_string(), Value::Long(2))]));
+
+        // the input does not fit into the memory limit of two pages
+        let large = format!(r#"{{"index": 1, "padding": "{}"}}"#, "x".repeat(200_000));
+        assert!(decoder.unpack(&large.into_bytes()).is_err());
+        // a new instance is created after the failure
+        assert_eq!(decoder.unpack(&br#"{"index": 3}"#.to_vec()).unwrap().len(), 1);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    // Returns an output buffer which is much larger than the memory.
+    const OUT_OF_RANGE_WAT: &str = r#"
+        (module
+          (memory (export "memory") 1)
+          (func (export "alloc") (param $len i32) (result i32)
+            (i32.const 1024))
+          (func (export "decode") (param $ptr i32) (param $len i32) (result i64)
+            (i64.const 0xFFFFFFFF)))
+    "#;
+
+    #[test]
+    fn test_wasm_decoder_out_of_range_output() {
+        let schema = serde_json::json!({
+            "type": "record",
+            "name": "wasm_test",
+            "fields": [{"name": "index", "type": "long"}]
+        });
+        let path = std::env::temp_dir().join(format!("wasm_test_range_{}.wat", std::process::id()));
+        std::fs::write(&path, OUT_OF_RANGE_WAT).unwrap();
+
+        let decoder = WasmDecoder::new(path.to_str().unwrap(), &schema, WasmLimits::default()).unwrap();
+        let err = decoder.unpack(&br#"{"index": 1}"#.to_vec()).unwrap_err();
+        assert!(err.to_string().contains("out of the linear memory"), "{}", err);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -5,20 +5,177 @@
  *
  * Author: Jun Zhu
  */
+use std::cell::{Cell, RefCell};
+use std::marker::PhantomData;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use apache_avro::types::Value;
+use serde::de::DeserializeOwned;
+
 use crate::decoder::{create_decoder, Decoder};
 use crate::encoder::{create_encoder, Encoder};
-use crate::schema::{Decoded, Encoded};
-use crate::error::{FcResult};
+use crate::schema::{Decoded, Encoded, from_decoded};
+use crate::error::{FcError, FcResult};
+
+/// Options applied to a ZeroMQ socket before it connects or binds.
+///
+/// Options which are None keep the defaults of libzmq.
+#[derive(Clone, Debug, Default)]
+pub struct ZmqSocketOptions {
+    /// Maximum number of messages queued for sending and receiving. Further
+    /// messages are dropped (SUB) or block the sender (PUSH)
+    pub hwm: Option<i32>,
+    /// Milliseconds pending messages are kept after the socket is closed
+    pub linger: Option<i32>,
+    /// Milliseconds a receive waits for a message
+    pub receive_timeout: Option<i32>,
+    /// Size of the kernel send and receive buffers in bytes
+    pub buffer_size: Option<i32>,
+    /// Milliseconds between attempts to reconnect
+    pub reconnect_interval: Option<i32>,
+    /// Enable TCP keepalive probes
+    pub tcp_keepalive: Option<bool>,
+    /// Keep only the latest message in the queue
+    pub conflate: bool,
+    /// Attach a monitor which reports connection events (ZmqConsumer only)
+    pub monitor: bool,
+}
+
+impl ZmqSocketOptions {
+    fn apply(&self, socket: &zmq::Socket) -> zmq::Result<()> {
+        if let Some(hwm) = self.hwm {
+            socket.set_sndhwm(hwm)?;
+            socket.set_rcvhwm(hwm)?;
+        }
+        if let Some(linger) = self.linger {
+            socket.set_linger(linger)?;
+        }
+        if let Some(timeout) = self.receive_timeout {
+            socket.set_rcvtimeo(timeout)?;
+        }
+        if let Some(size) = self.buffer_size {
+            socket.set_sndbuf(size)?;
+            socket.set_rcvbuf(size)?;
+        }
+        if let Some(interval) = self.reconnect_interval {
+            socket.set_reconnect_ivl(interval)?;
+        }
+        if let Some(keepalive) = self.tcp_keepalive {
+            socket.set_tcp_keepalive(if keepalive { 1 } else { 0 })?;
+        }
+        if self.conflate {
+            socket.set_conflate(true)?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts the messages missing from a sequence number field.
+struct SequenceTracker {
+    field: String,
+    last: Option<i64>,
+    dropped: u64,
+}
+
+impl SequenceTracker {
+    fn update(&mut self, decoded: &Decoded) {
+        let seq = match decoded.get(&self.field) {
+            Some(Value::Int(x)) => *x as i64,
+            Some(Value::Long(x)) => *x,
+            Some(Value::Union(_, v)) => match v.as_ref() {
+                Value::Int(x) => *x as i64,
+                Value::Long(x) => *x,
+                _ => return,
+            },
+            _ => return,
+        };
+        // a smaller number means that the sender has restarted
+        if let Some(last) = self.last.filter(|last| seq > *last) {
+            self.dropped += (seq - last - 1) as u64;
+        }
+        self.last = Some(seq);
+    }
+}
+
+/// Event reported by `ZmqConsumer::events`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionEvent {
+    Connected(String),
+    Disconnected(String),
+    /// A reconnection attempt has been scheduled
+    Retried(String),
+    /// No message has arrived within the watchdog timeout
+    NoData(Duration),
+}
+
+/// State of a ZmqConsumer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConsumerStatus {
+    pub endpoint: String,
+    /// None if the socket is not monitored
+    pub connected: Option<bool>,
+    pub received: u64,
+    pub dropped: u64,
+    /// Time since the last message, or since the creation if none has arrived
+    pub idle: Duration,
+    /// Whether the watchdog timeout has expired
+    pub stale: bool,
+}
+
+impl ConsumerStatus {
+    /// Return the status as (field, value) pairs, e.g. for a Redis hash.
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        vec![
+            ("endpoint", self.endpoint.clone()),
+            ("connected", self.connected.map_or("unknown".to_string(), |x| x.to_string())),
+            ("received", self.received.to_string()),
+            ("dropped", self.dropped.to_string()),
+            ("idle", format!("{:.3}", self.idle.as_secs_f64())),
+            ("live", (!self.stale).to_string()),
+        ]
+    }
+}
+
+/// Used for unique addresses of the monitor sockets.
+static MONITOR_COUNT: AtomicUsize = AtomicUsize::new(0);
 
 pub struct ZmqConsumer {
     socket: zmq::Socket,
     decoder: Option<Box<dyn Decoder>>,
+    sequence: RefCell<Option<SequenceTracker>>,
+    endpoint: String,
+    monitor: Option<zmq::Socket>,
+    connected: Cell<Option<bool>>,
+    received: Cell<u64>,
+    last_received: Cell<Instant>,
+    watchdog: Option<Duration>,
+    // whether NoData has been reported since the last message
+    idle_reported: Cell<bool>,
 }
 
 impl ZmqConsumer {
     pub fn new(endpoint: &str, sock_type: zmq::SocketType) -> Self {
+        ZmqConsumer::with_options(endpoint, sock_type, &ZmqSocketOptions::default())
+    }
+
+    pub fn with_options(endpoint: &str, sock_type: zmq::SocketType, options: &ZmqSocketOptions) -> Self {
         let ctx = zmq::Context::new();
         let socket = ctx.socket(sock_type).expect("Error in creating zmq socket");
+        options.apply(&socket).expect("Error in setting zmq socket options");
+
+        // the monitor is attached before connecting so that no event is missed
+        let monitor = options.monitor.then(|| {
+            let address = format!("inproc://zmq-consumer-monitor-{}", MONITOR_COUNT.fetch_add(1, Ordering::Relaxed));
+            let events = zmq::SocketEvent::CONNECTED.to_raw()
+                | zmq::SocketEvent::DISCONNECTED.to_raw()
+                | zmq::SocketEvent::CONNECT_RETRIED.to_raw();
+            socket.monitor(&address, events as i32).expect("Error in monitoring zmq socket");
+            let monitor = ctx.socket(zmq::PAIR).expect("Error in creating zmq socket");
+            monitor.connect(&address).expect("Error in connecting to zmq socket monitor");
+            monitor
+        });
+
         socket.connect(endpoint).unwrap_or_else(
             |_| panic!("Error in connecting to endpoint: {}", endpoint));
 
@@ -28,7 +185,15 @@ impl ZmqConsumer {
 
         ZmqConsumer {
             socket,
-            decoder: None
+            decoder: None,
+            sequence: RefCell::new(None),
+            endpoint: endpoint.to_owned(),
+            connected: Cell::new(monitor.as_ref().map(|_| false)),
+            monitor,
+            received: Cell::new(0),
+            last_received: Cell::new(Instant::now()),
+            watchdog: None,
+            idle_reported: Cell::new(false),
         }
     }
 
@@ -36,9 +201,150 @@ impl ZmqConsumer {
         self.decoder = Some(create_decoder(name, schema));
     }
 
+    /// Detect dropped messages from gaps in an integer field of the consumed records.
+    pub fn set_sequence_field(&mut self, field: &str) {
+        self.sequence.replace(Some(SequenceTracker {
+            field: field.to_owned(),
+            last: None,
+            dropped: 0,
+        }));
+    }
+
+    /// Number of messages missing from the sequence numbers so far.
+    pub fn dropped(&self) -> u64 {
+        self.sequence.borrow().as_ref().map_or(0, |x| x.dropped)
+    }
+
+    /// Report `ConnectionEvent::NoData` if no message arrives within the timeout.
+    ///
+    /// The consumer only notices when `events` is called, so a receive timeout
+    /// should be set as well.
+    pub fn set_watchdog(&mut self, timeout: Duration) {
+        self.watchdog = Some(timeout);
+    }
+
+    fn on_received(&self) {
+        self.received.set(self.received.get() + 1);
+        self.last_received.set(Instant::now());
+        self.idle_reported.set(false);
+    }
+
+    fn is_stale(&self) -> bool {
+        self.watchdog.is_some_and(|timeout| self.last_received.get().elapsed() >= timeout)
+    }
+
+    /// Return the connection events since the last call, without blocking.
+    pub fn events(&self) -> FcResult<Vec<ConnectionEvent>> {
+        let mut events = Vec::new();
+        if let Some(monitor) = &self.monitor {
+            loop {
+                let frames = match monitor.recv_multipart(zmq::DONTWAIT) {
+                    Ok(frames) => frames,
+                    Err(zmq::Error::EAGAIN) => break,
+                    Err(e) => return Err(e.into()),
+                };
+                // the first frame starts with the event ID and the second one is the endpoint
+                if frames.len() < 2 || frames[0].len() < 2 {
+                    continue;
+                }
+                let event = u16::from_ne_bytes([frames[0][0], frames[0][1]]);
+                let endpoint = String::from_utf8_lossy(&frames[1]).into_owned();
+                if event == zmq::SocketEvent::CONNECTED.to_raw() {
+                    self.connected.set(Some(true));
+                    events.push(ConnectionEvent::Connected(endpoint));
+                } else if event == zmq::SocketEvent::DISCONNECTED.to_raw() {
+                    self.connected.set(Some(false));
+                    events.push(ConnectionEvent::Disconnected(endpoint));
+                } else if event == zmq::SocketEvent::CONNECT_RETRIED.to_raw() {
+                    events.push(ConnectionEvent::Retried(endpoint));
+                }
+            }
+        }
+        if self.is_stale() && !self.idle_reported.get() {
+            self.idle_reported.set(true);
+            events.push(ConnectionEvent::NoData(self.last_received.get().elapsed()));
+        }
+        Ok(events)
+    }
+
+    /// Return the current state. Call `events` first to update the connection state.
+    pub fn status(&self) -> ConsumerStatus {
+        ConsumerStatus {
+            endpoint: self.endpoint.clone(),
+            connected: self.connected.get(),
+            received: self.received.get(),
+            dropped: self.dropped(),
+            idle: self.last_received.get().elapsed(),
+            stale: self.is_stale(),
+        }
+    }
+
+    /// Consumes a single record.
+    ///
+    /// Fails with `zmq::Error::EAGAIN` if the receive timeout expires.
     pub fn consume(&self) -> FcResult<Decoded> {
         let bytes: Encoded = self.socket.recv_bytes(0)?;
-        Ok(self.decoder.as_ref().unwrap().unpack(&bytes)?.into_iter().next().unwrap())
+        self.on_received();
+        let decoded = self.decoder.as_ref().unwrap().unpack(&bytes)?.into_iter().next().unwrap();
+        if let Some(sequence) = self.sequence.borrow_mut().as_mut() {
+            sequence.update(&decoded);
+        }
+        Ok(decoded)
+    }
+
+    /// Same as `consume` but returns None if the receive timeout expired.
+    pub fn try_consume(&self) -> FcResult<Option<Decoded>> {
+        match self.consume() {
+            Ok(x) => Ok(Some(x)),
+            Err(FcError::ZmqError(zmq::Error::EAGAIN)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Receives a message without copying it, e.g. for `AvroDecoder::unpack_borrowed`.
+    pub fn consume_raw(&self) -> FcResult<zmq::Message> {
+        let msg = self.socket.recv_msg(0)?;
+        self.on_received();
+        Ok(msg)
+    }
+
+    /// Same as `consume_raw` but returns None if the receive timeout expired.
+    pub fn try_consume_raw(&self) -> FcResult<Option<zmq::Message>> {
+        match self.consume_raw() {
+            Ok(x) => Ok(Some(x)),
+            Err(FcError::ZmqError(zmq::Error::EAGAIN)) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// ZmqConsumer which returns plain Rust structs.
+pub struct TypedZmqConsumer<T> {
+    consumer: ZmqConsumer,
+    _marker: PhantomData<T>,
+}
+
+impl<T: DeserializeOwned> TypedZmqConsumer<T> {
+    pub fn new(endpoint: &str, sock_type: zmq::SocketType) -> Self {
+        TypedZmqConsumer {
+            consumer: ZmqConsumer::new(endpoint, sock_type),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn with_options(endpoint: &str, sock_type: zmq::SocketType, options: &ZmqSocketOptions) -> Self {
+        TypedZmqConsumer {
+            consumer: ZmqConsumer::with_options(endpoint, sock_type, options),
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn set_decoder(&mut self, name: &str, schema: Option<&serde_json::Value>) {
+        self.consumer.set_decoder(name, schema);
+    }
+
+    pub fn consume(&self) -> FcResult<T> {
+        from_decoded(self.consumer.consume()?)
     }
 }
 
@@ -49,8 +355,13 @@ pub struct ZmqProducer {
 
 impl ZmqProducer {
     pub fn new(endpoint: &str, sock_type: zmq::SocketType) -> Self {
+        ZmqProducer::with_options(endpoint, sock_type, &ZmqSocketOptions::default())
+    }
+
+    pub fn with_options(endpoint: &str, sock_type: zmq::SocketType, options: &ZmqSocketOptions) -> Self {
         let ctx = zmq::Context::new();
         let socket = ctx.socket(sock_type).expect("Error in creating zmq socket");
+        options.apply(&socket).expect("Error in setting zmq socket options");
         socket.bind(endpoint).unwrap_or_else(
             |_| panic!("Error in binding to endpoint: {}", endpoint));
 
@@ -75,10 +386,14 @@ impl ZmqProducer {
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use apache_avro::types::Value;
 
+    use serde::Deserialize;
+
     use crate::schema::Decoded;
-    use crate::zmq_clients::{ZmqConsumer, ZmqProducer};
+    use crate::zmq_clients::{ConnectionEvent, TypedZmqConsumer, ZmqConsumer, ZmqProducer, ZmqSocketOptions};
 
     #[test]
     fn test_zmq_consumer_and_producer() {
@@ -114,4 +429,119 @@ mod tests {
             assert_eq!(ret, Decoded::from([("index".to_string(), Value::Int(i))]));
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_typed_zmq_consumer() {
+        #[derive(Deserialize, Debug, PartialEq)]
+        struct Raw {
+            index: i32,
+        }
+
+        let raw_schema = r#"
+            {
+                "namespace": "testcase",
+                "type": "record",
+                "name": "raw",
+                "fields": [
+                    {
+                      "name": "index",
+                      "type": "int"
+                    }
+                ]
+            }"#;
+        let json_schema: serde_json::Value = serde_json::from_str(raw_schema).unwrap();
+
+        let mut producer = ZmqProducer::new("tcp://*:5556", zmq::SocketType::PUSH);
+        producer.set_encoder("avro", Some(&json_schema));
+
+        let mut consumer = TypedZmqConsumer::<Raw>::new("tcp://localhost:5556", zmq::SocketType::PULL);
+        consumer.set_decoder("avro", Some(&json_schema));
+
+        let items = vec![Decoded::from([("index".to_string(), Value::Int(7))])];
+        let _ = producer.produce(&items);
+        assert_eq!(consumer.consume().unwrap(), Raw { index: 7 });
+    }
+
+    #[test]
+    fn test_zmq_socket_options_and_sequence() {
+        let raw_schema = r#"
+            {
+                "namespace": "testcase",
+                "type": "record",
+                "name": "raw",
+                "fields": [
+                    {
+                      "name": "index",
+                      "type": "int"
+                    }
+                ]
+            }"#;
+        let json_schema: serde_json::Value = serde_json::from_str(raw_schema).unwrap();
+
+        let options = ZmqSocketOptions {
+            hwm: Some(100),
+            linger: Some(0),
+            receive_timeout: Some(500),
+            ..Default::default()
+        };
+        let mut producer = ZmqProducer::with_options("tcp://*:5557", zmq::SocketType::PUSH, &options);
+        producer.set_encoder("avro", Some(&json_schema));
+
+        let mut consumer = ZmqConsumer::with_options("tcp://localhost:5557", zmq::SocketType::PULL, &options);
+        consumer.set_decoder("avro", Some(&json_schema));
+        consumer.set_sequence_field("index");
+
+        let items: Vec<Decoded> = [0, 1, 3, 7, 2].iter()
+            .map(|i| Decoded::from([("index".to_string(), Value::Int(*i))]))
+            .collect();
+        producer.produce(&items).unwrap();
+        for _ in 0..items.len() {
+            assert!(consumer.try_consume().unwrap().is_some());
+        }
+        // 2, 4, 5 and 6 are missing; 2 after 7 is a restart
+        assert_eq!(consumer.dropped(), 4);
+        assert!(consumer.try_consume().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_zmq_consumer_monitor() {
+        let json_schema = serde_json::json!({
+            "namespace": "testcase",
+            "type": "record",
+            "name": "raw",
+            "fields": [{"name": "index", "type": "int"}]
+        });
+
+        let mut producer = ZmqProducer::new("tcp://*:5558", zmq::SocketType::PUSH);
+        producer.set_encoder("avro", Some(&json_schema));
+
+        let options = ZmqSocketOptions { monitor: true, ..Default::default() };
+        let mut consumer = ZmqConsumer::with_options("tcp://localhost:5558", zmq::SocketType::PULL, &options);
+        consumer.set_decoder("avro", Some(&json_schema));
+        assert_eq!(consumer.status().connected, Some(false));
+
+        let mut events = Vec::new();
+        for _ in 0..100 {
+            events.extend(consumer.events().unwrap());
+            if consumer.status().connected == Some(true) {
+                break;
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(events.iter().any(|x| matches!(x, ConnectionEvent::Connected(_))));
+
+        consumer.set_watchdog(Duration::from_millis(100));
+        std::thread::sleep(Duration::from_millis(150));
+        let events = consumer.events().unwrap();
+        assert!(matches!(events[..], [ConnectionEvent::NoData(_)]));
+        // reported only once
+        assert!(consumer.events().unwrap().is_empty());
+        assert!(consumer.status().stale);
+
+        producer.produce(&[Decoded::from([("index".to_string(), Value::Int(1))])]).unwrap();
+        consumer.consume().unwrap();
+        let status = consumer.status();
+        assert_eq!((status.received, status.stale), (1, false));
+        assert_eq!(status.fields()[5], ("live", "true".to_string()));
+    }
+}
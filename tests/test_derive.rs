@@ -0,0 +1,37 @@
+use foamcore::schema::{FoamSchema, NDArray, load_schema};
+
+static SCHEMA1_FILEPATH: &str = "tests/data/schema1.json";
+
+#[derive(FoamSchema)]
+#[foam(namespace = "schema1", name = "raw")]
+#[allow(dead_code)]
+struct Schema1 {
+    integer: i64,
+    string: String,
+    array2d: NDArray,
+}
+
+#[derive(FoamSchema)]
+#[foam(namespace = "testcase")]
+#[allow(dead_code)]
+struct Images {
+    index: Option<i32>,
+    image: NDArray,
+    mask: NDArray,
+}
+
+#[test]
+fn test_derive_schema() {
+    let (schema, stream) = load_schema(SCHEMA1_FILEPATH);
+
+    assert_eq!(Schema1::json_schema(), schema.unwrap());
+    assert_eq!(Schema1::stream(), stream);
+}
+
+#[test]
+fn test_derive_schema_with_repeated_named_types() {
+    assert_eq!(Images::stream(), "testcase:Images");
+
+    let schema = apache_avro::Schema::parse(&Images::json_schema());
+    assert!(schema.is_ok());
+}
@@ -1,8 +1,11 @@
+use apache_avro::{Days, Decimal, Duration, Millis, Months};
 use apache_avro::types::Value;
+use serde::{Serialize, Deserialize};
 
-use foamcore::decoder::create_decoder;
-use foamcore::encoder::create_encoder;
-use foamcore::schema::{Decoded, load_schema};
+use foamcore::borrowed::to_decoded;
+use foamcore::decoder::{AvroDecoder, create_decoder, DecoderExt};
+use foamcore::encoder::{create_encoder, EncoderExt};
+use foamcore::schema::{Decoded, NDArray, load_schema};
 
 static SCHEMA1_FILEPATH: &str = "tests/data/schema1.json";
 
@@ -30,6 +33,166 @@ fn test_avro_encoder_decoder() {
     assert_eq!(raw, decoded[0]);
 }
 
+#[test]
+fn test_avro_logical_types() {
+    let json_schema = serde_json::json!({
+        "type": "record",
+        "name": "logical",
+        "fields": [
+            {"name": "price", "type": {"type": "bytes", "logicalType": "decimal", "precision": 9, "scale": 2}},
+            {"name": "offset", "type": {
+                "type": "fixed", "name": "Offset", "size": 4, "logicalType": "decimal", "precision": 6
+            }},
+            {"name": "id", "type": {"type": "string", "logicalType": "uuid"}},
+            {"name": "period", "type": {
+                "type": "fixed", "name": "Period", "size": 12, "logicalType": "duration"
+            }}
+        ]
+    });
+    let encoder = create_encoder("avro", Some(&json_schema));
+    let decoder = create_decoder("avro", Some(&json_schema));
+
+    let uuid = Value::String("550e8400-e29b-41d4-a716-446655440000".to_string())
+        .resolve(&apache_avro::Schema::Uuid).unwrap();
+    let raw = Decoded::from([
+        ("price".to_string(), Value::Decimal(Decimal::from(vec![0x30, 0x39]))),
+        // -200 is sign-extended to the size of the fixed
+        ("offset".to_string(), Value::Decimal(Decimal::from(vec![0xFF, 0x38]))),
+        ("id".to_string(), uuid),
+        ("period".to_string(), Value::Duration(Duration::new(Months::new(1), Days::new(2), Millis::new(3)))),
+    ]);
+    let bytes = encoder.pack(&raw).unwrap();
+    assert_eq!(decoder.unpack(&bytes).unwrap(), vec![raw.clone()]);
+    let borrowed = AvroDecoder::new(&json_schema);
+    assert_eq!(to_decoded(&borrowed.unpack_borrowed(&bytes).unwrap()[0]), raw);
+
+    // the reference implementation reads the same values
+    let schema = apache_avro::Schema::parse(&json_schema).unwrap();
+    let records: Vec<Value> = apache_avro::Reader::with_schema(&schema, &bytes[..]).unwrap()
+        .map(Result::unwrap).collect();
+    let mut expected: Vec<(String, Value)> = raw.into_iter().collect();
+    let order = ["price", "offset", "id", "period"];
+    expected.sort_by_key(|(k, _)| order.iter().position(|x| x == k));
+    assert_eq!(records, vec![Value::Record(expected)]);
+}
+
+#[test]
+fn test_json_encoder_decoder() {
+    let (json_schema, _) =  load_schema(SCHEMA1_FILEPATH);
+    let encoder = create_encoder("json", json_schema.as_ref());
+    let decoder = create_decoder("json", json_schema.as_ref());
+
+    let raw = Decoded::from([
+        ("integer".to_string(), Value::Long(1)),
+        ("string".to_string(), Value::String("Hello world!".to_string())),
+        ("array2d".to_string(), Value::Record(
+            vec![
+                ("shape".to_string(), Value::Array(vec![Value::Int(2), Value::Int(2)])),
+                ("dtype".to_string(), Value::String(">f4".to_string())),
+                ("data".to_string(), Value::Bytes(vec![1, 2, 3, 4]))
+            ]
+        ))
+    ]);
+    let bytes = encoder.pack(&raw).unwrap();
+    let decoded = decoder.unpack(&bytes).unwrap();
+
+    assert_eq!(decoded.len(), 1);
+    assert_eq!(raw, decoded[0]);
+
+    let batch = br#"[
+        {"integer": 1, "string": "a", "array2d": {"dtype": "<i2", "data": [1, 2]}},
+        {"integer": 2, "string": "b", "array2d": {"shape": [1], "dtype": "|u1", "data": "AQ=="}}
+    ]"#;
+    let decoded = decoder.unpack(&batch.to_vec()).unwrap();
+    assert_eq!(decoded.len(), 2);
+    assert_eq!(decoded[1]["integer"], Value::Long(2));
+}
+
+#[test]
+fn test_msgpack_and_cbor_encoder_decoder() {
+    let (json_schema, _) =  load_schema(SCHEMA1_FILEPATH);
+
+    let raw = Decoded::from([
+        ("integer".to_string(), Value::Long(1)),
+        ("string".to_string(), Value::String("Hello world!".to_string())),
+        ("array2d".to_string(), Value::Record(
+            vec![
+                ("shape".to_string(), Value::Array(vec![Value::Int(2), Value::Int(2)])),
+                ("dtype".to_string(), Value::String(">f4".to_string())),
+                ("data".to_string(), Value::Bytes(vec![1, 2, 3, 4]))
+            ]
+        ))
+    ]);
+
+    for name in ["msgpack", "cbor"] {
+        let encoder = create_encoder(name, json_schema.as_ref());
+        let decoder = create_decoder(name, json_schema.as_ref());
+
+        let mut bytes = encoder.pack(&raw).unwrap();
+        bytes.extend(encoder.pack(&raw).unwrap());
+        let decoded = decoder.unpack(&bytes).unwrap();
+
+        assert_eq!(decoded, vec![raw.clone(), raw.clone()]);
+    }
+}
+
+#[test]
+fn test_pack_batch() {
+    let (json_schema, _) =  load_schema(SCHEMA1_FILEPATH);
+
+    let records: Vec<Decoded> = (0..3).map(|i| Decoded::from([
+        ("integer".to_string(), Value::Long(i)),
+        ("string".to_string(), Value::String(format!("record {}", i))),
+        ("array2d".to_string(), Value::Record(
+            vec![
+                ("shape".to_string(), Value::Array(vec![Value::Int(1), Value::Int(2)])),
+                ("dtype".to_string(), Value::String("<i2".to_string())),
+                ("data".to_string(), Value::Bytes(vec![i as u8, 0, 1, 0]))
+            ]
+        ))
+    ])).collect();
+
+    let mut names = vec!["avro", "json", "msgpack", "cbor"];
+    if cfg!(feature = "arrow") {
+        names.push("arrow");
+    }
+    for name in names {
+        let encoder = create_encoder(name, json_schema.as_ref());
+        let decoder = create_decoder(name, json_schema.as_ref());
+
+        let bytes = encoder.pack_batch(&records).unwrap();
+        assert_eq!(decoder.unpack(&bytes).unwrap(), records, "{}", name);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+struct Schema1 {
+    integer: i64,
+    string: String,
+    array2d: NDArray,
+}
+
+#[test]
+fn test_avro_encoder_decoder_serde() {
+    let (json_schema, _) =  load_schema(SCHEMA1_FILEPATH);
+    let encoder = create_encoder("avro", json_schema.as_ref());
+    let decoder = create_decoder("avro", json_schema.as_ref());
+
+    let raw = Schema1 {
+        integer: 1,
+        string: "Hello world!".to_string(),
+        array2d: NDArray {
+            shape: vec![2, 2],
+            dtype: ">f4".to_string(),
+            data: vec![1, 2, 3, 4],
+        },
+    };
+    let bytes = encoder.pack_serde(&raw).unwrap();
+    let decoded: Vec<Schema1> = decoder.unpack_as(&bytes).unwrap();
+
+    assert_eq!(decoded, vec![raw]);
+}
+
 #[test]
 fn test_pickle_encoder_decoder() {
     let _ = create_decoder("pickle", None);
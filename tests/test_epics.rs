@@ -0,0 +1,55 @@
+#![cfg(feature = "epics")]
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::Duration;
+
+use apache_avro::types::Value;
+
+use foamcore::epics_clients::EpicsConsumer;
+
+#[test]
+fn test_epics_consumer_with_soft_ioc() {
+    let prefix = format!("foamcore_test_{}:", std::process::id());
+    let ioc = Command::new("softIoc")
+        .args(["-S", "-m", &format!("P={}", prefix), "-d", "tests/data/epics_test.db"])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .spawn();
+    let mut ioc = match ioc {
+        Ok(child) => child,
+        Err(e) => {
+            println!("Test skipped: no soft IOC: {:?}", e);
+            return;
+        },
+    };
+    if Command::new("camonitor").arg("-h").stdout(Stdio::null()).status().is_err() {
+        println!("Test skipped: camonitor not found");
+        ioc.kill().unwrap();
+        return;
+    }
+    thread::sleep(Duration::from_secs(1));
+
+    let json_schema = serde_json::json!({
+        "namespace": "epics_test",
+        "type": "record",
+        "name": "raw",
+        "fields": [
+            {"name": "temperature", "type": "double"},
+            {"name": "counter", "type": "long"},
+            {"name": "comment", "type": ["null", "string"]}
+        ]
+    });
+    let pvs = vec![
+        ("temperature".to_string(), format!("{}TEMPERATURE", prefix)),
+        ("counter".to_string(), format!("{}COUNTER", prefix)),
+    ];
+    let mut consumer = EpicsConsumer::new(&json_schema, &pvs, "ca").unwrap();
+    let record = consumer.consume().unwrap();
+
+    assert_eq!(record["temperature"], Value::Double(21.5));
+    assert_eq!(record["counter"], Value::Long(7));
+    assert_eq!(record["comment"], Value::Union(0, Box::new(Value::Null)));
+    assert_eq!(consumer.skipped(), 0);
+
+    ioc.kill().unwrap();
+}
@@ -1,3 +1,6 @@
+use std::thread;
+use std::time::Duration;
+
 use foamcore::schema::{load_schema, SchemaRegistry};
 use foamcore::error::FcError;
 
@@ -42,4 +45,78 @@ fn test_schema_registry() {
         let schema_readback = registry.get(&stream).unwrap();
         assert_eq!(schema_readback, &schema.unwrap());
     }
-}
\ No newline at end of file
+}
+
+#[test]
+fn test_schema_registry_invalidation() {
+    let host = "127.0.0.1";
+    let port = 6379;
+
+    let mut reader = SchemaRegistry::new(host, port);
+    reader.set_ttl(None);
+    let mut writer = SchemaRegistry::new(host, port);
+
+    let (schema, stream) = load_schema(SCHEMA1_FILEPATH);
+    let mut schema = schema.unwrap();
+    let stream = stream + "_invalidation";
+
+    if let Err(error) = reader.enable_invalidation() {
+        match error {
+            FcError::RedisError(_) => {
+                println!("Test skipped: no Redis connection: {:?}", error);
+                return;
+            },
+            _ => panic!("{:?}", error),
+        }
+    }
+
+    writer.set(&stream, Some(&schema)).unwrap();
+    assert_eq!(reader.get(&stream).unwrap(), &schema);
+
+    schema["fields"].as_array_mut().unwrap().pop();
+    writer.set(&stream, Some(&schema)).unwrap();
+
+    let mut updated = false;
+    for _ in 0..50 {
+        if reader.get(&stream).unwrap() == &schema {
+            updated = true;
+            break;
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    assert!(updated);
+
+    writer.delete(&stream).unwrap();
+}
+
+#[test]
+fn test_schema_registry_versions() {
+    let host = "127.0.0.1";
+    let port = 6379;
+
+    let mut registry = SchemaRegistry::new(host, port);
+
+    let (schema, stream) = load_schema(SCHEMA1_FILEPATH);
+    let stream = stream + "_versions";
+
+    if let Err(error) = registry.set(&stream, schema.as_ref()) {
+        match error {
+            FcError::RedisError(_) => {
+                println!("Test skipped: no Redis connection: {:?}", error);
+                return;
+            },
+            _ => panic!("{:?}", error),
+        }
+    }
+
+    assert!(registry.list().unwrap().contains(&stream));
+    assert_eq!(registry.versions(&stream).unwrap(), vec!["0"]);
+    assert_eq!(registry.get_version(&stream, "0").unwrap(), schema);
+    assert_eq!(registry.get_version(&stream, "1").unwrap(), None);
+
+    registry.delete(&stream).unwrap();
+    assert!(!registry.list().unwrap().contains(&stream));
+    assert!(registry.versions(&stream).unwrap().is_empty());
+    assert_eq!(registry.get_version(&stream, "0").unwrap(), None);
+    assert!(registry.get(&stream).is_err());
+}